@@ -0,0 +1,590 @@
+//! Evaluates expressions over many points in time at once.
+//!
+//! Instead of walking the syntax tree once per sample, each node is computed
+//! for a whole block of samples, a "lane" each, so the per-node overhead is
+//! paid once per block and the arithmetic runs over plain slices. Branches,
+//! `&&`, `||` and the bodies of `sum`/`prod` only run on the lanes that reach
+//! them, exactly as the tree-walker would evaluate each sample.
+
+use std::collections::HashMap;
+
+use crate::{
+    builtins::{self, Implementation},
+    context::Context,
+    error::{ErrorKind, MusathError},
+    expression::{BinaryOperator, Expression, Primary, is_truthy},
+    function::{Function, FunctionBody},
+};
+
+/// The values of identifiers across a block, on top of a [`Context`]
+/// providing functions and constants. Like the tree-walker, each call and
+/// `let` evaluates its body in a copy with its own bindings added.
+#[derive(Debug, Clone)]
+pub struct BlockContext<'a> {
+    context: &'a Context,
+    values: HashMap<String, Vec<f64>>,
+    lanes: usize,
+}
+
+impl<'a> BlockContext<'a> {
+    pub fn new(context: &'a Context, lanes: usize) -> Self {
+        Self {
+            context,
+            values: HashMap::new(),
+            lanes,
+        }
+    }
+
+    pub fn context(&self) -> &Context {
+        self.context
+    }
+
+    /// The number of samples in the block.
+    pub fn lanes(&self) -> usize {
+        self.lanes
+    }
+
+    /// Binds `identifier` to one value per lane.
+    pub fn push_value(&mut self, identifier: impl Into<String>, values: Vec<f64>) {
+        assert_eq!(values.len(), self.lanes, "expected one value per lane");
+
+        self.values.insert(identifier.into(), values);
+    }
+
+    pub fn value(&self, identifier: &str) -> Option<Vec<f64>> {
+        match self.values.get(identifier) {
+            Some(values) => Some(values.clone()),
+            None => Some(vec![*self.context.value(identifier)?; self.lanes]),
+        }
+    }
+
+    /// A block of only the given lanes.
+    fn select(&self, lanes: &[usize]) -> Self {
+        Self {
+            context: self.context,
+            values: self
+                .values
+                .iter()
+                .map(|(identifier, values)| {
+                    let values = lanes.iter().map(|lane| values[*lane]).collect();
+
+                    (identifier.clone(), values)
+                })
+                .collect(),
+            lanes: lanes.len(),
+        }
+    }
+
+    /// The context of the tree-walker for a single lane.
+    fn lane(&self, lane: usize) -> Context {
+        let mut context = self.context.clone();
+
+        for (identifier, values) in &self.values {
+            context.push_value(identifier, values[lane]);
+        }
+
+        context
+    }
+}
+
+impl Expression {
+    /// Evaluates the expression at every lane of `context`. The result for
+    /// each lane is the same as [`Expression::eval`] with that lane's values.
+    pub fn eval_block(&self, context: &BlockContext) -> Result<Vec<f64>, MusathError> {
+        if context.lanes == 0 {
+            return Ok(Vec::new());
+        }
+
+        match self {
+            Self::Binary(left, operator @ (BinaryOperator::And | BinaryOperator::Or), right) => {
+                let left = left.eval_block(context)?;
+
+                // `&&` needs its right operand where the left one is truthy,
+                // and `||` where it is not.
+                let needed = matches!(operator, BinaryOperator::And);
+                let lanes = partition(&left, needed);
+                let right = right.eval_block(&context.select(&lanes))?;
+
+                // Elsewhere the result doesn't depend on the right operand.
+                let mut result = left
+                    .iter()
+                    .map(|left| operator.eval(*left, 0.0))
+                    .collect::<Vec<_>>();
+
+                let right = lanes
+                    .iter()
+                    .zip(right)
+                    .map(|(lane, right)| operator.eval(left[*lane], right));
+
+                scatter(&mut result, &lanes, right);
+
+                Ok(result)
+            }
+            Self::Binary(left, operator, right) => {
+                let left = left.eval_block(context)?;
+                let right = right.eval_block(context)?;
+
+                Ok(left
+                    .into_iter()
+                    .zip(right)
+                    .map(|(left, right)| operator.eval(left, right))
+                    .collect())
+            }
+            Self::Unary(operator, operand) => Ok(operand
+                .eval_block(context)?
+                .into_iter()
+                .map(|operand| operator.eval(operand))
+                .collect()),
+            Self::Primary(primary) => primary.eval_block(context),
+            Self::Conditional(condition, consequent, alternative) => {
+                let condition = condition.eval_block(context)?;
+                let consequent_lanes = partition(&condition, true);
+                let alternative_lanes = partition(&condition, false);
+
+                let mut result = vec![0.0; context.lanes];
+
+                scatter(
+                    &mut result,
+                    &consequent_lanes,
+                    consequent.eval_block(&context.select(&consequent_lanes))?,
+                );
+                scatter(
+                    &mut result,
+                    &alternative_lanes,
+                    alternative.eval_block(&context.select(&alternative_lanes))?,
+                );
+
+                Ok(result)
+            }
+            Self::Let(bindings, body) => {
+                let mut inner_context = context.clone();
+
+                for (identifier, expression) in bindings {
+                    let values = expression.eval_block(&inner_context)?;
+
+                    inner_context.push_value(identifier, values);
+                }
+
+                body.eval_block(&inner_context)
+            }
+        }
+    }
+}
+
+impl Primary {
+    /// Evaluates the primary at every lane of `context`. See
+    /// [`Expression::eval_block`].
+    pub fn eval_block(&self, context: &BlockContext) -> Result<Vec<f64>, MusathError> {
+        match self {
+            Self::Decimal(number) => Ok(vec![*number; context.lanes]),
+            Self::Integer(number) => Ok(vec![*number as f64; context.lanes]),
+            Self::Note(note) => Ok(vec![note.frequency(context.context.a4_hz()); context.lanes]),
+            Self::Grouping(expression) => expression.eval_block(context),
+            Self::Identifier(identifier, span) => context.value(identifier).ok_or_else(|| {
+                MusathError::with_span(ErrorKind::UndefinedIdentifier(identifier.clone()), *span)
+            }),
+            Self::Call(identifier, arguments, span) => {
+                let function = context.context.function(identifier).ok_or_else(|| {
+                    MusathError::with_span(ErrorKind::UndefinedFunction(identifier.clone()), *span)
+                })?;
+
+                match function.body() {
+                    FunctionBody::Expression(body) if function.control_rate().is_some() => {
+                        let arguments = arguments
+                            .iter()
+                            .take(function.signature().parameters().len())
+                            .map(|argument| argument.eval_block(context))
+                            .collect::<Result<Vec<_>, _>>()?;
+
+                        control_rate_call(function, body, &arguments, context)
+                    }
+                    FunctionBody::Expression(body) => {
+                        let mut inner_context = context.clone();
+
+                        for (parameter, argument) in
+                            function.signature().parameters().iter().zip(arguments)
+                        {
+                            inner_context.push_value(parameter, argument.eval_block(context)?);
+                        }
+
+                        body.eval_block(&inner_context)
+                    }
+                    FunctionBody::Closure(_) => builtin(identifier, arguments, context),
+                }
+            }
+        }
+    }
+}
+
+/// Evaluates a function with a control rate at the control points around
+/// each lane and interpolates between them. Lanes with the same arguments
+/// between the same control points share them, so the body runs a few times
+/// per block rather than once per sample.
+fn control_rate_call(
+    function: &Function,
+    body: &Expression,
+    arguments: &[Vec<f64>],
+    context: &BlockContext,
+) -> Result<Vec<f64>, MusathError> {
+    let control_rate = function
+        .control_rate()
+        .expect("expected a function with a control rate");
+
+    let positions: Vec<(f64, f64)> = arguments[0]
+        .iter()
+        .map(|t| control_rate.position(*t))
+        .collect();
+
+    let mut distinct = HashMap::new();
+    let mut representatives = Vec::new();
+    let mut shared = Vec::with_capacity(context.lanes);
+
+    for (lane, (index, _)) in positions.iter().enumerate() {
+        let key: Vec<u64> = std::iter::once(*index)
+            .chain(arguments[1..].iter().map(|values| values[lane]))
+            .map(f64::to_bits)
+            .collect();
+
+        let next = distinct.len();
+
+        shared.push(*distinct.entry(key).or_insert_with(|| {
+            representatives.push(lane);
+            next
+        }));
+    }
+
+    let offsets = control_rate.interpolation().offsets();
+    let mut points = vec![Vec::with_capacity(offsets.len()); representatives.len()];
+
+    for offset in offsets {
+        let mut inner_context = context.select(&representatives);
+
+        for (position, (parameter, values)) in function
+            .signature()
+            .parameters()
+            .iter()
+            .zip(arguments)
+            .enumerate()
+        {
+            let values = representatives
+                .iter()
+                .map(|lane| match position {
+                    0 => control_rate.time(positions[*lane].0, *offset),
+                    _ => values[*lane],
+                })
+                .collect();
+
+            inner_context.push_value(parameter, values);
+        }
+
+        for (points, value) in points.iter_mut().zip(body.eval_block(&inner_context)?) {
+            points.push(value);
+        }
+    }
+
+    Ok(shared
+        .into_iter()
+        .zip(positions)
+        .map(|(shared, (_, fraction))| {
+            control_rate
+                .interpolation()
+                .interpolate(&points[shared], fraction)
+        })
+        .collect())
+}
+
+/// Evaluates a call to a built-in function, a lane at a time if it is not
+/// one of the functions below.
+fn builtin(
+    identifier: &str,
+    arguments: &[Box<Expression>],
+    context: &BlockContext,
+) -> Result<Vec<f64>, MusathError> {
+    if let Some(builtin) = builtins::builtin(identifier)
+        && builtin.arity().accepts(arguments.len())
+    {
+        // The values of the setting follow the arguments.
+        let setting = builtin
+            .setting()
+            .map_or(&[][..], |setting| context.context().setting(setting));
+        let value = |index: usize| match arguments.get(index) {
+            Some(argument) => argument.eval_block(context),
+            None => Ok(vec![setting[index - arguments.len()]; context.lanes]),
+        };
+
+        match builtin.implementation() {
+            Implementation::Unary(function) => {
+                return Ok(value(0)?.into_iter().map(function).collect());
+            }
+            Implementation::Binary(function) => {
+                let (x, y) = (value(0)?, value(1)?);
+
+                return Ok((0..context.lanes)
+                    .map(|lane| function(x[lane], y[lane]))
+                    .collect());
+            }
+            Implementation::Ternary(function) => {
+                let (x, y, z) = (value(0)?, value(1)?, value(2)?);
+
+                return Ok((0..context.lanes)
+                    .map(|lane| function(x[lane], y[lane], z[lane]))
+                    .collect());
+            }
+            Implementation::Quaternary(function) => {
+                let (x, y, z, w) = (value(0)?, value(1)?, value(2)?, value(3)?);
+
+                return Ok((0..context.lanes)
+                    .map(|lane| function(x[lane], y[lane], z[lane], w[lane]))
+                    .collect());
+            }
+            Implementation::Slice(function) => {
+                let values = (0..arguments.len() + setting.len())
+                    .map(value)
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                return Ok((0..context.lanes)
+                    .map(|lane| {
+                        let lane: Vec<f64> = values.iter().map(|values| values[lane]).collect();
+
+                        function(&lane)
+                    })
+                    .collect());
+            }
+            Implementation::Lazy(_) => {}
+        }
+    }
+
+    match (identifier, arguments.len()) {
+        ("mix", 1..) => {
+            let values = arguments
+                .iter()
+                .map(|argument| argument.eval_block(context))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok((0..context.lanes)
+                .map(|lane| {
+                    values.iter().map(|values| values[lane]).sum::<f64>() / values.len() as f64
+                })
+                .collect())
+        }
+        ("sum", 4) => fold(identifier, arguments, context, |sum, term| sum + term),
+        ("prod", 4) => fold(identifier, arguments, context, |product, term| {
+            product * term
+        }),
+        _ => per_lane(identifier, arguments, context),
+    }
+}
+
+/// Evaluates a call to a built-in function with the tree-walker, one lane at
+/// a time.
+fn per_lane(
+    identifier: &str,
+    arguments: &[Box<Expression>],
+    context: &BlockContext,
+) -> Result<Vec<f64>, MusathError> {
+    let function = context
+        .context
+        .function(identifier)
+        .expect("expected a built-in function");
+
+    (0..context.lanes)
+        .map(|lane| function.eval(arguments, &mut context.lane(lane)))
+        .collect()
+}
+
+/// Evaluates `sum` or `prod`, folding each lane's terms into the result of
+/// the built-in for no terms with `combine`, in increasing order of the loop
+/// variable.
+fn fold(
+    identifier: &str,
+    arguments: &[Box<Expression>],
+    context: &BlockContext,
+    combine: fn(f64, f64) -> f64,
+) -> Result<Vec<f64>, MusathError> {
+    let Expression::Primary(Primary::Identifier(variable, _)) = arguments[0].as_ref() else {
+        // Let the built-in report the error.
+        return per_lane(identifier, arguments, context);
+    };
+
+    let bound = |values: Vec<f64>| -> Vec<isize> {
+        values
+            .into_iter()
+            .map(|value| value.round() as isize)
+            .collect()
+    };
+
+    let starts = bound(arguments[1].eval_block(context)?);
+    let ends = bound(arguments[2].eval_block(context)?);
+
+    let empty = match identifier {
+        "prod" => std::iter::empty::<f64>().product(),
+        _ => std::iter::empty::<f64>().sum(),
+    };
+
+    let mut result = vec![empty; context.lanes];
+
+    let first = starts.iter().copied().min().unwrap_or(0);
+    let last = ends.iter().copied().max().unwrap_or(0);
+
+    for value in first..last {
+        let lanes = (0..context.lanes)
+            .filter(|lane| starts[*lane] <= value && value < ends[*lane])
+            .collect::<Vec<_>>();
+
+        if lanes.is_empty() {
+            continue;
+        }
+
+        let mut inner_context = context.select(&lanes);
+
+        inner_context.push_value(variable, vec![value as f64; lanes.len()]);
+
+        for (lane, term) in lanes.iter().zip(arguments[3].eval_block(&inner_context)?) {
+            result[*lane] = combine(result[*lane], term);
+        }
+    }
+
+    Ok(result)
+}
+
+/// The lanes whose value's truthiness is `truthy`.
+fn partition(values: &[f64], truthy: bool) -> Vec<usize> {
+    (0..values.len())
+        .filter(|lane| is_truthy(values[*lane]) == truthy)
+        .collect()
+}
+
+/// Writes `values` to the given lanes of `result`.
+fn scatter(result: &mut [f64], lanes: &[usize], values: impl IntoIterator<Item = f64>) {
+    for (lane, value) in lanes.iter().zip(values) {
+        result[*lane] = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{document::Document, wave_provider::MultichannelWaveProvider};
+
+    const EXAMPLES: [&str; 6] = [
+        "delay.mth",
+        "harmonics.mth",
+        "noise.mth",
+        "organ.mth",
+        "pulse.mth",
+        "test.mth",
+    ];
+
+    /// Evaluates `count` consecutive samples from `start_sample` in one block
+    /// and checks them against the tree-walker.
+    fn assert_identical(document: &Document, start_sample: u64, count: usize) {
+        let sample_rate = 44100;
+
+        for channel in 0..document.channels() {
+            let mut block = vec![0.0; count];
+
+            document
+                .try_fill_block(start_sample, sample_rate, channel, &mut block)
+                .unwrap();
+
+            for (sample, found) in (start_sample..).zip(block) {
+                let t = sample as f64 / sample_rate as f64;
+                let expected = document.eval_channel(t, channel).unwrap();
+
+                assert_eq!(
+                    found.to_bits(),
+                    expected.to_bits(),
+                    "channel {} at t = {}: expected {}, found {}",
+                    channel,
+                    t,
+                    expected,
+                    found
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval_block_examples() {
+        for path in EXAMPLES {
+            let source = std::fs::read_to_string(path).unwrap();
+            let document = Document::from_source_at(&source, path).unwrap();
+
+            assert_identical(&document, 0, 64);
+            assert_identical(&document, 88200, 64);
+        }
+    }
+
+    #[test]
+    fn test_eval_block() {
+        let document = Document::from_source(
+            "
+            loop(n) = if n <= 0 then 0 else n + loop(n - 1);
+            binding(x) = let a = x * 2, b = a + 1 in let a = b * a in a - b;
+            short(x) = (x > 0.5 && loop(x * 8)) + (x < 0.25 || 1 / x);
+            folds(t) = sum(i, 0, t * 8, prod(j, 1, i, j + t)) + sum(i, 3, 1, i);
+            builtins(t) = mix(abs(-t), min(t, 2), max(t, 3), floor(t), ceil(t), sin(t) * cos(t));
+            library(t) = tan(t) + asin(t / 8) + acos(t / 8) + atan(t) + atan2(t, 2) +
+                sinh(t) + cosh(t) + tanh(t) + exp(t) + ln(abs(t) + 1) + log2(abs(t) + 1) +
+                log10(abs(t) + 1) + sqrt(abs(t)) + cbrt(t) + sign(t) + round(t) + trunc(t) +
+                fract(t) + clamp(t, -1, 1) + lerp(2, 3, t) + smoothstep(-1, 2, t) + hypot(t, 3) +
+                fmod(t, 0.75);
+            output(t) = loop(3) + binding(t) + short(t) + folds(t) + builtins(t) + library(t);
+            ",
+        )
+        .unwrap();
+
+        assert!(document.validate().is_empty());
+
+        let t = [-2.5, 0.0, 0.125, 0.5, 0.75, 1.0, 7.25];
+        let block = document.eval_block(&t, 0).unwrap();
+
+        for (t, found) in t.into_iter().zip(block) {
+            assert_eq!(found.to_bits(), document.eval(t).unwrap().to_bits());
+        }
+
+        assert_eq!(document.eval_block(&[], 0).unwrap(), Vec::<f64>::new());
+
+        let document =
+            Document::from_source("CHANNELS = 4\noutput(t, channel) = t * channel").unwrap();
+
+        assert_identical(&document, 1, 10);
+
+        let document = Document::from_source(
+            "
+            @rate(100) decay(t, period) = 1 - (t % period) / period;
+            @rate(250, cubic) wobble(t) = sin(2 * pi * 3 * t) * decay(t, 0.3);
+            output(t) = sin(2 * pi * 440 * t) * decay(t, 0.5) + wobble(t) + wobble(t / 2);
+            ",
+        )
+        .unwrap();
+
+        assert_identical(&document, 0, 1000);
+        assert_identical(&document, 44077, 1000);
+
+        let document = Document::from_source(
+            "
+            SAMPLE_RATE = 8000
+            A4_HZ = 432
+            pitches(t) = sin(2 * pi * (C#3 + mtof(60 + t)) * t) + ftom(Eb2 * (1 + t)) / 100 +
+                semitones(A4, t) / cents(Bb1, 100 * t) + degree(t * 20 - 7) / 1000;
+            envelopes(t) = adsr(t, 0.1, 0.5, 0.05, 0.1, 0.5, 0.2) +
+                adsr(t, 0, 0.3, 0.1, 0.1, 0.2, 0.4, -4) + env(t, 0, 0, 0, 0.2, 1, 0, 0.5, 0.25) +
+                env(t, 0, 1, 3, 0.4, 0, -2, 0.8, 1);
+            output(t) = saw(440, t) + square(220, t, 0.3) + tri(880, -t) + pulse(110, t, 0.5) +
+                envelopes(t) + pitches(t);
+            ",
+        )
+        .unwrap();
+
+        assert_identical(&document, 0, 1000);
+        assert_identical(&document, 20000, 1000);
+
+        let document = Document::from_source("output(t) = undefined(t)").unwrap();
+
+        assert!(document.eval_block(&[0.0, 1.0], 0).is_err());
+
+        let document = Document::from_source("output(t) = sum(1, 0, 2, t)").unwrap();
+
+        assert!(document.eval_block(&[0.0, 1.0], 0).is_err());
+    }
+}
@@ -1,27 +1,165 @@
+use std::collections::HashMap;
+
 use pest::iterators::Pairs;
+use tracing::info;
 
-use crate::{Rule, context::Context, function::Function};
+use crate::{
+    Rule,
+    constant::{self, Constant},
+    context::Context,
+    error::{ErrorKind, MusathError, Span},
+    expression::LanguageVersion,
+    function::Function,
+    import::{Import, Importer},
+    hoister, inliner, optimizer, validation,
+};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Body {
     context: Context,
+    imports: Vec<Import>,
+    constants: Vec<Constant>,
 }
 
 impl Body {
-    pub fn parse(pairs: &mut Pairs<Rule>) -> Self {
-        let mut context = Context::default();
+    /// Parses the body, loading its imports with `importer` and evaluating
+    /// its constants. Constants are evaluated before any function of this
+    /// file is registered, so they may only call builtins.
+    ///
+    /// Imported functions and constants are merged into the body's context.
+    /// Defining a name twice across imports, or both in an import and in this
+    /// file, is an error.
+    ///
+    /// The body starts from `context`, which holds the settings it is
+    /// rendered with, such as the sample rate oscillators depend on.
+    pub fn parse(
+        pairs: &mut Pairs<Rule>,
+        version: LanguageVersion,
+        mut context: Context,
+        importer: &mut Importer,
+    ) -> Result<Self, MusathError> {
+        let mut imports = Vec::new();
+        let mut constants = Vec::new();
+        let mut functions = Vec::new();
 
         for pair in pairs {
             match pair.as_rule() {
-                Rule::function => context.set_function(Function::parse(&mut pair.into_inner())),
-                _ => unreachable!("expected function, found {:?}", pair),
+                Rule::import => {
+                    imports.push(Import::parse(&mut pair.into_inner()));
+                }
+                Rule::constant => {
+                    constants.push(Constant::parse(&mut pair.into_inner(), version));
+                }
+                Rule::function => {
+                    functions.push(Function::parse_versioned(&mut pair.into_inner(), version));
+                }
+                _ => unreachable!("expected import, constant or function, found {:?}", pair),
             };
         }
 
-        Self { context }
+        let mut imported_functions = HashMap::new();
+        let mut imported_values = HashMap::new();
+
+        for import in &imports {
+            let module = importer.load(import, &context)?;
+
+            for function in module.functions() {
+                let identifier = function.signature().identifier();
+
+                match imported_functions.insert(identifier.clone(), function.clone()) {
+                    Some(existing) if !existing.eq_ignoring_spans(function) => {
+                        return Err(collision(identifier.clone(), import.span()));
+                    }
+                    _ => (),
+                }
+            }
+
+            for (identifier, value) in module.values() {
+                match imported_values.insert(identifier.clone(), *value) {
+                    Some(existing) if existing != *value => {
+                        return Err(collision(identifier.clone(), import.span()));
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        for constant in &constants {
+            if imported_values.contains_key(constant.identifier()) {
+                return Err(collision(constant.identifier().clone(), constant.span()));
+            }
+        }
+
+        for function in &functions {
+            let identifier = function.signature().identifier();
+
+            if imported_functions.contains_key(identifier) {
+                return Err(collision(identifier.clone(), function.signature().span()));
+            }
+        }
+
+        for (identifier, value) in imported_values {
+            context.push_value(identifier, value);
+        }
+
+        constant::evaluate(&constants, &mut context)?;
+
+        for function in imported_functions.into_values().chain(functions) {
+            context.set_function(function);
+        }
+
+        Ok(Self {
+            context,
+            imports,
+            constants,
+        })
     }
 
     pub fn context(&self) -> &Context {
         &self.context
     }
+
+    pub fn imports(&self) -> &[Import] {
+        &self.imports
+    }
+
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+
+    /// Renders the body at `sample_rate` instead of the one it was parsed
+    /// with. Constants are not evaluated again.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.context.set_sample_rate(sample_rate);
+    }
+
+    /// Whether the body defines separate `left` and `right` channels.
+    pub fn is_stereo(&self) -> bool {
+        self.context().function("left").is_some() && self.context().function("right").is_some()
+    }
+
+    /// Simplifies the body's functions, expands calls to small ones and
+    /// evaluates their time-invariant parts ahead of time. See
+    /// [`optimizer::optimize`], [`inliner`] and [`hoister::hoist`].
+    pub fn optimize(&mut self) {
+        optimizer::optimize(&mut self.context);
+        inliner::inline(&mut self.context, inliner::DEFAULT_THRESHOLD);
+        // Expanded bodies may have constant arguments to fold.
+        optimizer::optimize(&mut self.context);
+
+        let hoisted = hoister::hoist(&mut self.context, hoister::DEFAULT_THRESHOLD);
+        info!("Hoisted {} time-invariant nodes", hoisted);
+
+        // Hoisted values may complete identities such as `x * 1`.
+        optimizer::optimize(&mut self.context);
+    }
+
+    /// Statically checks the body's functions. See [`validation::validate`].
+    pub fn validate(&self) -> Vec<MusathError> {
+        validation::validate(self.context())
+    }
+}
+
+fn collision(identifier: String, span: Span) -> MusathError {
+    MusathError::with_span(ErrorKind::NameCollision(identifier), span)
 }
@@ -0,0 +1,917 @@
+//! The functions every document can call without defining them.
+//!
+//! [`BUILTINS`] is the single list of them: [`Context::default`] registers
+//! each one, the bytecode compiler, the JIT and the block evaluator look up
+//! the plain numeric ones there to call the same Rust functions the
+//! tree-walker does, and the table of built-in functions in the README is
+//! [`table`].
+//!
+//! Some numeric functions also receive a [`Setting`] of the [`Context`]
+//! after their arguments, such as the sample rate the
+//! [oscillators](crate::oscillator) depend on.
+
+use std::{
+    fmt::{self, Debug, Write},
+    sync::Arc,
+};
+
+use crate::{
+    context::{Context, Setting},
+    envelope::{self, Adsr},
+    error::{ErrorKind, MusathError},
+    expression::{Expression, Primary},
+    function::{Arity, Function},
+    oscillator, pitch, tuning,
+};
+
+/// How a built-in function computes its value.
+#[derive(Clone, Copy)]
+pub enum Implementation {
+    Unary(fn(f64) -> f64),
+    Binary(fn(f64, f64) -> f64),
+    Ternary(fn(f64, f64, f64) -> f64),
+    Quaternary(fn(f64, f64, f64, f64) -> f64),
+    /// Receives all the arguments at once, for functions taking more than
+    /// four or any number of them.
+    Slice(fn(&[f64]) -> f64),
+    /// Receives the unevaluated arguments, like `sum` with its loop
+    /// variable.
+    Lazy(fn(&[Box<Expression>], &mut Context) -> Result<f64, MusathError>),
+}
+
+/// A built-in function and its documentation.
+pub struct Builtin {
+    identifier: &'static str,
+    parameters: &'static [&'static str],
+    arity: Arity,
+    setting: Option<Setting>,
+    implementation: Implementation,
+    meaning: &'static str,
+    example: &'static str,
+    value: &'static str,
+}
+
+impl Builtin {
+    const fn new(
+        identifier: &'static str,
+        parameters: &'static [&'static str],
+        implementation: Implementation,
+        meaning: &'static str,
+        example: &'static str,
+        value: &'static str,
+    ) -> Self {
+        Self {
+            identifier,
+            parameters,
+            arity: Arity::Exact(parameters.len()),
+            setting: None,
+            implementation,
+            meaning,
+            example,
+            value,
+        }
+    }
+
+    /// Accepts any number of arguments beyond the parameters.
+    const fn variadic(mut self) -> Self {
+        self.arity = Arity::AtLeast(self.parameters.len());
+        self
+    }
+
+    /// Accepts the numbers of arguments `arity` allows instead of one per
+    /// parameter.
+    const fn with_arity(mut self, arity: Arity) -> Self {
+        self.arity = arity;
+        self
+    }
+
+    /// Passes `setting` to the implementation after the arguments.
+    const fn with_setting(mut self, setting: Setting) -> Self {
+        self.setting = Some(setting);
+        self
+    }
+
+    pub fn identifier(&self) -> &'static str {
+        self.identifier
+    }
+
+    pub fn parameters(&self) -> &'static [&'static str] {
+        self.parameters
+    }
+
+    pub fn implementation(&self) -> Implementation {
+        self.implementation
+    }
+
+    /// The setting the implementation takes after the arguments, which
+    /// compilers pass as constants.
+    pub fn setting(&self) -> Option<Setting> {
+        self.setting
+    }
+
+    pub fn arity(&self) -> Arity {
+        self.arity
+    }
+
+    /// The function to register in a [`Context`], which checks the number of
+    /// arguments before computing its value.
+    pub fn function(&'static self) -> Function {
+        let body = Arc::new(
+            move |arguments: &[Box<Expression>], context: &mut Context| {
+                let arity = self.arity();
+
+                if !arity.accepts(arguments.len()) {
+                    return Err(MusathError::new(ErrorKind::ArityMismatch {
+                        function: self.identifier.to_string(),
+                        expected: arity,
+                        found: arguments.len(),
+                    }));
+                }
+
+                if let Implementation::Lazy(function) = self.implementation {
+                    return function(arguments, context);
+                }
+
+                let mut values = arguments
+                    .iter()
+                    .map(|argument| argument.eval(context))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                // The values of the setting follow the arguments.
+                if let Some(setting) = self.setting {
+                    values.extend_from_slice(context.setting(setting));
+                }
+
+                Ok(match self.implementation {
+                    Implementation::Unary(function) => function(values[0]),
+                    Implementation::Binary(function) => function(values[0], values[1]),
+                    Implementation::Ternary(function) => function(values[0], values[1], values[2]),
+                    Implementation::Quaternary(function) => {
+                        function(values[0], values[1], values[2], values[3])
+                    }
+                    Implementation::Slice(function) => function(&values),
+                    Implementation::Lazy(_) => unreachable!("expected numeric builtin"),
+                })
+            },
+        );
+
+        Function::with_arity(self.identifier, self.parameters, self.arity, body)
+    }
+
+    /// The signature as written in the README, such as `clamp(x, lo, hi)`.
+    /// Optional parameters are in brackets, and repeated ones are followed by
+    /// an ellipsis.
+    pub fn signature(&self) -> String {
+        let (required, rest) = match self.arity {
+            Arity::OneOf(counts) => self.parameters.split_at(counts[0]),
+            _ => (self.parameters, &[][..]),
+        };
+
+        let optional = rest
+            .iter()
+            .map(|parameter| format!("[, {}]", parameter))
+            .collect::<String>();
+        let ellipsis = match self.arity {
+            Arity::AtLeast(_) | Arity::Groups { .. } => ", ...",
+            Arity::Exact(_) | Arity::OneOf(_) => "",
+        };
+
+        format!(
+            "{}({}{}{})",
+            self.identifier,
+            required.join(", "),
+            optional,
+            ellipsis
+        )
+    }
+}
+
+impl Debug for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Builtin({})", self.identifier)
+    }
+}
+
+/// Built-in functions are identified by name.
+impl PartialEq for Builtin {
+    fn eq(&self, other: &Self) -> bool {
+        self.identifier == other.identifier
+    }
+}
+
+/// The built-in function called `identifier`, if there is one.
+pub fn builtin(identifier: &str) -> Option<&'static Builtin> {
+    BUILTINS
+        .iter()
+        .find(|builtin| builtin.identifier == identifier)
+}
+
+/// The README's table of built-in functions.
+pub fn table() -> String {
+    let mut table = String::from("| Signature | Meaning | Example | Value |\n|-|-|-|-|\n");
+
+    for builtin in BUILTINS {
+        writeln!(
+            table,
+            "| `{}` | {} | `{}` | `{}` |",
+            builtin.signature(),
+            builtin.meaning,
+            builtin.example,
+            builtin.value
+        )
+        .unwrap();
+    }
+
+    table
+}
+
+pub const BUILTINS: &[Builtin] = &[
+    Builtin::new(
+        "abs",
+        &["x"],
+        Implementation::Unary(abs),
+        "Absolute value of `x`",
+        "abs(-1)",
+        "1",
+    ),
+    Builtin::new(
+        "sign",
+        &["x"],
+        Implementation::Unary(sign),
+        "`-1`, `0` or `1` for negative, zero or positive `x`",
+        "sign(-3)",
+        "-1",
+    ),
+    Builtin::new(
+        "min",
+        &["l", "r"],
+        Implementation::Binary(min),
+        "Minimum value between `l` and `r`",
+        "min(2, 3)",
+        "2",
+    ),
+    Builtin::new(
+        "max",
+        &["l", "r"],
+        Implementation::Binary(max),
+        "Maximum value between `l` and `r`",
+        "max(4, 5)",
+        "5",
+    ),
+    Builtin::new(
+        "clamp",
+        &["x", "lo", "hi"],
+        Implementation::Ternary(clamp),
+        "`x` limited to the range from `lo` to `hi`",
+        "clamp(1.5, 0, 1)",
+        "1",
+    ),
+    Builtin::new(
+        "floor",
+        &["x"],
+        Implementation::Unary(floor),
+        "Floor of `x`",
+        "floor(0.5)",
+        "0",
+    ),
+    Builtin::new(
+        "ceil",
+        &["x"],
+        Implementation::Unary(ceil),
+        "Ceiling of `x`",
+        "ceil(0.5)",
+        "1",
+    ),
+    Builtin::new(
+        "round",
+        &["x"],
+        Implementation::Unary(round),
+        "`x` rounded to the nearest integer, away from zero when halfway",
+        "round(2.5)",
+        "3",
+    ),
+    Builtin::new(
+        "trunc",
+        &["x"],
+        Implementation::Unary(trunc),
+        "Integer part of `x`, rounded towards zero",
+        "trunc(-1.7)",
+        "-1",
+    ),
+    Builtin::new(
+        "fract",
+        &["x"],
+        Implementation::Unary(fract),
+        "Fractional part of `x`, with the sign of `x`",
+        "fract(-1.25)",
+        "-0.25",
+    ),
+    Builtin::new(
+        "fmod",
+        &["x", "y"],
+        Implementation::Binary(fmod),
+        "Remainder of `x / y` with the sign of `x`, unlike the Euclidean `%`",
+        "fmod(-7, 3)",
+        "-1",
+    ),
+    Builtin::new(
+        "sqrt",
+        &["x"],
+        Implementation::Unary(sqrt),
+        "Square root of `x`",
+        "sqrt(16)",
+        "4",
+    ),
+    Builtin::new(
+        "cbrt",
+        &["x"],
+        Implementation::Unary(cbrt),
+        "Cube root of `x`",
+        "cbrt(-27)",
+        "-3",
+    ),
+    Builtin::new(
+        "hypot",
+        &["x", "y"],
+        Implementation::Binary(hypot),
+        "Length of the hypotenuse of a right triangle with sides `x` and `y`",
+        "hypot(3, 4)",
+        "5",
+    ),
+    Builtin::new(
+        "exp",
+        &["x"],
+        Implementation::Unary(exp),
+        "`e` to the power of `x`",
+        "exp(1)",
+        "2.7183",
+    ),
+    Builtin::new(
+        "ln",
+        &["x"],
+        Implementation::Unary(ln),
+        "Natural logarithm of `x`",
+        "ln(e)",
+        "1",
+    ),
+    Builtin::new(
+        "log2",
+        &["x"],
+        Implementation::Unary(log2),
+        "Base 2 logarithm of `x`",
+        "log2(8)",
+        "3",
+    ),
+    Builtin::new(
+        "log10",
+        &["x"],
+        Implementation::Unary(log10),
+        "Base 10 logarithm of `x`",
+        "log10(1000)",
+        "3",
+    ),
+    Builtin::new(
+        "sin",
+        &["x"],
+        Implementation::Unary(sin),
+        "Sine of `x` radians",
+        "sin(pi/2)",
+        "1",
+    ),
+    Builtin::new(
+        "cos",
+        &["x"],
+        Implementation::Unary(cos),
+        "Cosine of `x` radians",
+        "cos(0)",
+        "1",
+    ),
+    Builtin::new(
+        "tan",
+        &["x"],
+        Implementation::Unary(tan),
+        "Tangent of `x` radians",
+        "tan(pi/4)",
+        "1",
+    ),
+    Builtin::new(
+        "asin",
+        &["x"],
+        Implementation::Unary(asin),
+        "Arcsine of `x`, in radians",
+        "asin(1)",
+        "1.5708",
+    ),
+    Builtin::new(
+        "acos",
+        &["x"],
+        Implementation::Unary(acos),
+        "Arccosine of `x`, in radians",
+        "acos(1)",
+        "0",
+    ),
+    Builtin::new(
+        "atan",
+        &["x"],
+        Implementation::Unary(atan),
+        "Arctangent of `x`, in radians",
+        "atan(1)",
+        "0.7854",
+    ),
+    Builtin::new(
+        "atan2",
+        &["y", "x"],
+        Implementation::Binary(atan2),
+        "Angle in radians from the positive x axis to the point (`x`, `y`)",
+        "atan2(1, -1)",
+        "2.3562",
+    ),
+    Builtin::new(
+        "sinh",
+        &["x"],
+        Implementation::Unary(sinh),
+        "Hyperbolic sine of `x`",
+        "sinh(1)",
+        "1.1752",
+    ),
+    Builtin::new(
+        "cosh",
+        &["x"],
+        Implementation::Unary(cosh),
+        "Hyperbolic cosine of `x`",
+        "cosh(0)",
+        "1",
+    ),
+    Builtin::new(
+        "tanh",
+        &["x"],
+        Implementation::Unary(tanh),
+        "Hyperbolic tangent of `x`",
+        "tanh(1)",
+        "0.7616",
+    ),
+    Builtin::new(
+        "lerp",
+        &["a", "b", "x"],
+        Implementation::Ternary(lerp),
+        "`x` of the way from `a` to `b`",
+        "lerp(10, 20, 0.25)",
+        "12.5",
+    ),
+    Builtin::new(
+        "smoothstep",
+        &["lo", "hi", "x"],
+        Implementation::Ternary(smoothstep),
+        "`0` below `lo`, `1` above `hi`, and a smooth curve in between",
+        "smoothstep(0, 2, 0.5)",
+        "0.15625",
+    ),
+    Builtin::new(
+        "saw",
+        &["freq", "t"],
+        Implementation::Ternary(saw),
+        "Band-limited sawtooth wave of frequency `freq` rising from `-1` to `1`",
+        "saw(1, 0.25)",
+        "-0.5",
+    )
+    .with_setting(Setting::SampleRate),
+    Builtin::new(
+        "square",
+        &["freq", "t", "width"],
+        Implementation::Quaternary(square),
+        "Band-limited square wave, `1` for the first `width` of each period and `-1` after",
+        "square(1, 0.75, 0.5)",
+        "-1",
+    )
+    .with_setting(Setting::SampleRate),
+    Builtin::new(
+        "tri",
+        &["freq", "t"],
+        Implementation::Ternary(tri),
+        "Band-limited triangle wave in phase with `sin(freq * t * tau)`",
+        "tri(1, 0.125)",
+        "0.5",
+    )
+    .with_setting(Setting::SampleRate),
+    Builtin::new(
+        "pulse",
+        &["freq", "t", "width"],
+        Implementation::Quaternary(pulse),
+        "Band-limited pulse wave, `1` for the first `width` of each period and `0` after",
+        "pulse(1, 0.25, 0.5)",
+        "1",
+    )
+    .with_setting(Setting::SampleRate),
+    Builtin::new(
+        "adsr",
+        &["t", "gate_start", "gate_len", "a", "d", "s", "r", "curve"],
+        Implementation::Slice(adsr),
+        "Envelope of a note held from `gate_start` for `gate_len`, straight or bent by `curve`",
+        "adsr(0.2, 0, 1, 0.1, 0.2, 0.5, 0.3, -4)",
+        "0.5596",
+    )
+    .with_arity(Arity::OneOf(&[7, 8])),
+    Builtin::new(
+        "env",
+        &["t", "t0", "v0", "c1", "t1", "v1"],
+        Implementation::Slice(env),
+        "Envelope through the value `vn` at each time `tn`, along segments bent by `cn`",
+        "env(1.5, 0, 0, 0, 1, 1, 0, 2, 0)",
+        "0.5",
+    )
+    .with_arity(Arity::Groups { first: 3, group: 3 }),
+    Builtin::new(
+        "mtof",
+        &["note"],
+        Implementation::Binary(mtof),
+        "Frequency of the MIDI note number `note`, with `A4` at `69`",
+        "mtof(60)",
+        "261.6256",
+    )
+    .with_setting(Setting::A4Hz),
+    Builtin::new(
+        "ftom",
+        &["hz"],
+        Implementation::Binary(ftom),
+        "MIDI note number of the frequency `hz`, the inverse of `mtof`",
+        "ftom(880)",
+        "81",
+    )
+    .with_setting(Setting::A4Hz),
+    Builtin::new(
+        "semitones",
+        &["hz", "n"],
+        Implementation::Binary(semitones),
+        "`hz` raised by `n` equal-tempered semitones",
+        "semitones(A4, 7)",
+        "659.2551",
+    ),
+    Builtin::new(
+        "cents",
+        &["hz", "c"],
+        Implementation::Binary(cents),
+        "`hz` raised by `c` cents, hundredths of a semitone",
+        "cents(440, -100)",
+        "415.3047",
+    ),
+    Builtin::new(
+        "degree",
+        &["n"],
+        Implementation::Slice(degree),
+        "Frequency of degree `n` of the `TUNING` scale, from `0` at middle C",
+        "degree(9)",
+        "440",
+    )
+    .with_setting(Setting::Tuning),
+    Builtin::new(
+        "mix",
+        &["x"],
+        Implementation::Lazy(mix),
+        "The mean of all the arguments",
+        "mix(1, 2, 6)",
+        "3",
+    )
+    .variadic(),
+    Builtin::new(
+        "sum",
+        &["x", "start", "end", "expression"],
+        Implementation::Lazy(sum),
+        "Sum of `expression` for each integer `x` from `start` up to but excluding `end`",
+        "sum(n, 1, 5, n*2)",
+        "20",
+    ),
+    Builtin::new(
+        "prod",
+        &["x", "start", "end", "expression"],
+        Implementation::Lazy(prod),
+        "Product of `expression` for each integer `x` from `start` up to but excluding `end`",
+        "prod(n, 1, 5, n+1)",
+        "120",
+    ),
+];
+
+fn abs(x: f64) -> f64 {
+    x.abs()
+}
+
+fn sign(x: f64) -> f64 {
+    if x > 0.0 {
+        1.0
+    } else if x < 0.0 {
+        -1.0
+    } else {
+        x
+    }
+}
+
+fn min(l: f64, r: f64) -> f64 {
+    l.min(r)
+}
+
+fn max(l: f64, r: f64) -> f64 {
+    l.max(r)
+}
+
+/// Unlike [`f64::clamp`], does not panic if `lo > hi`, in which case the
+/// result is `hi`.
+fn clamp(x: f64, lo: f64, hi: f64) -> f64 {
+    x.max(lo).min(hi)
+}
+
+fn floor(x: f64) -> f64 {
+    x.floor()
+}
+
+fn ceil(x: f64) -> f64 {
+    x.ceil()
+}
+
+fn round(x: f64) -> f64 {
+    x.round()
+}
+
+fn trunc(x: f64) -> f64 {
+    x.trunc()
+}
+
+fn fract(x: f64) -> f64 {
+    x.fract()
+}
+
+fn fmod(x: f64, y: f64) -> f64 {
+    x % y
+}
+
+fn sqrt(x: f64) -> f64 {
+    x.sqrt()
+}
+
+fn cbrt(x: f64) -> f64 {
+    x.cbrt()
+}
+
+fn hypot(x: f64, y: f64) -> f64 {
+    x.hypot(y)
+}
+
+fn exp(x: f64) -> f64 {
+    x.exp()
+}
+
+fn ln(x: f64) -> f64 {
+    x.ln()
+}
+
+fn log2(x: f64) -> f64 {
+    x.log2()
+}
+
+fn log10(x: f64) -> f64 {
+    x.log10()
+}
+
+fn sin(x: f64) -> f64 {
+    x.sin()
+}
+
+fn cos(x: f64) -> f64 {
+    x.cos()
+}
+
+fn tan(x: f64) -> f64 {
+    x.tan()
+}
+
+fn asin(x: f64) -> f64 {
+    x.asin()
+}
+
+fn acos(x: f64) -> f64 {
+    x.acos()
+}
+
+fn atan(x: f64) -> f64 {
+    x.atan()
+}
+
+fn atan2(y: f64, x: f64) -> f64 {
+    y.atan2(x)
+}
+
+fn sinh(x: f64) -> f64 {
+    x.sinh()
+}
+
+fn cosh(x: f64) -> f64 {
+    x.cosh()
+}
+
+fn tanh(x: f64) -> f64 {
+    x.tanh()
+}
+
+fn lerp(a: f64, b: f64, x: f64) -> f64 {
+    a + (b - a) * x
+}
+
+fn smoothstep(lo: f64, hi: f64, x: f64) -> f64 {
+    let x = clamp((x - lo) / (hi - lo), 0.0, 1.0);
+
+    x * x * (3.0 - 2.0 * x)
+}
+
+fn saw(freq: f64, t: f64, sample_rate: f64) -> f64 {
+    oscillator::saw(freq, t, sample_rate)
+}
+
+fn square(freq: f64, t: f64, width: f64, sample_rate: f64) -> f64 {
+    oscillator::square(freq, t, width, sample_rate)
+}
+
+fn tri(freq: f64, t: f64, sample_rate: f64) -> f64 {
+    oscillator::tri(freq, t, sample_rate)
+}
+
+fn pulse(freq: f64, t: f64, width: f64, sample_rate: f64) -> f64 {
+    oscillator::pulse(freq, t, width, sample_rate)
+}
+
+fn mtof(note: f64, a4_hz: f64) -> f64 {
+    pitch::mtof(note, a4_hz)
+}
+
+fn ftom(hz: f64, a4_hz: f64) -> f64 {
+    pitch::ftom(hz, a4_hz)
+}
+
+fn semitones(hz: f64, n: f64) -> f64 {
+    pitch::semitones(hz, n)
+}
+
+fn cents(hz: f64, c: f64) -> f64 {
+    pitch::cents(hz, c)
+}
+
+/// Takes an optional curve after the other arguments, which is straight by
+/// default.
+fn adsr(arguments: &[f64]) -> f64 {
+    let (&[t, gate_start, gate_len, a, d, s, r], curve) = arguments
+        .split_first_chunk::<7>()
+        .expect("expected 7 or 8 arguments");
+
+    Adsr::new(a, d, s, r)
+        .with_curve(curve.first().copied().unwrap_or(0.0))
+        .value(t, gate_start, gate_len)
+}
+
+fn env(arguments: &[f64]) -> f64 {
+    let (t, breakpoints) = arguments.split_first().expect("expected a time");
+
+    envelope::env(*t, breakpoints)
+}
+
+fn degree(arguments: &[f64]) -> f64 {
+    let (n, table) = arguments.split_first().expect("expected a degree");
+
+    tuning::degree(*n, table)
+}
+
+fn mix(arguments: &[Box<Expression>], context: &mut Context) -> Result<f64, MusathError> {
+    Ok(arguments
+        .iter()
+        .map(|argument| argument.eval(context))
+        .sum::<Result<f64, MusathError>>()?
+        / arguments.len() as f64)
+}
+
+fn sum(arguments: &[Box<Expression>], context: &mut Context) -> Result<f64, MusathError> {
+    fold("sum", arguments, context)?.sum()
+}
+
+fn prod(arguments: &[Box<Expression>], context: &mut Context) -> Result<f64, MusathError> {
+    fold("prod", arguments, context)?.product()
+}
+
+/// The terms of `sum` or `prod`: the last argument evaluated with the
+/// identifier in the first bound to each integer between the rounded second
+/// and third.
+fn fold<'a>(
+    identifier: &'static str,
+    arguments: &'a [Box<Expression>],
+    context: &'a mut Context,
+) -> Result<impl Iterator<Item = Result<f64, MusathError>> + 'a, MusathError> {
+    let Expression::Primary(Primary::Identifier(variable, _)) = arguments[0].as_ref() else {
+        return Err(MusathError::new(ErrorKind::InvalidArgument {
+            function: String::from(identifier),
+            message: format!("expected identifier, found {:?}", arguments[0]),
+        }));
+    };
+
+    let start = arguments[1].eval(context)?.round() as isize;
+    let end = arguments[2].eval(context)?.round() as isize;
+
+    Ok((start..end).map(move |value| {
+        context.push_value(variable, value as f64);
+
+        let term = arguments[3].eval(context);
+
+        context.pop_value(variable);
+
+        term
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::document::Document;
+
+    use super::*;
+
+    #[test]
+    fn test_examples() {
+        for builtin in BUILTINS {
+            let document =
+                Document::from_source(&format!("output(t) = {}", builtin.example)).unwrap();
+
+            let found = document.eval(0.0).unwrap();
+            let expected: f64 = builtin.value.parse().unwrap();
+
+            assert!(
+                (found - expected).abs() < 1e-4,
+                "{}: expected {}, found {}",
+                builtin.example,
+                expected,
+                found
+            );
+        }
+    }
+
+    #[test]
+    fn test_builtins() {
+        assert_eq!(sign(0.0), 0.0);
+        assert_eq!(sign(2.5), 1.0);
+        assert!(sign(f64::NAN).is_nan());
+        assert_eq!(clamp(-1.0, 0.0, 1.0), 0.0);
+        assert_eq!(clamp(0.5, 1.0, 0.0), 0.0);
+        assert_eq!(fmod(7.5, -2.0), 1.5);
+        assert_eq!(smoothstep(0.0, 1.0, -1.0), 0.0);
+        assert_eq!(smoothstep(0.0, 1.0, 2.0), 1.0);
+
+        assert_eq!(builtin("atan2").unwrap().signature(), "atan2(y, x)");
+        assert_eq!(builtin("mix").unwrap().signature(), "mix(x, ...)");
+        assert_eq!(
+            builtin("adsr").unwrap().signature(),
+            "adsr(t, gate_start, gate_len, a, d, s, r[, curve])"
+        );
+        assert!(builtin("output").is_none());
+
+        let error = builtin("hypot")
+            .unwrap()
+            .function()
+            .eval(
+                &[Box::new(Expression::Primary(Primary::Integer(1)))],
+                &mut Context::default(),
+            )
+            .unwrap_err();
+
+        assert!(matches!(
+            error.kind(),
+            ErrorKind::ArityMismatch { function, found: 1, .. } if function == "hypot"
+        ));
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        let t = 0.5 / 8000.0;
+
+        let mut document =
+            Document::from_source("SAMPLE_RATE = 8000\noutput(t) = saw(100, t)").unwrap();
+
+        assert_eq!(document.eval(t).unwrap(), oscillator::saw(100.0, t, 8000.0));
+
+        document.set_sample_rate(48000);
+
+        assert_eq!(
+            document.eval(t).unwrap(),
+            oscillator::saw(100.0, t, 48000.0)
+        );
+
+        let document = Document::from_source("output(t) = pulse(100, t, 0.5)").unwrap();
+
+        assert_eq!(
+            document.eval(t).unwrap(),
+            oscillator::pulse(100.0, t, 0.5, 44100.0)
+        );
+    }
+
+    #[test]
+    fn test_readme_table() {
+        let readme = std::fs::read_to_string("README.md").unwrap();
+
+        assert!(
+            readme.contains(&table()),
+            "the README's table of built-in functions is out of date; replace it with:\n\n{}",
+            table()
+        );
+    }
+}
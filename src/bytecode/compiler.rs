@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+
+use crate::{
+    builtins::{self, Implementation},
+    context::Context,
+    document::Document,
+    error::{ErrorKind, MusathError, Span},
+    expression::{BinaryOperator, Expression, Primary},
+    function::{Function, FunctionBody},
+};
+
+use super::{FunctionInfo, Instruction, Outputs, Program};
+
+/// Compiles the functions `document` renders with, and everything they call.
+pub fn compile(document: &Document) -> Result<Program, MusathError> {
+    let context = document.body().context();
+
+    let mut compiler = Compiler {
+        context,
+        code: Vec::new(),
+        functions: Vec::new(),
+        indices: HashMap::new(),
+        pending: Vec::new(),
+        caches: 0,
+    };
+
+    let outputs = if document.channels() == 2 && document.body().is_stereo() {
+        Outputs::Stereo {
+            left: compiler.entry("left")?,
+            right: compiler.entry("right")?,
+        }
+    } else {
+        Outputs::Output {
+            function: compiler.entry("output")?,
+            channel: context
+                .function("output")
+                .is_some_and(|output| output.signature().parameters().len() == 2),
+        }
+    };
+
+    while let Some((index, function)) = compiler.pending.pop() {
+        compiler.function(index, function)?;
+    }
+
+    Ok(Program {
+        code: compiler.code,
+        functions: compiler.functions,
+        caches: compiler.caches,
+        channels: document.channels(),
+        outputs,
+    })
+}
+
+struct Compiler<'a> {
+    context: &'a Context,
+    code: Vec<Instruction>,
+    functions: Vec<FunctionInfo>,
+    indices: HashMap<&'a str, usize>,
+    /// Functions that have been referred to but not compiled yet.
+    pending: Vec<(usize, &'a Function)>,
+    caches: usize,
+}
+
+/// The slots of the function being compiled. Named slots hold parameters and
+/// bindings; unnamed ones hold the bounds and totals of `sum`/`prod` loops.
+#[derive(Default)]
+struct Scope {
+    slots: Vec<Option<String>>,
+    size: usize,
+}
+
+impl Scope {
+    fn push(&mut self, identifier: Option<&str>) -> usize {
+        self.slots.push(identifier.map(ToString::to_string));
+        self.size = self.size.max(self.slots.len());
+        self.slots.len() - 1
+    }
+
+    fn truncate(&mut self, length: usize) {
+        self.slots.truncate(length);
+    }
+
+    fn resolve(&self, identifier: &str) -> Option<usize> {
+        self.slots
+            .iter()
+            .rposition(|slot| slot.as_deref() == Some(identifier))
+    }
+}
+
+impl<'a> Compiler<'a> {
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        self.code.push(instruction);
+        self.code.len() - 1
+    }
+
+    /// Points the jump at `jump` to the next instruction to be emitted.
+    fn patch(&mut self, jump: usize) {
+        let target = self.code.len();
+
+        match &mut self.code[jump] {
+            Instruction::Jump(destination) | Instruction::JumpIfFalse(destination) => {
+                *destination = target
+            }
+            Instruction::LoopTest { exit, .. } => *exit = target,
+            instruction => unreachable!("expected jump, found {:?}", instruction),
+        }
+    }
+
+    /// The index of one of the functions a document renders with.
+    fn entry(&mut self, identifier: &'static str) -> Result<usize, MusathError> {
+        let function = self
+            .context
+            .function(identifier)
+            .ok_or_else(|| MusathError::new(ErrorKind::MissingOutput))?;
+
+        self.function_index(identifier, function, Span::default())
+    }
+
+    /// The index of a user function, queueing it for compilation the first
+    /// time it is referred to.
+    fn function_index(
+        &mut self,
+        identifier: &'a str,
+        function: &'a Function,
+        span: Span,
+    ) -> Result<usize, MusathError> {
+        if let Some(index) = self.indices.get(identifier) {
+            return Ok(*index);
+        }
+
+        if !matches!(function.body(), FunctionBody::Expression(_)) {
+            return Err(MusathError::with_span(
+                ErrorKind::Uncompilable(identifier.to_string()),
+                span,
+            ));
+        }
+
+        let index = self.functions.len();
+
+        self.functions.push(FunctionInfo {
+            identifier: identifier.to_string(),
+            entry: 0,
+            parameters: function.signature().parameters().len(),
+            slots: 0,
+            control_rate: function.control_rate(),
+        });
+        self.indices.insert(identifier, index);
+        self.pending.push((index, function));
+
+        Ok(index)
+    }
+
+    fn function(&mut self, index: usize, function: &'a Function) -> Result<(), MusathError> {
+        let FunctionBody::Expression(expression) = function.body() else {
+            unreachable!("expected expression body, found {:?}", function.body());
+        };
+
+        let mut scope = Scope::default();
+
+        for parameter in function.signature().parameters() {
+            scope.push(Some(parameter));
+        }
+
+        self.functions[index].entry = self.code.len();
+        self.expression(expression, &mut scope)?;
+        self.emit(Instruction::Return);
+        self.functions[index].slots = scope.size;
+
+        Ok(())
+    }
+
+    fn expression(
+        &mut self,
+        expression: &'a Expression,
+        scope: &mut Scope,
+    ) -> Result<(), MusathError> {
+        match expression {
+            Expression::Binary(left, BinaryOperator::And, right) => {
+                self.expression(left, scope)?;
+                let short_circuit = self.emit(Instruction::JumpIfFalse(0));
+                self.expression(right, scope)?;
+                self.emit(Instruction::Truthy);
+                let end = self.emit(Instruction::Jump(0));
+                self.patch(short_circuit);
+                self.emit(Instruction::Constant(0.0));
+                self.patch(end);
+            }
+            Expression::Binary(left, BinaryOperator::Or, right) => {
+                self.expression(left, scope)?;
+                let evaluate_right = self.emit(Instruction::JumpIfFalse(0));
+                self.emit(Instruction::Constant(1.0));
+                let end = self.emit(Instruction::Jump(0));
+                self.patch(evaluate_right);
+                self.expression(right, scope)?;
+                self.emit(Instruction::Truthy);
+                self.patch(end);
+            }
+            Expression::Binary(left, operator, right) => {
+                self.expression(left, scope)?;
+                self.expression(right, scope)?;
+                self.emit(Instruction::Binary(operator.clone()));
+            }
+            Expression::Unary(operator, operand) => {
+                self.expression(operand, scope)?;
+                self.emit(Instruction::Unary(operator.clone()));
+            }
+            Expression::Primary(primary) => self.primary(primary, scope)?,
+            Expression::Conditional(condition, consequent, alternative) => {
+                self.expression(condition, scope)?;
+                let otherwise = self.emit(Instruction::JumpIfFalse(0));
+                self.expression(consequent, scope)?;
+                let end = self.emit(Instruction::Jump(0));
+                self.patch(otherwise);
+                self.expression(alternative, scope)?;
+                self.patch(end);
+            }
+            Expression::Let(bindings, body) => {
+                let length = scope.slots.len();
+
+                for (identifier, expression) in bindings {
+                    self.expression(expression, scope)?;
+                    let slot = scope.push(Some(identifier));
+                    self.emit(Instruction::Store(slot));
+                }
+
+                self.expression(body, scope)?;
+                scope.truncate(length);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn primary(&mut self, primary: &'a Primary, scope: &mut Scope) -> Result<(), MusathError> {
+        match primary {
+            Primary::Decimal(number) => {
+                self.emit(Instruction::Constant(*number));
+            }
+            Primary::Integer(number) => {
+                self.emit(Instruction::Constant(*number as f64));
+            }
+            Primary::Note(note) => {
+                self.emit(Instruction::Constant(note.frequency(self.context.a4_hz())));
+            }
+            Primary::Grouping(expression) => self.expression(expression, scope)?,
+            Primary::Identifier(identifier, span) => {
+                if let Some(slot) = scope.resolve(identifier) {
+                    self.emit(Instruction::Load(slot));
+                } else if let Some(value) = self.context.value(identifier) {
+                    self.emit(Instruction::Constant(*value));
+                } else {
+                    return Err(MusathError::with_span(
+                        ErrorKind::UndefinedIdentifier(identifier.clone()),
+                        *span,
+                    ));
+                }
+            }
+            Primary::Call(identifier, arguments, span) => {
+                let function = self.context.function(identifier).ok_or_else(|| {
+                    MusathError::with_span(ErrorKind::UndefinedFunction(identifier.clone()), *span)
+                })?;
+
+                let arity = function.signature().arity();
+
+                if !arity.accepts(arguments.len()) {
+                    return Err(MusathError::with_span(
+                        ErrorKind::ArityMismatch {
+                            function: identifier.clone(),
+                            expected: arity,
+                            found: arguments.len(),
+                        },
+                        *span,
+                    ));
+                }
+
+                match function.body() {
+                    FunctionBody::Expression(_) => {
+                        let index = self.function_index(identifier, function, *span)?;
+
+                        for argument in arguments {
+                            self.expression(argument, scope)?;
+                        }
+
+                        if function.control_rate().is_some() {
+                            self.emit(Instruction::ControlCall {
+                                function: index,
+                                arguments: arguments.len(),
+                                cache: self.caches,
+                            });
+                            self.caches += 1;
+                        } else {
+                            self.emit(Instruction::Call {
+                                function: index,
+                                arguments: arguments.len(),
+                            });
+                        }
+                    }
+                    FunctionBody::Closure(_) => {
+                        self.builtin(identifier, arguments, *span, scope)?
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn builtin(
+        &mut self,
+        identifier: &'a str,
+        arguments: &'a [Box<Expression>],
+        span: Span,
+        scope: &mut Scope,
+    ) -> Result<(), MusathError> {
+        if let Some(builtin) = builtins::builtin(identifier)
+            && !matches!(builtin.implementation(), Implementation::Lazy(_))
+        {
+            for argument in arguments {
+                self.expression(argument, scope)?;
+            }
+
+            let setting = builtin
+                .setting()
+                .map_or(&[][..], |setting| self.context.setting(setting));
+
+            for value in setting {
+                self.emit(Instruction::Constant(*value));
+            }
+
+            let instruction = match builtin.implementation() {
+                Implementation::Slice(_) => {
+                    Instruction::MathSlice(builtin, arguments.len() + setting.len())
+                }
+                _ => Instruction::Math(builtin),
+            };
+
+            self.emit(instruction);
+            return Ok(());
+        }
+
+        match identifier {
+            "mix" => {
+                for argument in arguments {
+                    self.expression(argument, scope)?;
+                }
+
+                self.emit(Instruction::Mix(arguments.len()));
+            }
+            "sum" => self.fold(
+                identifier,
+                arguments,
+                span,
+                scope,
+                BinaryOperator::Add,
+                std::iter::empty::<f64>().sum(),
+            )?,
+            "prod" => self.fold(
+                identifier,
+                arguments,
+                span,
+                scope,
+                BinaryOperator::Multiply,
+                std::iter::empty::<f64>().product(),
+            )?,
+            _ => {
+                return Err(MusathError::with_span(
+                    ErrorKind::Uncompilable(identifier.to_string()),
+                    span,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compiles `sum` or `prod`: a loop combining the values of the last
+    /// argument into an accumulator starting at `identity`.
+    fn fold(
+        &mut self,
+        identifier: &str,
+        arguments: &'a [Box<Expression>],
+        span: Span,
+        scope: &mut Scope,
+        operator: BinaryOperator,
+        identity: f64,
+    ) -> Result<(), MusathError> {
+        let Expression::Primary(Primary::Identifier(variable, _)) = arguments[0].as_ref() else {
+            return Err(MusathError::with_span(
+                ErrorKind::InvalidArgument {
+                    function: identifier.to_string(),
+                    message: format!("expected identifier, found {:?}", arguments[0]),
+                },
+                span,
+            ));
+        };
+
+        let length = scope.slots.len();
+
+        self.expression(&arguments[1], scope)?;
+        self.emit(Instruction::Round);
+        let start = scope.push(None);
+        self.emit(Instruction::Store(start));
+
+        self.expression(&arguments[2], scope)?;
+        self.emit(Instruction::Round);
+        let end = scope.push(None);
+        self.emit(Instruction::Store(end));
+
+        self.emit(Instruction::Constant(identity));
+        let accumulator = scope.push(None);
+        self.emit(Instruction::Store(accumulator));
+
+        let variable = {
+            scope.slots[start] = Some(variable.clone());
+            start
+        };
+
+        let test = self.emit(Instruction::LoopTest {
+            variable,
+            end,
+            exit: 0,
+        });
+        self.expression(&arguments[3], scope)?;
+        self.emit(Instruction::Accumulate {
+            accumulator,
+            operator,
+        });
+        self.emit(Instruction::Increment(variable));
+        self.emit(Instruction::Jump(test));
+        self.patch(test);
+        self.emit(Instruction::Load(accumulator));
+
+        scope.truncate(length);
+
+        Ok(())
+    }
+}
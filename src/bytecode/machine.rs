@@ -0,0 +1,296 @@
+use std::convert::Infallible;
+
+use crate::{
+    builtins::Implementation,
+    control_rate::ControlPoints,
+    expression::is_truthy,
+    wave_provider::{MultichannelWaveProvider, WaveProvider},
+};
+
+use super::{Instruction, Outputs, Program};
+
+/// Where to continue once the current function returns.
+struct Frame {
+    return_address: usize,
+    base: usize,
+}
+
+impl Program {
+    /// Runs the function at index `function` of [`Program::functions`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `arguments` does not have one value per parameter.
+    pub fn call(&self, function: usize, arguments: &[f64]) -> f64 {
+        self.machine().call(function, arguments)
+    }
+
+    /// A machine to run the program's functions with, remembering control
+    /// points between calls.
+    pub fn machine(&self) -> Machine<'_> {
+        Machine {
+            program: self,
+            caches: (0..self.caches).map(|_| None).collect(),
+        }
+    }
+}
+
+/// Runs the functions of a [`Program`]. Between calls, a machine remembers
+/// the control points last computed by each call of a function with a
+/// [control rate](crate::control_rate), so consecutive samples computed by
+/// the same machine share them.
+pub struct Machine<'a> {
+    program: &'a Program,
+    caches: Vec<Option<ControlPoints>>,
+}
+
+impl Machine<'_> {
+    /// Runs the function at index `function` of [`Program::functions`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `arguments` does not have one value per parameter.
+    pub fn call(&mut self, function: usize, arguments: &[f64]) -> f64 {
+        let info = &self.program.functions[function];
+
+        assert_eq!(
+            arguments.len(),
+            info.parameters,
+            "`{}` takes {} arguments",
+            info.identifier,
+            info.parameters
+        );
+
+        self.run(function, arguments)
+    }
+
+    /// The value of `channel` at time `t`.
+    pub fn value_at_time(&mut self, t: f64, channel: u16) -> f64 {
+        match self.program.outputs {
+            Outputs::Stereo { left, .. } if channel == 0 => self.call(left, &[t]),
+            Outputs::Stereo { right, .. } if channel == 1 => self.call(right, &[t]),
+            Outputs::Stereo { .. } => unreachable!("expected channel 0 or 1, found {}", channel),
+            Outputs::Output {
+                function,
+                channel: false,
+            } => self.call(function, &[t]),
+            Outputs::Output {
+                function,
+                channel: true,
+            } => self.call(function, &[t, channel.into()]),
+        }
+    }
+
+    fn run(&mut self, function: usize, arguments: &[f64]) -> f64 {
+        let program = self.program;
+        let info = &program.functions[function];
+
+        let mut stack: Vec<f64> = Vec::with_capacity(16);
+        let mut locals = Vec::with_capacity(info.slots.max(16));
+        let mut frames: Vec<Frame> = Vec::new();
+        let mut base = 0;
+        let mut ip = info.entry;
+
+        locals.extend_from_slice(arguments);
+        locals.resize(info.slots, 0.0);
+
+        loop {
+            match &program.code[ip] {
+                Instruction::Constant(value) => stack.push(*value),
+                Instruction::Load(slot) => stack.push(locals[base + slot]),
+                Instruction::Store(slot) => locals[base + slot] = pop(&mut stack),
+                Instruction::Unary(operator) => {
+                    let operand = pop(&mut stack);
+
+                    stack.push(operator.eval(operand));
+                }
+                Instruction::Binary(operator) => {
+                    let right = pop(&mut stack);
+                    let left = pop(&mut stack);
+
+                    stack.push(operator.eval(left, right));
+                }
+                Instruction::Truthy => {
+                    let value = pop(&mut stack);
+
+                    stack.push(if is_truthy(value) { 1.0 } else { 0.0 });
+                }
+                Instruction::Jump(target) => {
+                    ip = *target;
+                    continue;
+                }
+                Instruction::JumpIfFalse(target) => {
+                    if !is_truthy(pop(&mut stack)) {
+                        ip = *target;
+                        continue;
+                    }
+                }
+                Instruction::Call {
+                    function,
+                    arguments,
+                } => {
+                    let info = &program.functions[*function];
+
+                    frames.push(Frame {
+                        return_address: ip + 1,
+                        base,
+                    });
+
+                    base = locals.len();
+                    locals.extend(stack.drain(stack.len() - arguments..));
+                    locals.resize(base + info.slots, 0.0);
+                    ip = info.entry;
+                    continue;
+                }
+                Instruction::ControlCall {
+                    function,
+                    arguments,
+                    cache,
+                } => {
+                    let arguments = stack.split_off(stack.len() - arguments);
+                    let value = self.control_call(*function, &arguments, *cache);
+
+                    stack.push(value);
+                }
+                Instruction::Return => match frames.pop() {
+                    Some(frame) => {
+                        locals.truncate(base);
+                        base = frame.base;
+                        ip = frame.return_address;
+                        continue;
+                    }
+                    None => return pop(&mut stack),
+                },
+                Instruction::Math(builtin) => {
+                    let value = match builtin.implementation() {
+                        Implementation::Unary(function) => function(pop(&mut stack)),
+                        Implementation::Binary(function) => {
+                            let y = pop(&mut stack);
+                            let x = pop(&mut stack);
+
+                            function(x, y)
+                        }
+                        Implementation::Ternary(function) => {
+                            let z = pop(&mut stack);
+                            let y = pop(&mut stack);
+                            let x = pop(&mut stack);
+
+                            function(x, y, z)
+                        }
+                        Implementation::Quaternary(function) => {
+                            let w = pop(&mut stack);
+                            let z = pop(&mut stack);
+                            let y = pop(&mut stack);
+                            let x = pop(&mut stack);
+
+                            function(x, y, z, w)
+                        }
+                        Implementation::Slice(_) => {
+                            unreachable!("`{}` takes a slice", builtin.identifier())
+                        }
+                        Implementation::Lazy(_) => {
+                            unreachable!("`{}` is not numeric", builtin.identifier())
+                        }
+                    };
+
+                    stack.push(value);
+                }
+                Instruction::MathSlice(builtin, count) => {
+                    let Implementation::Slice(function) = builtin.implementation() else {
+                        unreachable!("`{}` does not take a slice", builtin.identifier());
+                    };
+
+                    let start = stack.len() - count;
+                    let value = function(&stack[start..]);
+
+                    stack.truncate(start);
+                    stack.push(value);
+                }
+                Instruction::Mix(count) => {
+                    let mix = stack.drain(stack.len() - count..).sum::<f64>() / *count as f64;
+
+                    stack.push(mix);
+                }
+                Instruction::Round => {
+                    let value = pop(&mut stack);
+
+                    stack.push(value.round() as isize as f64);
+                }
+                Instruction::LoopTest {
+                    variable,
+                    end,
+                    exit,
+                } => {
+                    if locals[base + variable] >= locals[base + end] {
+                        ip = *exit;
+                        continue;
+                    }
+                }
+                Instruction::Accumulate {
+                    accumulator,
+                    operator,
+                } => {
+                    let value = pop(&mut stack);
+                    let accumulator = &mut locals[base + accumulator];
+
+                    *accumulator = operator.eval(*accumulator, value);
+                }
+                Instruction::Increment(slot) => locals[base + slot] += 1.0,
+            }
+
+            ip += 1;
+        }
+    }
+
+    /// Interpolates a function with a control rate, reusing the control
+    /// points of the previous call from the same place if they are still
+    /// needed.
+    fn control_call(&mut self, function: usize, arguments: &[f64], cache: usize) -> f64 {
+        let control_rate = self.program.functions[function]
+            .control_rate
+            .expect("expected a function with a control rate");
+
+        let mut points = self.caches[cache].take();
+        let Ok(value) = control_rate.interpolate_cached(&mut points, arguments, |arguments| {
+            Ok::<_, Infallible>(self.run(function, arguments))
+        });
+
+        self.caches[cache] = points;
+
+        value
+    }
+}
+
+fn pop(stack: &mut Vec<f64>) -> f64 {
+    stack.pop().expect("expected a value on the stack")
+}
+
+impl WaveProvider for Program {
+    fn value_at_time(&self, t: f64) -> f64 {
+        MultichannelWaveProvider::value_at_time(self, t, 0)
+    }
+
+    fn fill_block(&self, start_sample: u64, sample_rate: u32, out: &mut [f64]) {
+        MultichannelWaveProvider::fill_block(self, start_sample, sample_rate, 0, out)
+    }
+}
+
+impl MultichannelWaveProvider for Program {
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn value_at_time(&self, t: f64, channel: u16) -> f64 {
+        self.machine().value_at_time(t, channel)
+    }
+
+    /// Computes the block with a single [`Machine`], so consecutive samples
+    /// share control points.
+    fn fill_block(&self, start_sample: u64, sample_rate: u32, channel: u16, out: &mut [f64]) {
+        let mut machine = self.machine();
+
+        for (sample, value) in (start_sample..).zip(out) {
+            *value = machine.value_at_time(sample as f64 / sample_rate as f64, channel);
+        }
+    }
+}
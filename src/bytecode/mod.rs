@@ -0,0 +1,354 @@
+//! A compiled form of a [`Document`](crate::document::Document).
+//!
+//! The tree-walking evaluator looks every name up in a [`Context`] and clones
+//! it on each call. Compiling resolves names ahead of time instead: parameters,
+//! `let` bindings and loop variables become numbered slots in a call frame,
+//! constants are inlined, and calls refer to functions by index. The resulting
+//! [`Program`] is run by a small stack machine.
+//!
+//! Names are resolved lexically, as [`validation`](crate::validation) assumes,
+//! while the tree-walker looks them up in the caller's context. The two agree
+//! on every document that does not rely on a caller's parameter shadowing a
+//! constant, and then produce bit-identical samples.
+//!
+//! [`Context`]: crate::context::Context
+
+use crate::{
+    builtins::Builtin,
+    control_rate::ControlRate,
+    expression::{BinaryOperator, UnaryOperator},
+};
+
+pub mod compiler;
+pub mod machine;
+
+pub use compiler::compile;
+pub use machine::Machine;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    /// Pushes a value.
+    Constant(f64),
+    /// Pushes the value of a slot in the current frame.
+    Load(usize),
+    /// Pops a value into a slot in the current frame.
+    Store(usize),
+    Unary(UnaryOperator),
+    Binary(BinaryOperator),
+    /// Replaces the top of the stack with `1` if it is truthy and `0`
+    /// otherwise.
+    Truthy,
+    Jump(usize),
+    /// Pops a value and jumps if it is not truthy.
+    JumpIfFalse(usize),
+    /// Calls a function with its arguments on top of the stack, which become
+    /// the first slots of its frame.
+    Call {
+        function: usize,
+        arguments: usize,
+    },
+    /// Calls a function with a control rate, with its arguments on top of
+    /// the stack, at the control points around the time in its first
+    /// argument, and interpolates. The control points are remembered in
+    /// `cache` between calls of a [`Machine`](machine::Machine).
+    ControlCall {
+        function: usize,
+        arguments: usize,
+        cache: usize,
+    },
+    /// Returns the top of the stack to the caller.
+    Return,
+    /// Pops the arguments of a numeric builtin and pushes its value.
+    Math(&'static Builtin),
+    /// Pops the given number of arguments of a numeric builtin taking them
+    /// as a slice and pushes its value.
+    MathSlice(&'static Builtin, usize),
+    /// Pops the given number of values and pushes their mean.
+    Mix(usize),
+    /// Rounds the top of the stack to an integer, as `sum` and `prod` do
+    /// with their bounds.
+    Round,
+    /// Jumps to `exit` unless the `variable` slot is less than the `end` slot.
+    LoopTest {
+        variable: usize,
+        end: usize,
+        exit: usize,
+    },
+    /// Pops a value and combines it into the `accumulator` slot.
+    Accumulate {
+        accumulator: usize,
+        operator: BinaryOperator,
+    },
+    /// Adds one to a slot.
+    Increment(usize),
+}
+
+/// Where a compiled function starts and how large its frame is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionInfo {
+    identifier: String,
+    entry: usize,
+    parameters: usize,
+    slots: usize,
+    control_rate: Option<ControlRate>,
+}
+
+impl FunctionInfo {
+    pub fn identifier(&self) -> &String {
+        &self.identifier
+    }
+
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    pub fn parameters(&self) -> usize {
+        self.parameters
+    }
+
+    /// The number of slots in the function's frame, including its
+    /// parameters.
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    pub fn control_rate(&self) -> Option<ControlRate> {
+        self.control_rate
+    }
+}
+
+/// A compiled document. See [`compile`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    code: Vec<Instruction>,
+    functions: Vec<FunctionInfo>,
+    /// The number of [`Instruction::ControlCall`]s.
+    caches: usize,
+    channels: u16,
+    outputs: Outputs,
+}
+
+/// The functions producing each channel, chosen as by
+/// [`Document::eval_channel`](crate::document::Document::eval_channel).
+#[derive(Debug, Clone, PartialEq)]
+enum Outputs {
+    Stereo { left: usize, right: usize },
+    Output { function: usize, channel: bool },
+}
+
+impl Program {
+    pub fn code(&self) -> &[Instruction] {
+        &self.code
+    }
+
+    pub fn functions(&self) -> &[FunctionInfo] {
+        &self.functions
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::{document::Document, error::ErrorKind, wave_provider::MultichannelWaveProvider};
+
+    use super::*;
+
+    const EXAMPLES: [&str; 6] = [
+        "delay.mth",
+        "harmonics.mth",
+        "noise.mth",
+        "organ.mth",
+        "pulse.mth",
+        "test.mth",
+    ];
+
+    fn document(path: &str) -> Document {
+        let source = std::fs::read_to_string(path).unwrap();
+
+        Document::from_source_at(&source, path).unwrap()
+    }
+
+    /// Both evaluators at `count` points spread over the document's duration.
+    fn assert_identical(document: &Document, count: usize) {
+        let program = compile(document).unwrap();
+        let duration = document.header().duration().unwrap().unwrap_or(1.0);
+
+        assert_eq!(program.channels(), document.channels());
+
+        for index in 0..count {
+            let t = (index * 7919 % count) as f64 / count as f64 * duration;
+
+            for channel in 0..document.channels() {
+                let expected = document.eval_channel(t, channel).unwrap();
+                let found = program.value_at_time(t, channel);
+
+                assert_eq!(
+                    found.to_bits(),
+                    expected.to_bits(),
+                    "channel {} at t = {}: expected {}, found {}",
+                    channel,
+                    t,
+                    expected,
+                    found
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_compile_examples() {
+        for path in EXAMPLES {
+            assert_identical(&document(path), 500);
+        }
+    }
+
+    #[test]
+    fn test_compile() {
+        let document = Document::from_source(
+            "
+            loop(n) = if n <= 0 then 0 else n + loop(n - 1);
+            binding(x) = let a = x * 2, b = a + 1 in let a = b * a in a - b;
+            short(x) = x > 1 && loop(x) || -x;
+            folds(t) = sum(i, 0, 4, prod(j, 1, i, j + t)) + sum(i, 3, 1, i) + prod(i, 0, 0, i);
+            builtins(t) = mix(abs(-t), min(t, 2), max(t, 3), floor(t), ceil(t), sin(t) * cos(t));
+            library(t) = tan(t) + asin(t / 8) + acos(t / 8) + atan(t) + atan2(t, 2) +
+                sinh(t) + cosh(t) + tanh(t) + exp(t) + ln(abs(t) + 1) + log2(abs(t) + 1) +
+                log10(abs(t) + 1) + sqrt(abs(t)) + cbrt(t) + sign(t) + round(t) + trunc(t) +
+                fract(t) + clamp(t, -1, 1) + lerp(2, 3, t) + smoothstep(-1, 2, t) + hypot(t, 3) +
+                fmod(t, 0.75);
+            output(t) = loop(3) + binding(t) + short(t) + folds(t) + builtins(t) + library(t);
+            ",
+        )
+        .unwrap();
+
+        assert!(document.validate().is_empty());
+
+        for t in [-2.5, -1.0, 0.0, 0.5, 1.0, 2.0, 7.25] {
+            assert_eq!(
+                program_value(&document, t).to_bits(),
+                document.eval(t).unwrap().to_bits()
+            );
+        }
+
+        let document = Document::from_source(
+            "
+            CHANNELS = 2
+            left(t) = t;
+            right(t) = -t;
+            ",
+        )
+        .unwrap();
+
+        assert_identical(&document, 10);
+
+        let document = Document::from_source(
+            "
+            CHANNELS = 4
+            output(t, channel) = t * channel;
+            ",
+        )
+        .unwrap();
+
+        assert_identical(&document, 10);
+
+        let document = Document::from_source(
+            "
+            SAMPLE_RATE = 8000
+            A4_HZ = 432
+            pitches(t) = sin(2 * pi * (C#3 + mtof(60 + t)) * t) + ftom(Eb2 * (1 + t)) / 100 +
+                semitones(A4, t) / cents(Bb1, 100 * t) + degree(t * 20 - 7) / 1000;
+            envelopes(t) = adsr(t, 0.1, 0.5, 0.05, 0.1, 0.5, 0.2) +
+                adsr(t, 0, 0.3, 0.1, 0.1, 0.2, 0.4, -4) + env(t, 0, 0, 0, 0.2, 1, 0, 0.5, 0.25) +
+                env(t, 0, 1, 3, 0.4, 0, -2, 0.8, 1);
+            output(t) = saw(440, t) + square(220, t, 0.3) + tri(880, -t) + pulse(110, t, 0.5) +
+                envelopes(t) + pitches(t);
+            ",
+        )
+        .unwrap();
+
+        assert_identical(&document, 1000);
+    }
+
+    #[test]
+    fn test_compile_control_rate() {
+        let document = Document::from_source(
+            "
+            @rate(100) decay(t, period) = 1 - (t % period) / period;
+            @rate(250, cubic) wobble(t) = sin(2 * pi * 3 * t) * decay(t, 0.3);
+            output(t) = sin(2 * pi * 440 * t) * decay(t, 0.5) + wobble(t) + wobble(t / 2);
+            ",
+        )
+        .unwrap();
+
+        assert!(document.validate().is_empty());
+        assert_identical(&document, 500);
+
+        // Consecutive samples reuse control points within a block, without
+        // changing the result.
+        let program = compile(&document).unwrap();
+        let mut block = [0.0; 2000];
+
+        MultichannelWaveProvider::fill_block(&program, 17, 8000, 0, &mut block);
+
+        for (sample, value) in (17..).zip(block) {
+            let expected = document.eval(sample as f64 / 8000.0).unwrap();
+
+            assert_eq!(value.to_bits(), expected.to_bits());
+        }
+    }
+
+    fn program_value(document: &Document, t: f64) -> f64 {
+        MultichannelWaveProvider::value_at_time(&compile(document).unwrap(), t, 0)
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let error = compile(&Document::from_source("output(t) = t + x;").unwrap()).unwrap_err();
+
+        assert!(
+            matches!(error.kind(), ErrorKind::UndefinedIdentifier(identifier) if identifier == "x")
+        );
+
+        let error = compile(&Document::from_source("f(t) = t;").unwrap()).unwrap_err();
+
+        assert!(matches!(error.kind(), ErrorKind::MissingOutput));
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture` to compare
+    /// the evaluators' speed.
+    #[test]
+    #[ignore]
+    fn test_speedup() {
+        for path in EXAMPLES {
+            let document = document(path);
+            let program = compile(&document).unwrap();
+            let times = (0..2000).map(|index| index as f64 / 44100.0);
+
+            let start = Instant::now();
+            let expected = times
+                .clone()
+                .map(|t| document.eval(t).unwrap())
+                .sum::<f64>();
+            let tree_walker = start.elapsed();
+
+            let start = Instant::now();
+            let found = times.map(|t| program.value_at_time(t, 0)).sum::<f64>();
+            let bytecode = start.elapsed();
+
+            assert_eq!(found.to_bits(), expected.to_bits());
+
+            println!(
+                "{}: {:?} tree-walking, {:?} compiled, {:.1}x faster",
+                path,
+                tree_walker,
+                bytecode,
+                tree_walker.as_secs_f64() / bytecode.as_secs_f64()
+            );
+        }
+    }
+}
@@ -1,32 +1,100 @@
-use crate::{document::Document, wave_provider::WaveProvider};
+use hound::{SampleFormat, WavSpec};
+
+use crate::{
+    bytecode,
+    document::Document,
+    error::{ErrorKind, MusathError},
+    wave_provider::{MultichannelWaveProvider, WaveProvider},
+};
 
 pub struct Composition {
     title: Option<String>,
     duration: Option<f64>,
-    wave_provider: Box<dyn WaveProvider + Send + Sync>,
+    sample_rate: Option<u32>,
+    bit_depth: Option<u16>,
+    sample_format: Option<SampleFormat>,
+    wave_provider: Box<dyn MultichannelWaveProvider + Send + Sync>,
 }
 
 impl Composition {
-    pub fn from_document(document: Document) -> Self {
-        Self {
-            title: document.header().title().map(ToString::to_string),
-            duration: document.header().duration(),
-            wave_provider: Box::new(document),
-        }
+    /// A composition rendering `document` after optimizing it.
+    pub fn from_document(mut document: Document) -> Result<Self, MusathError> {
+        document.optimize();
+
+        Self::from_unoptimized_document(document)
+    }
+
+    /// A composition rendering `document` exactly as written.
+    pub fn from_unoptimized_document(document: Document) -> Result<Self, MusathError> {
+        let wave_provider = Box::new(bytecode::compile(&document)?);
+
+        Self::from_document_with(&document, wave_provider)
+    }
+
+    /// A composition rendering `document` as native code, after optimizing
+    /// it. See [`jit`](crate::jit).
+    #[cfg(feature = "jit")]
+    pub fn from_document_native(mut document: Document) -> Result<Self, MusathError> {
+        document.optimize();
+
+        let wave_provider = Box::new(crate::jit::compile(&document)?);
+
+        Self::from_document_with(&document, wave_provider)
+    }
+
+    fn from_document_with(
+        document: &Document,
+        wave_provider: Box<dyn MultichannelWaveProvider + Send + Sync>,
+    ) -> Result<Self, MusathError> {
+        Ok(Self {
+            title: document.header().title()?.map(ToString::to_string),
+            duration: document.header().duration()?,
+            sample_rate: document.header().sample_rate()?,
+            bit_depth: document.header().bit_depth()?,
+            sample_format: document.header().sample_format()?,
+            wave_provider,
+        })
     }
 
     pub fn from_function<F: Fn(f64) -> f64 + Send + Sync + 'static>(
         title: impl Into<String>,
         duration: f64,
         function: F,
+    ) -> Self {
+        Self::from_functions(title, duration, [function])
+    }
+
+    /// A composition with one channel per function, such as `[left, right]`.
+    pub fn from_functions<W: WaveProvider + Send + Sync + 'static, const N: usize>(
+        title: impl Into<String>,
+        duration: f64,
+        functions: [W; N],
     ) -> Self {
         Self {
             title: Some(title.into()),
             duration: Some(duration),
-            wave_provider: Box::new(function) as Box<dyn WaveProvider + Send + Sync>,
+            sample_rate: None,
+            bit_depth: None,
+            sample_format: None,
+            wave_provider: Box::new(functions) as Box<dyn MultichannelWaveProvider + Send + Sync>,
         }
     }
 
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = Some(sample_rate);
+        self
+    }
+
+    pub fn with_bit_depth(mut self, bit_depth: u16) -> Self {
+        self.bit_depth = Some(bit_depth);
+        self
+    }
+
+    pub fn with_sample_format(mut self, sample_format: SampleFormat) -> Self {
+        self.sample_format = Some(sample_format);
+        self
+    }
+
     pub fn title(&self) -> Option<&String> {
         self.title.as_ref()
     }
@@ -35,8 +103,97 @@ impl Composition {
         self.duration
     }
 
-    pub fn wave_provider(&self) -> &dyn WaveProvider {
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
+    }
+
+    pub fn bit_depth(&self) -> Option<u16> {
+        self.bit_depth
+    }
+
+    pub fn sample_format(&self) -> Option<SampleFormat> {
+        self.sample_format
+    }
+
+    pub fn wave_provider(&self) -> &dyn MultichannelWaveProvider {
         self.wave_provider.as_ref()
     }
 
+    /// The WAV format to render in, taking anything the composition leaves
+    /// unspecified from `defaults`. A bit depth other than 32 without a
+    /// sample format implies integer samples.
+    pub fn spec(&self, defaults: WavSpec) -> Result<WavSpec, MusathError> {
+        let bits_per_sample = self.bit_depth.unwrap_or(defaults.bits_per_sample);
+
+        let sample_format = match (self.sample_format, bits_per_sample) {
+            (Some(sample_format), _) => sample_format,
+            (None, 32) => defaults.sample_format,
+            (None, _) => SampleFormat::Int,
+        };
+
+        match (sample_format, bits_per_sample) {
+            (SampleFormat::Int, 16 | 24 | 32) | (SampleFormat::Float, 32) => Ok(WavSpec {
+                channels: self.wave_provider.channels(),
+                sample_rate: self.sample_rate.unwrap_or(defaults.sample_rate),
+                bits_per_sample,
+                sample_format,
+            }),
+            _ => Err(MusathError::new(ErrorKind::UnsupportedSampleFormat {
+                bit_depth: bits_per_sample,
+                sample_format,
+            })),
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULTS: WavSpec = WavSpec {
+        channels: 1,
+        sample_rate: 44100,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+
+    #[test]
+    fn test_spec() {
+        let document = Document::from_source(
+            "SAMPLE_RATE = 48000\nBIT_DEPTH = 24\nleft(t) = t\nright(t) = t",
+        )
+        .unwrap();
+        let composition = Composition::from_document(document).unwrap();
+
+        assert_eq!(
+            composition.spec(DEFAULTS).unwrap(),
+            WavSpec {
+                channels: 2,
+                sample_rate: 48000,
+                bits_per_sample: 24,
+                sample_format: SampleFormat::Int,
+            }
+        );
+
+        let composition = composition.with_sample_rate(22050).with_bit_depth(32);
+
+        assert_eq!(composition.spec(DEFAULTS).unwrap().sample_rate, 22050);
+        assert_eq!(
+            composition.spec(DEFAULTS).unwrap().sample_format,
+            SampleFormat::Float
+        );
+
+        let composition = Composition::from_function("test", 1.0, |t| t)
+            .with_bit_depth(16)
+            .with_sample_format(SampleFormat::Float);
+
+        assert!(composition.spec(DEFAULTS).is_err());
+        assert_eq!(
+            Composition::from_function("test", 1.0, |t| t)
+                .spec(DEFAULTS)
+                .unwrap(),
+            DEFAULTS
+        );
+    }
 }
@@ -0,0 +1,289 @@
+use std::collections::{HashMap, HashSet};
+
+use pest::iterators::Pairs;
+
+use crate::{
+    Rule,
+    context::Context,
+    error::{ErrorKind, MusathError, Span},
+    expression::{Expression, LanguageVersion, Primary},
+    validation::BINDING_BUILTINS,
+};
+
+/// A `const NAME = expression` declaration, evaluated once when the body is
+/// loaded.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Constant {
+    identifier: String,
+    expression: Expression,
+    span: Span,
+}
+
+impl Constant {
+    pub fn parse(pairs: &mut Pairs<Rule>, version: LanguageVersion) -> Self {
+        let identifier_pair = pairs.next().unwrap();
+        let expression_pair = pairs.next().unwrap();
+
+        Self {
+            identifier: identifier_pair.as_str().to_string(),
+            expression: Expression::parse_versioned(&mut expression_pair.into_inner(), version),
+            span: Span::from(identifier_pair.as_span()),
+        }
+    }
+
+    pub fn identifier(&self) -> &String {
+        &self.identifier
+    }
+
+    pub fn expression(&self) -> &Expression {
+        &self.expression
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// Evaluates `constants` in dependency order, pushing each value onto
+/// `context`. Constants may refer to each other in any order, as long as no
+/// constant depends on itself. Defining a constant twice is an error.
+pub fn evaluate(constants: &[Constant], context: &mut Context) -> Result<(), MusathError> {
+    let mut evaluation = Evaluation {
+        constants: HashMap::new(),
+        evaluated: HashSet::new(),
+        stack: Vec::new(),
+    };
+
+    for constant in constants {
+        if evaluation
+            .constants
+            .insert(constant.identifier().as_str(), constant)
+            .is_some()
+        {
+            return Err(MusathError::with_span(
+                ErrorKind::DuplicateConstant(constant.identifier().clone()),
+                constant.span(),
+            ));
+        }
+    }
+
+    for constant in constants {
+        evaluation.visit(constant, constant.span(), context)?;
+    }
+
+    Ok(())
+}
+
+struct Evaluation<'a> {
+    constants: HashMap<&'a str, &'a Constant>,
+    evaluated: HashSet<&'a str>,
+    stack: Vec<&'a str>,
+}
+
+impl<'a> Evaluation<'a> {
+    /// Evaluates `constant` after its dependencies. `reference` is where it
+    /// was referred to from, which is where a cycle is reported.
+    fn visit(
+        &mut self,
+        constant: &'a Constant,
+        reference: Span,
+        context: &mut Context,
+    ) -> Result<(), MusathError> {
+        let identifier = constant.identifier().as_str();
+
+        if self.evaluated.contains(identifier) {
+            return Ok(());
+        }
+
+        if let Some(position) = self.stack.iter().position(|entry| *entry == identifier) {
+            let mut cycle = self.stack[position..]
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            cycle.push(identifier.to_string());
+
+            return Err(MusathError::with_span(
+                ErrorKind::CyclicConstant(cycle),
+                reference,
+            ));
+        }
+
+        self.stack.push(identifier);
+
+        let mut dependencies = Vec::new();
+        free_identifiers(constant.expression(), &mut Vec::new(), &mut dependencies);
+
+        for (dependency, span) in dependencies {
+            if let Some(dependency) = self.constants.get(dependency).copied() {
+                self.visit(dependency, span, context)?;
+            }
+        }
+
+        let value = constant.expression().eval(context)?;
+        context.push_value(identifier, value);
+
+        self.stack.pop();
+        self.evaluated.insert(identifier);
+
+        Ok(())
+    }
+}
+
+/// Collects the identifiers `expression` reads that are not bound within it
+/// by a `let` or a `sum`/`prod` loop.
+fn free_identifiers<'a>(
+    expression: &'a Expression,
+    scope: &mut Vec<&'a str>,
+    identifiers: &mut Vec<(&'a str, Span)>,
+) {
+    match expression {
+        Expression::Binary(left, _, right) => {
+            free_identifiers(left, scope, identifiers);
+            free_identifiers(right, scope, identifiers);
+        }
+        Expression::Unary(_, operand) => free_identifiers(operand, scope, identifiers),
+        Expression::Conditional(condition, consequent, alternative) => {
+            free_identifiers(condition, scope, identifiers);
+            free_identifiers(consequent, scope, identifiers);
+            free_identifiers(alternative, scope, identifiers);
+        }
+        Expression::Let(bindings, body) => {
+            for (identifier, expression) in bindings {
+                free_identifiers(expression, scope, identifiers);
+                scope.push(identifier);
+            }
+
+            free_identifiers(body, scope, identifiers);
+            scope.truncate(scope.len() - bindings.len());
+        }
+        Expression::Primary(Primary::Identifier(identifier, span)) => {
+            if !scope.contains(&identifier.as_str()) {
+                identifiers.push((identifier, *span));
+            }
+        }
+        Expression::Primary(Primary::Grouping(expression)) => {
+            free_identifiers(expression, scope, identifiers)
+        }
+        Expression::Primary(Primary::Call(identifier, arguments, _)) => {
+            match (
+                BINDING_BUILTINS.contains(&identifier.as_str()),
+                arguments.split_last(),
+            ) {
+                (true, Some((body, bounds))) => {
+                    // The first argument names the loop variable rather than
+                    // reading it.
+                    for bound in bounds.iter().skip(1) {
+                        free_identifiers(bound, scope, identifiers);
+                    }
+
+                    match bounds.first().map(AsRef::as_ref) {
+                        Some(Expression::Primary(Primary::Identifier(variable, _))) => {
+                            scope.push(variable);
+                            free_identifiers(body, scope, identifiers);
+                            scope.pop();
+                        }
+                        _ => free_identifiers(body, scope, identifiers),
+                    }
+                }
+                _ => {
+                    for argument in arguments {
+                        free_identifiers(argument, scope, identifiers);
+                    }
+                }
+            }
+        }
+        Expression::Primary(Primary::Decimal(_) | Primary::Integer(_) | Primary::Note(_)) => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pest::Parser;
+
+    use crate::{MusathParser, document::Document, expression::BinaryOperator};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_constant() {
+        assert_eq!(
+            Constant::parse(
+                &mut MusathParser::parse(Rule::constant, "const A = 440 * 2;")
+                    .unwrap()
+                    .next()
+                    .unwrap()
+                    .into_inner(),
+                LanguageVersion::default(),
+            ),
+            Constant {
+                identifier: String::from("A"),
+                expression: Expression::Binary(
+                    Box::new(Expression::Primary(Primary::Integer(440))),
+                    BinaryOperator::Multiply,
+                    Box::new(Expression::Primary(Primary::Integer(2))),
+                ),
+                span: Span::new(6, 7, 1, 7),
+            },
+        );
+
+        assert!(MusathParser::parse(Rule::constant, "constA = 1").is_err());
+    }
+
+    #[test]
+    fn test_evaluate_constants() {
+        let document = Document::from_source(
+            "const B = A * 2;\n\
+             const A = floor(pi);\n\
+             const C = sum(B, 0, 2, B) + B;\n\
+             output(t) = A + B + C",
+        )
+        .unwrap();
+
+        assert_eq!(document.body().context().value("A"), Some(&3.0));
+        assert_eq!(document.body().context().value("B"), Some(&6.0));
+        assert_eq!(document.body().context().value("C"), Some(&7.0));
+        assert_eq!(document.eval(0.0).unwrap(), 16.0);
+        assert!(document.validate().is_empty());
+
+        // A loop variable named like a constant shadows it instead of reading
+        // it.
+        let document = Document::from_source(
+            "const X = sum(X, 0, 3, X);\n\
+             const Y = prod(X, 1, 3, X + Y0);\n\
+             const Y0 = 1;\n\
+             output(t) = X + Y",
+        )
+        .unwrap();
+
+        assert_eq!(document.body().context().value("X"), Some(&3.0));
+        assert_eq!(document.body().context().value("Y"), Some(&6.0));
+    }
+
+    #[test]
+    fn test_evaluate_constants_errors() {
+        let error =
+            Document::from_source("const A = B;\nconst B = C + 1;\nconst C = A;").unwrap_err();
+
+        assert!(matches!(
+            error.kind(),
+            ErrorKind::CyclicConstant(cycle) if cycle == &["A", "B", "C", "A"]
+        ));
+        assert_eq!(error.span().unwrap().line(), 3);
+        assert_eq!(error.span().unwrap().column(), 11);
+
+        let error = Document::from_source("const A = 1;\nconst A = 2;").unwrap_err();
+
+        assert!(matches!(
+            error.kind(),
+            ErrorKind::DuplicateConstant(identifier) if identifier == "A"
+        ));
+        assert_eq!(error.span().unwrap().line(), 2);
+
+        let error = Document::from_source("f(t) = t\nconst A = f(1);").unwrap_err();
+
+        assert!(matches!(
+            error.kind(),
+            ErrorKind::UndefinedFunction(identifier) if identifier == "f"
+        ));
+    }
+}
@@ -1,20 +1,64 @@
 use std::{
     collections::HashMap,
     f64::consts::{E, PI, TAU},
-    sync::Arc,
+    sync::{Arc, LazyLock, Mutex, PoisonError},
 };
 
 use crate::{
-    expression::{Expression, Primary},
-    function::Function,
+    builtins::BUILTINS, control_rate::ControlPoints, function::Function, pitch,
+    renderer::DEFAULT_SPEC, tuning::Tuning,
 };
 
-#[derive(Debug, Clone, PartialEq)]
+/// A property of the render that builtins may depend on, besides their
+/// arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    /// The sample rate in Hz, which band-limited
+    /// [oscillators](crate::oscillator) depend on.
+    SampleRate,
+    /// The frequency of `A4` in Hz, which [pitches](crate::pitch) are
+    /// relative to.
+    A4Hz,
+    /// The [table](Tuning::table) of the tuning, which is several values,
+    /// so only builtins taking their arguments as a slice can take it.
+    Tuning,
+}
+
+/// The builtin functions, shared by every context until one of them defines
+/// a function of its own.
+static BUILTIN_FUNCTIONS: LazyLock<Arc<HashMap<String, Function>>> = LazyLock::new(|| {
+    Arc::new(
+        BUILTINS
+            .iter()
+            .map(|builtin| (builtin.identifier().to_string(), builtin.function()))
+            .collect(),
+    )
+});
+
+/// The values, functions and settings an expression is evaluated in.
+///
+/// Functions and the tuning are shared between clones. Values are stacks:
+/// parameters and `let` bindings are pushed while they are in scope and
+/// popped afterwards.
+///
+/// Clones also share the last control points of each call to a function with
+/// a [control rate](crate::control_rate), until a function or setting is
+/// changed.
+#[derive(Debug, Clone)]
 pub struct Context {
     values: HashMap<String, Vec<f64>>,
-    functions: HashMap<String, Function>,
+    functions: Arc<HashMap<String, Function>>,
+    sample_rate: f64,
+    a4_hz: f64,
+    tuning: Arc<Tuning>,
+    control_points: Arc<Mutex<HashMap<ControlCall, ControlPoints>>>,
 }
 
+/// A call site of a function with a control rate: the addresses of the
+/// function and of the call's arguments, which stay the same while the
+/// functions of a context are unchanged.
+pub(crate) type ControlCall = (usize, usize);
+
 impl Context {
     pub fn values(&self) -> &HashMap<String, Vec<f64>> {
         &self.values
@@ -28,26 +72,104 @@ impl Context {
         self.functions().get(identifier.as_ref())
     }
 
+    /// The functions, which stay available while a call to one of them
+    /// binds values in this context.
+    pub(crate) fn shared_functions(&self) -> Arc<HashMap<String, Function>> {
+        Arc::clone(&self.functions)
+    }
+
     pub fn set_function(&mut self, function: Function) {
-        self.functions
+        Arc::make_mut(&mut self.functions)
             .insert(function.signature().identifier().to_string(), function);
+        self.control_points = Arc::default();
     }
 
     pub fn value(&self, identifier: impl AsRef<str>) -> Option<&f64> {
         self.values().get(identifier.as_ref())?.last()
     }
 
-    pub fn push_value(&mut self, identifier: impl Into<String>, value: f64) {
-        self.values
-            .entry(identifier.into())
-            .and_modify(|values| values.push(value))
-            .or_insert(vec![value]);
+    /// Binds `identifier` to `value`, shadowing its current value until
+    /// [`pop_value`](Self::pop_value) is called.
+    pub fn push_value(&mut self, identifier: impl AsRef<str> + Into<String>, value: f64) {
+        match self.values.get_mut(identifier.as_ref()) {
+            Some(values) => values.push(value),
+            None => {
+                self.values.insert(identifier.into(), vec![value]);
+            }
+        }
     }
 
-    pub fn pop_value(&mut self, identifier: impl Into<String>) {
-        self.values.entry(identifier.into()).and_modify(|values| {
+    pub fn pop_value(&mut self, identifier: impl AsRef<str>) {
+        if let Some(values) = self.values.get_mut(identifier.as_ref()) {
             values.pop();
-        });
+        }
+    }
+
+    /// The sample rate the document is rendered at, which band-limited
+    /// [oscillators](crate::oscillator) depend on.
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.control_points = Arc::default();
+    }
+
+    /// The frequency of `A4` in Hz, which note names and MIDI note numbers
+    /// are relative to.
+    pub fn a4_hz(&self) -> f64 {
+        self.a4_hz
+    }
+
+    pub fn set_a4_hz(&mut self, a4_hz: f64) {
+        self.a4_hz = a4_hz;
+        self.control_points = Arc::default();
+    }
+
+    /// The tuning that scale degrees are tuned by.
+    pub fn tuning(&self) -> &Tuning {
+        &self.tuning
+    }
+
+    pub fn set_tuning(&mut self, tuning: Tuning) {
+        self.tuning = Arc::new(tuning);
+        self.control_points = Arc::default();
+    }
+
+    pub fn setting(&self, setting: Setting) -> &[f64] {
+        match setting {
+            Setting::SampleRate => std::slice::from_ref(&self.sample_rate),
+            Setting::A4Hz => std::slice::from_ref(&self.a4_hz),
+            Setting::Tuning => self.tuning.table(),
+        }
+    }
+
+    /// Removes the control points of the last evaluation of `call`, to be
+    /// put back by [`set_control_points`](Self::set_control_points).
+    pub(crate) fn take_control_points(&self, call: ControlCall) -> Option<ControlPoints> {
+        self.control_points
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&call)
+    }
+
+    pub(crate) fn set_control_points(&self, call: ControlCall, points: ControlPoints) {
+        self.control_points
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(call, points);
+    }
+}
+
+/// Compares everything but the cached control points.
+impl PartialEq for Context {
+    fn eq(&self, other: &Self) -> bool {
+        self.values == other.values
+            && self.functions == other.functions
+            && self.sample_rate == other.sample_rate
+            && self.a4_hz == other.a4_hz
+            && self.tuning == other.tuning
     }
 }
 
@@ -55,131 +177,17 @@ impl Default for Context {
     fn default() -> Self {
         let mut context = Self {
             values: HashMap::new(),
-            functions: HashMap::new(),
+            functions: Arc::clone(&BUILTIN_FUNCTIONS),
+            sample_rate: DEFAULT_SPEC.sample_rate as f64,
+            a4_hz: pitch::A4_HZ,
+            tuning: Arc::default(),
+            control_points: Arc::default(),
         };
 
         context.push_value("pi", PI);
         context.push_value("tau", TAU);
         context.push_value("e", E);
 
-        context.set_function(Function::new(
-            "abs",
-            Arc::new(|arguments, context| arguments[0].eval(context).abs()),
-        ));
-
-        context.set_function(Function::new(
-            "min",
-            Arc::new(|arguments, context| {
-                arguments[0].eval(context).min(arguments[1].eval(context))
-            }),
-        ));
-
-        context.set_function(Function::new(
-            "max",
-            Arc::new(|arguments, context| {
-                arguments[0].eval(context).max(arguments[1].eval(context))
-            }),
-        ));
-
-        context.set_function(Function::new(
-            "sin",
-            Arc::new(|arguments, context| arguments[0].eval(context).sin()),
-        ));
-
-        context.set_function(Function::new(
-            "cos",
-            Arc::new(|arguments, context| arguments[0].eval(context).cos()),
-        ));
-
-        context.set_function(Function::new(
-            "floor",
-            Arc::new(|arguments, context| arguments[0].eval(context).floor()),
-        ));
-
-        context.set_function(Function::new(
-            "ceil",
-            Arc::new(|arguments, context| arguments[0].eval(context).ceil()),
-        ));
-
-        context.set_function(Function::new(
-            "mix",
-            Arc::new(|arguments, context| {
-                arguments
-                    .iter()
-                    .map(|argument| argument.eval(context))
-                    .sum::<f64>()
-                    / arguments.len() as f64
-            }),
-        ));
-
-        context.set_function(Function::new(
-            "sum",
-            Arc::new(|arguments, context| {
-                if let Expression::Primary(Primary::Identifier(identifier)) = arguments[0].as_ref()
-                {
-                    let start = arguments
-                        .get(1)
-                        .expect("expected start")
-                        .eval(context)
-                        .round() as isize;
-                    let end = arguments
-                        .get(2)
-                        .expect("expected end")
-                        .eval(context)
-                        .round() as isize;
-
-                    (start..end)
-                        .map(|value| {
-                            let mut context = context.clone();
-
-                            context.push_value(identifier, value as f64);
-
-                            arguments
-                                .get(3)
-                                .expect("expected sum expression")
-                                .eval(&context)
-                        })
-                        .sum()
-                } else {
-                    panic!("expected identifier, found {:?}", arguments[0])
-                }
-            }),
-        ));
-
-        context.set_function(Function::new(
-            "prod",
-            Arc::new(|arguments, context| {
-                if let Expression::Primary(Primary::Identifier(identifier)) = arguments[0].as_ref()
-                {
-                    let start = arguments
-                        .get(1)
-                        .expect("expected start")
-                        .eval(context)
-                        .round() as isize;
-                    let end = arguments
-                        .get(2)
-                        .expect("expected end")
-                        .eval(context)
-                        .round() as isize;
-
-                    (start..end)
-                        .map(|value| {
-                            let mut context = context.clone();
-
-                            context.push_value(identifier, value as f64);
-
-                            arguments
-                                .get(3)
-                                .expect("expected product expression")
-                                .eval(&context)
-                        })
-                        .product()
-                } else {
-                    panic!("expected identifier, found {:?}", arguments[0])
-                }
-            }),
-        ));
-
         context
     }
 }
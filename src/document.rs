@@ -1,22 +1,60 @@
-use pest::iterators::Pairs;
+use std::path::Path;
+
+use pest::{Parser, iterators::Pairs};
 
 use crate::{
-    Rule,
+    MusathParser, Rule,
+    block::BlockContext,
     body::Body,
-    expression::{Expression, Primary},
-    header::Header, wave_provider::WaveProvider,
+    constant::Constant,
+    context::Context,
+    error::{ErrorKind, MusathError, Span},
+    expression::{Expression, LanguageVersion, Primary},
+    function::Function,
+    header::Header,
+    import::Importer,
+    pitch,
+    renderer::DEFAULT_SPEC,
+    tuning::{KeyboardMap, Scale, Tuning},
+    wave_provider::{MultichannelWaveProvider, WaveProvider},
 };
 
 #[derive(Debug)]
 pub struct Document {
     header: Header,
     body: Body,
+    warnings: Vec<MusathError>,
+    channels: u16,
 }
 
 impl Document {
-    pub fn parse(pairs: &mut Pairs<Rule>) -> Self {
+    /// Parses a complete `.mth` source file. Imports are resolved relative to
+    /// the working directory.
+    pub fn from_source(source: &str) -> Result<Self, MusathError> {
+        Self::from_source_with(source, &mut Importer::default())
+    }
+
+    /// Parses `source` as the contents of the file at `path`, resolving
+    /// imports relative to it.
+    pub fn from_source_at(source: &str, path: impl AsRef<Path>) -> Result<Self, MusathError> {
+        Self::from_source_with(source, &mut Importer::new(path))
+    }
+
+    pub fn from_source_with(source: &str, importer: &mut Importer) -> Result<Self, MusathError> {
+        Self::parse_with(&mut MusathParser::parse(Rule::document, source)?, importer)
+    }
+
+    pub fn parse(pairs: &mut Pairs<Rule>) -> Result<Self, MusathError> {
+        Self::parse_with(pairs, &mut Importer::default())
+    }
+
+    pub fn parse_with(
+        pairs: &mut Pairs<Rule>,
+        importer: &mut Importer,
+    ) -> Result<Self, MusathError> {
         let mut header = None;
         let mut body = None;
+        let mut warnings = Vec::new();
 
         for pair in pairs {
             match pair.as_rule() {
@@ -24,17 +62,72 @@ impl Document {
                     header = Some(Header::parse(&mut pair.into_inner()));
                 }
                 Rule::body => {
-                    body = Some(Body::parse(&mut pair.into_inner()));
+                    let (version, sample_rate, a4_hz) = match &header {
+                        Some(header) => (
+                            header.language_version()?,
+                            header.sample_rate()?,
+                            header.a4_hz()?,
+                        ),
+                        None => (None, None, None),
+                    };
+                    let a4_hz = a4_hz.unwrap_or(pitch::A4_HZ);
+
+                    // An imported file takes its settings from the file
+                    // importing it.
+                    let context = match importer.settings() {
+                        Some(settings) => settings.clone(),
+                        None => {
+                            let mut context = Context::default();
+                            context.set_sample_rate(
+                                sample_rate.unwrap_or(DEFAULT_SPEC.sample_rate) as f64,
+                            );
+                            context.set_a4_hz(a4_hz);
+
+                            if let Some(header) = &header {
+                                context.set_tuning(tuning(header, importer, a4_hz)?);
+                            }
+
+                            context
+                        }
+                    };
+
+                    if version.is_none() {
+                        warnings = precedence_warnings(pair.clone().into_inner());
+                    }
+
+                    body = Some(Body::parse(
+                        &mut pair.into_inner(),
+                        version.unwrap_or_default(),
+                        context,
+                        importer,
+                    )?);
+
+                    if importer.settings().is_none() {
+                        warnings.extend(importer.take_warnings());
+                    }
                 }
                 Rule::EOI => (),
                 _ => unreachable!("expected header, body, EOI, found {:?}", pair),
             };
         }
 
-        Self {
-            header: header.unwrap(),
-            body: body.unwrap(),
-        }
+        let header = header
+            .ok_or_else(|| MusathError::new(ErrorKind::Syntax(String::from("missing header"))))?;
+        let body =
+            body.ok_or_else(|| MusathError::new(ErrorKind::Syntax(String::from("missing body"))))?;
+
+        let channels = match header.channels()? {
+            Some(channels) => channels,
+            None if body.is_stereo() => 2,
+            None => 1,
+        };
+
+        Ok(Self {
+            header,
+            body,
+            warnings,
+            channels,
+        })
     }
 
     pub fn header(&self) -> &Header {
@@ -45,29 +138,368 @@ impl Document {
         &self.body
     }
 
-    pub fn eval(&self, t: f64) -> f64 {
-        let output = self
-            .body()
-            .context()
-            .functions()
-            .get("output")
-            .expect("missing output function");
+    /// Non-fatal diagnostics collected while parsing.
+    pub fn warnings(&self) -> &[MusathError] {
+        &self.warnings
+    }
+
+    pub fn into_warnings(self) -> Vec<MusathError> {
+        self.warnings
+    }
+
+    /// The number of channels to render: `CHANNELS` if declared, otherwise
+    /// two if the body defines `left` and `right`, otherwise one.
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// Renders the document at `sample_rate` instead of its `SAMPLE_RATE`,
+    /// which oscillators depend on. Call this before compiling the document
+    /// to render it at a different rate, as `--sample-rate` does.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.body.set_sample_rate(sample_rate as f64);
+    }
+
+    /// Statically checks the document, returning every problem found.
+    pub fn validate(&self) -> Vec<MusathError> {
+        let mut errors = self.body().validate();
+
+        let missing_output = self.body().context().function("output").is_none()
+            && !(self.channels() == 2 && self.body().is_stereo());
+
+        if missing_output
+            && !errors
+                .iter()
+                .any(|error| matches!(error.kind(), ErrorKind::MissingOutput))
+        {
+            errors.push(MusathError::new(ErrorKind::MissingOutput));
+        }
+
+        errors
+    }
+
+    /// Folds constants, simplifies the functions and expands small ones in
+    /// place. See [`Body::optimize`].
+    pub fn optimize(&mut self) {
+        self.body.optimize();
+    }
+
+    pub fn eval(&self, t: f64) -> Result<f64, MusathError> {
+        self.eval_channel(t, 0)
+    }
+
+    /// Evaluates one channel at time `t`. Stereo documents may define
+    /// `left(t)` and `right(t)`; otherwise `output` provides every channel,
+    /// receiving the channel number as a second argument if it takes one.
+    pub fn eval_channel(&self, t: f64, channel: u16) -> Result<f64, MusathError> {
+        let output = self.output(channel)?;
 
         let mut context = self.body().context().clone();
 
         context.push_value("t", t);
 
-        output.eval(
-            &[Box::new(Expression::Primary(Primary::Identifier(
-                String::from("t"),
-            )))],
-            &context,
-        )
+        output.eval(&mut context)
+    }
+
+    /// Evaluates one channel at each time in `t` at once. See
+    /// [`eval_channel`](Self::eval_channel) and [`block`](crate::block).
+    pub fn eval_block(&self, t: &[f64], channel: u16) -> Result<Vec<f64>, MusathError> {
+        let output = self.output(channel)?;
+
+        let mut context = BlockContext::new(self.body().context(), t.len());
+
+        context.push_value("t", t.to_vec());
+
+        output.eval_block(&context)
+    }
+
+    /// The call of the function providing `channel`, in terms of `t`.
+    fn output(&self, channel: u16) -> Result<Primary, MusathError> {
+        let stereo = self.channels() == 2 && self.body().is_stereo();
+
+        let identifier = match channel {
+            0 if stereo => "left",
+            1 if stereo => "right",
+            _ => "output",
+        };
+
+        let function = self
+            .body()
+            .context()
+            .function(identifier)
+            .ok_or_else(|| MusathError::new(ErrorKind::MissingOutput))?;
+
+        let mut arguments = vec![Box::new(Expression::Primary(Primary::Identifier(
+            String::from("t"),
+            Span::default(),
+        )))];
+
+        if function.signature().parameters().len() == 2 {
+            arguments.push(Box::new(Expression::Primary(Primary::Integer(
+                channel.into(),
+            ))));
+        }
+
+        Ok(Primary::Call(
+            String::from(identifier),
+            arguments,
+            Span::default(),
+        ))
     }
 }
 
+/// Loads the tuning named by the `TUNING` and `KEYBOARD_MAP` keys of
+/// `header`, resolving their paths as `importer` resolves imports. Without a
+/// keyboard map, middle C is tuned relative to `a4_hz`.
+fn tuning(header: &Header, importer: &Importer, a4_hz: f64) -> Result<Tuning, MusathError> {
+    let failed = |key: &str, path: &str, message: String| {
+        let kind = ErrorKind::TuningFailed {
+            path: path.to_string(),
+            message,
+        };
+
+        match header.span(key) {
+            Some(span) => MusathError::with_span(kind, span),
+            None => MusathError::new(kind),
+        }
+    };
+
+    let load = |key: &str, path: &str| {
+        std::fs::read_to_string(importer.resolve(path))
+            .map_err(|error| failed(key, path, error.to_string()))
+    };
+
+    let scale = match header.tuning()? {
+        Some(path) => Scale::parse(&load("TUNING", path)?)
+            .map_err(|error| failed("TUNING", path, error.to_string()))?,
+        None => Scale::default(),
+    };
+
+    match header.keyboard_map()? {
+        Some(path) => KeyboardMap::parse(&load("KEYBOARD_MAP", path)?)
+            .and_then(|keyboard_map| Tuning::new(scale, keyboard_map))
+            .map_err(|error| failed("KEYBOARD_MAP", path, error.to_string())),
+        None => Tuning::new(scale, KeyboardMap::linear(a4_hz)),
+    }
+}
+
+/// Flags every constant and function of the body in `pairs` whose meaning
+/// depends on the operator precedence, so files written before language
+/// version 2 are not silently reinterpreted. Only the expressions are parsed
+/// again, so imports are not loaded and constants are not evaluated twice.
+fn precedence_warnings(pairs: Pairs<Rule>) -> Vec<MusathError> {
+    let warning = |identifier: &String, span| {
+        MusathError::warning(ErrorKind::PrecedenceChanged(identifier.clone()), span)
+    };
+
+    pairs
+        .filter_map(|pair| match pair.as_rule() {
+            Rule::constant => {
+                let constant = Constant::parse(&mut pair.clone().into_inner(), LanguageVersion::V2);
+                let legacy_constant = Constant::parse(&mut pair.into_inner(), LanguageVersion::V1);
+
+                (constant != legacy_constant)
+                    .then(|| warning(constant.identifier(), constant.span()))
+            }
+            Rule::function => {
+                let function =
+                    Function::parse_versioned(&mut pair.clone().into_inner(), LanguageVersion::V2);
+                let legacy_function =
+                    Function::parse_versioned(&mut pair.into_inner(), LanguageVersion::V1);
+
+                (function != legacy_function).then(|| {
+                    warning(
+                        function.signature().identifier(),
+                        function.signature().span(),
+                    )
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
 impl WaveProvider for Document {
     fn value_at_time(&self, t: f64) -> f64 {
+        self.eval(t).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    fn try_value_at_time(&self, t: f64) -> Result<f64, MusathError> {
         self.eval(t)
     }
+
+    fn fill_block(&self, start_sample: u64, sample_rate: u32, out: &mut [f64]) {
+        WaveProvider::try_fill_block(self, start_sample, sample_rate, out)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    fn try_fill_block(
+        &self,
+        start_sample: u64,
+        sample_rate: u32,
+        out: &mut [f64],
+    ) -> Result<(), MusathError> {
+        MultichannelWaveProvider::try_fill_block(self, start_sample, sample_rate, 0, out)
+    }
+}
+
+impl MultichannelWaveProvider for Document {
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn value_at_time(&self, t: f64, channel: u16) -> f64 {
+        self.eval_channel(t, channel)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    fn try_value_at_time(&self, t: f64, channel: u16) -> Result<f64, MusathError> {
+        self.eval_channel(t, channel)
+    }
+
+    fn fill_block(&self, start_sample: u64, sample_rate: u32, channel: u16, out: &mut [f64]) {
+        MultichannelWaveProvider::try_fill_block(self, start_sample, sample_rate, channel, out)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    fn try_fill_block(
+        &self,
+        start_sample: u64,
+        sample_rate: u32,
+        channel: u16,
+        out: &mut [f64],
+    ) -> Result<(), MusathError> {
+        let t = (start_sample..start_sample + out.len() as u64)
+            .map(|sample| sample as f64 / sample_rate as f64)
+            .collect::<Vec<_>>();
+
+        out.copy_from_slice(&self.eval_block(&t, channel)?);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precedence_warnings() {
+        let source = "decay(t, p) = 1 - t - 0.5 % p\noutput(t) = decay(t, 1) + -2^2";
+
+        let document = Document::from_source(source).unwrap();
+
+        assert_eq!(document.warnings().len(), 2);
+        assert!(matches!(
+            document.warnings()[0].kind(),
+            ErrorKind::PrecedenceChanged(function) if function == "decay"
+        ));
+
+        assert_eq!(document.eval(0.25).unwrap(), 0.25 - 4.0);
+
+        let document =
+            Document::from_source("const K = 2 - 1 % 2;\nconst L = 1;\noutput(t) = t * K").unwrap();
+
+        assert!(matches!(
+            document.warnings(),
+            [warning] if matches!(
+                warning.kind(),
+                ErrorKind::PrecedenceChanged(constant) if constant == "K"
+            )
+        ));
+
+        let document = Document::from_source(&format!("LANGUAGE_VERSION = 1\n{}", source)).unwrap();
+
+        assert!(document.warnings().is_empty());
+        assert_eq!(document.eval(0.25).unwrap(), 0.25 + 4.0);
+
+        let document = Document::from_source(&format!("LANGUAGE_VERSION = 2\n{}", source)).unwrap();
+
+        assert!(document.warnings().is_empty());
+        assert_eq!(document.eval(0.25).unwrap(), 0.25 - 4.0);
+
+        assert!(Document::from_source(&format!("LANGUAGE_VERSION = 3\n{}", source)).is_err());
+    }
+
+    #[test]
+    fn test_channels() {
+        let document = Document::from_source("left(t) = t\nright(t) = -t").unwrap();
+
+        assert_eq!(document.channels(), 2);
+        assert_eq!(document.eval_channel(0.5, 0).unwrap(), 0.5);
+        assert_eq!(document.eval_channel(0.5, 1).unwrap(), -0.5);
+        assert!(document.validate().is_empty());
+
+        let document = Document::from_source("CHANNELS = 1\nleft(t) = t\nright(t) = -t").unwrap();
+
+        assert_eq!(document.channels(), 1);
+        assert!(matches!(
+            document.validate()[..],
+            [ref error] if matches!(error.kind(), ErrorKind::MissingOutput)
+        ));
+
+        let document = Document::from_source("CHANNELS = 2\noutput(t) = t").unwrap();
+
+        assert_eq!(document.eval_channel(0.5, 0).unwrap(), 0.5);
+        assert_eq!(document.eval_channel(0.5, 1).unwrap(), 0.5);
+
+        let document =
+            Document::from_source("CHANNELS = 4\noutput(t, channel) = t * channel").unwrap();
+
+        assert_eq!(document.channels(), 4);
+        assert_eq!(document.eval_channel(0.5, 3).unwrap(), 1.5);
+
+        assert!(Document::from_source("CHANNELS = 0\noutput(t) = t").is_err());
+        assert!(Document::from_source("CHANNELS = 1.5\noutput(t) = t").is_err());
+    }
+
+    #[test]
+    fn test_a4_hz() {
+        let document = Document::from_source("output(t) = A4 + mtof(57) * t").unwrap();
+
+        assert_eq!(document.eval(0.0).unwrap(), 440.0);
+        assert_eq!(document.eval(1.0).unwrap(), 660.0);
+
+        let document = Document::from_source("A4_HZ = 432\noutput(t) = A5 + ftom(t)").unwrap();
+
+        assert_eq!(document.eval(432.0).unwrap(), 864.0 + 69.0);
+
+        assert!(Document::from_source("A4_HZ = 0\noutput(t) = A4").is_err());
+        assert!(Document::from_source("A4_HZ = \"440\"\noutput(t) = A4").is_err());
+    }
+
+    #[test]
+    fn test_tuning() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tunings/test.mth");
+        let document = |header: &str| {
+            Document::from_source_at(&format!("{}\noutput(t) = degree(t)", header), path)
+        };
+
+        let equal = document("A4_HZ = 432").unwrap();
+
+        assert!((equal.eval(9.0).unwrap() - 432.0).abs() < 1e-9);
+        assert!((equal.eval(-3.0).unwrap() - 216.0).abs() < 1e-9);
+
+        let just =
+            document("TUNING = \"just_major.scl\"\nKEYBOARD_MAP = \"white_keys.kbm\"").unwrap();
+
+        assert!((just.eval(0.0).unwrap() - 264.0).abs() < 1e-9);
+        assert!((just.eval(4.0).unwrap() - 396.0).abs() < 1e-9);
+        assert!((just.eval(-7.0).unwrap() - 132.0).abs() < 1e-9);
+
+        let error = document("TUNING = \"missing.scl\"").unwrap_err();
+
+        assert!(matches!(
+            error.kind(),
+            ErrorKind::TuningFailed { path, .. } if path == "missing.scl"
+        ));
+        assert_eq!(error.span().unwrap().column(), 10);
+
+        let error = document("KEYBOARD_MAP = \"pyth_12.scl\"").unwrap_err();
+
+        assert!(matches!(
+            error.kind(),
+            ErrorKind::TuningFailed { message, .. } if message.starts_with("line 3:")
+        ));
+    }
 }
@@ -0,0 +1,212 @@
+//! Envelopes, which shape the loudness or any other parameter of a sound
+//! over time.
+//!
+//! [`Adsr`] follows the attack, decay, sustain and release of a note held
+//! for a while, and [`env()`] passes through breakpoints at arbitrary times.
+//! Each segment is either a straight line or an exponential curve, as set by
+//! its curvature: see [`segment`].
+//!
+//! Like the rest of a composition, envelopes are functions of time, so they
+//! work alongside [`Composition::from_function`] as well as in documents:
+//!
+//! ```
+//! use musath::envelope::Adsr;
+//!
+//! let pluck = Adsr::new(0.01, 0.2, 0.3, 0.5).with_curve(-4.0);
+//!
+//! let note = |t: f64| (440.0 * t * std::f64::consts::TAU).sin() * pluck.value(t, 0.0, 1.0);
+//!
+//! assert_eq!(note(2.0), 0.0);
+//! ```
+//!
+//! [`Composition::from_function`]: crate::composition::Composition::from_function
+
+/// The value `x` of the way from `from` to `to`, along a straight line if
+/// `curve` is 0. Otherwise the segment is an exponential curve that starts
+/// slowly and speeds up if `curve` is positive, or starts quickly and slows
+/// down if it is negative, more so the larger it is.
+pub fn segment(from: f64, to: f64, x: f64, curve: f64) -> f64 {
+    if curve.abs() < 1e-3 {
+        from + (to - from) * x
+    } else {
+        from + (to - from) * (1.0 - (curve * x).exp()) / (1.0 - curve.exp())
+    }
+}
+
+/// An attack, decay, sustain and release envelope.
+///
+/// Over `attack` seconds from the start of a note, the envelope rises from 0
+/// to 1, then falls to `sustain` over `decay` seconds and stays there until
+/// the note ends. It then falls from wherever it is to 0 over `release`
+/// seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Adsr {
+    attack: f64,
+    decay: f64,
+    sustain: f64,
+    release: f64,
+    curve: f64,
+}
+
+impl Adsr {
+    /// An envelope with straight segments.
+    pub fn new(attack: f64, decay: f64, sustain: f64, release: f64) -> Self {
+        Self {
+            attack,
+            decay,
+            sustain,
+            release,
+            curve: 0.0,
+        }
+    }
+
+    /// Curves every segment by `curve`, as [`segment`] does. Negative values
+    /// such as -4 sound like the exponential envelopes of analog synthesizers.
+    pub fn with_curve(mut self, curve: f64) -> Self {
+        self.curve = curve;
+        self
+    }
+
+    /// The value at time `t` for a note starting at `gate_start` and lasting
+    /// `gate_len` seconds before it is released.
+    pub fn value(&self, t: f64, gate_start: f64, gate_len: f64) -> f64 {
+        let time = t - gate_start;
+        let gate_len = gate_len.max(0.0);
+
+        if time < 0.0 {
+            0.0
+        } else if time < gate_len {
+            self.held(time)
+        } else if time - gate_len < self.release {
+            segment(
+                self.held(gate_len),
+                0.0,
+                (time - gate_len) / self.release,
+                self.curve,
+            )
+        } else {
+            0.0
+        }
+    }
+
+    /// The value `time` seconds into a note that has not been released.
+    fn held(&self, time: f64) -> f64 {
+        if time < self.attack {
+            segment(0.0, 1.0, time / self.attack, self.curve)
+        } else if time - self.attack < self.decay {
+            segment(
+                1.0,
+                self.sustain,
+                (time - self.attack) / self.decay,
+                self.curve,
+            )
+        } else {
+            self.sustain
+        }
+    }
+}
+
+/// The value at time `t` of the envelope through `breakpoints`, given as the
+/// first time and value followed by the curve of each segment, as [`segment`]
+/// takes it, the time it ends at and the value it reaches:
+/// `[t0, v0, c1, t1, v1, c2, t2, v2, ...]`, with the times in increasing
+/// order. It is `v0` before `t0` and the last value after the last time.
+///
+/// # Panics
+///
+/// If `breakpoints` is not two values followed by groups of three.
+pub fn env(t: f64, breakpoints: &[f64]) -> f64 {
+    let Some(([mut start, mut from], segments)) = breakpoints
+        .split_first_chunk::<2>()
+        .filter(|(_, segments)| segments.len() % 3 == 0)
+        .map(|(first, segments)| (*first, segments))
+    else {
+        panic!(
+            "expected two values followed by groups of three, found {:?}",
+            breakpoints
+        );
+    };
+
+    if t < start {
+        return from;
+    }
+
+    for &[curve, end, to] in segments.as_chunks::<3>().0 {
+        if t < end {
+            return segment(from, to, (t - start) / (end - start), curve);
+        }
+
+        (start, from) = (end, to);
+    }
+
+    from
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segment() {
+        for curve in [0.0, 4.0, -4.0] {
+            assert_eq!(segment(2.0, 3.0, 0.0, curve), 2.0);
+            assert!((segment(2.0, 3.0, 1.0, curve) - 3.0).abs() < 1e-12);
+        }
+
+        assert_eq!(segment(2.0, 3.0, 0.25, 0.0), 2.25);
+        assert!(segment(0.0, 1.0, 0.5, 4.0) < 0.5);
+        assert!(segment(0.0, 1.0, 0.5, -4.0) > 0.5);
+        assert!(segment(1.0, 0.0, 0.5, -4.0) < 0.5);
+    }
+
+    #[test]
+    fn test_adsr() {
+        let envelope = Adsr::new(0.1, 0.2, 0.5, 0.4);
+        let value = |t| envelope.value(t, 1.0, 1.0);
+
+        assert_eq!(value(0.5), 0.0);
+        assert_eq!(value(1.0), 0.0);
+        assert!((value(1.05) - 0.5).abs() < 1e-12);
+        assert!((value(1.1) - 1.0).abs() < 1e-12);
+        assert!((value(1.2) - 0.75).abs() < 1e-12);
+        assert_eq!(value(1.5), 0.5);
+        assert_eq!(value(2.0), 0.5);
+        assert!((value(2.2) - 0.25).abs() < 1e-12);
+        assert!(value(2.4).abs() < 1e-12);
+        assert_eq!(value(3.0), 0.0);
+
+        // Releasing during the attack falls from the level reached.
+        assert!((envelope.value(1.05, 1.0, 0.05) - 0.5).abs() < 1e-12);
+        assert!((envelope.value(1.25, 1.0, 0.05) - 0.25).abs() < 1e-12);
+
+        // Segments without duration are skipped.
+        let envelope = Adsr::new(0.0, 0.0, 0.5, 0.0);
+
+        assert_eq!(envelope.value(0.0, 0.0, 1.0), 0.5);
+        assert_eq!(envelope.value(1.0, 0.0, 1.0), 0.0);
+
+        let curved = Adsr::new(0.1, 0.2, 0.5, 0.4).with_curve(-4.0);
+
+        assert!(curved.value(1.05, 1.0, 1.0) > 0.5);
+        assert!(curved.value(2.2, 1.0, 1.0) < 0.25);
+    }
+
+    #[test]
+    fn test_env() {
+        let breakpoints = [1.0, 0.0, 0.0, 2.0, 1.0, 0.0, 2.0, 0.5, 0.0, 4.0, 0.0];
+
+        assert_eq!(env(0.0, &breakpoints), 0.0);
+        assert_eq!(env(1.5, &breakpoints), 0.5);
+        assert_eq!(env(2.0, &breakpoints), 0.5);
+        assert_eq!(env(3.0, &breakpoints), 0.25);
+        assert_eq!(env(6.0, &breakpoints), 0.0);
+        assert_eq!(env(0.0, &[1.0, 0.5]), 0.5);
+
+        let breakpoints = [0.0, 0.0, 4.0, 1.0, 1.0, 0.0, 2.0, 0.0];
+
+        assert_eq!(env(-1.0, &breakpoints), 0.0);
+        assert_eq!(env(0.5, &breakpoints), segment(0.0, 1.0, 0.5, 4.0));
+        assert_eq!(env(1.5, &breakpoints), 0.5);
+        assert_eq!(env(3.0, &breakpoints), 0.0);
+    }
+}
@@ -0,0 +1,455 @@
+use std::{
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{Rule, function::Arity};
+
+/// A region of a `.mth` source file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    start: usize,
+    end: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, column: usize) -> Self {
+        Self {
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
+    }
+}
+
+impl From<pest::Span<'_>> for Span {
+    fn from(span: pest::Span<'_>) -> Self {
+        let (line, column) = span.start_pos().line_col();
+
+        Self::new(span.start(), span.end(), line, column)
+    }
+}
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    Syntax(String),
+    InvalidHeaderValue {
+        key: String,
+        expected: &'static str,
+    },
+    MissingOutput,
+    InvalidSignature {
+        function: String,
+        expected: Arity,
+    },
+    UndefinedFunction(String),
+    UndefinedIdentifier(String),
+    ArityMismatch {
+        function: String,
+        expected: Arity,
+        found: usize,
+    },
+    UnboundedRecursion(Vec<String>),
+    CyclicConstant(Vec<String>),
+    DuplicateConstant(String),
+    ImportFailed {
+        path: String,
+        errors: Vec<MusathError>,
+    },
+    ImportCycle(Vec<String>),
+    InvalidTuning(String),
+    TuningFailed {
+        path: String,
+        message: String,
+    },
+    NameCollision(String),
+    PrecedenceChanged(String),
+    InvalidArgument {
+        function: String,
+        message: String,
+    },
+    InvalidControlRate(String),
+    Uncompilable(String),
+    Codegen(String),
+    UnsupportedSampleFormat {
+        bit_depth: u16,
+        sample_format: hound::SampleFormat,
+    },
+    InvalidTemplate {
+        template: String,
+        message: String,
+    },
+    OutputExists(String),
+    Wav(hound::Error),
+    Io(std::io::Error),
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax(message) => write!(f, "{}", message),
+            Self::InvalidHeaderValue { key, expected } => {
+                write!(f, "expected {} to be a {}", key, expected)
+            }
+            Self::MissingOutput => write!(
+                f,
+                "missing output function; define `output(t)`, or `left(t)` and `right(t)`"
+            ),
+            Self::InvalidSignature { function, expected } => {
+                write!(f, "expected `{}` to take {}", function, expected)
+            }
+            Self::UndefinedFunction(identifier) => {
+                write!(f, "undefined function `{}`", identifier)
+            }
+            Self::UndefinedIdentifier(identifier) => {
+                write!(f, "undefined identifier `{}`", identifier)
+            }
+            Self::ArityMismatch {
+                function,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`{}` takes {} but was called with {}",
+                function, expected, found
+            ),
+            Self::UnboundedRecursion(cycle) => {
+                write!(f, "unbounded recursion: {}", cycle.join(" -> "))
+            }
+            Self::CyclicConstant(cycle) => {
+                write!(f, "cyclic constant definition: {}", cycle.join(" -> "))
+            }
+            Self::DuplicateConstant(identifier) => {
+                write!(f, "constant `{}` is defined more than once", identifier)
+            }
+            Self::ImportFailed { path, errors } => {
+                let errors = errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+                write!(f, "could not import `{}`: {}", path, errors.join("; "))
+            }
+            Self::ImportCycle(cycle) => write!(f, "import cycle: {}", cycle.join(" -> ")),
+            Self::InvalidTuning(message) => write!(f, "{}", message),
+            Self::TuningFailed { path, message } => {
+                write!(f, "could not load tuning `{}`: {}", path, message)
+            }
+            Self::NameCollision(identifier) => {
+                write!(f, "`{}` is already defined by an import", identifier)
+            }
+            Self::PrecedenceChanged(function) => write!(
+                f,
+                "`{}` parses differently under the language version 2 operator precedence; \
+                 set LANGUAGE_VERSION to 1 to keep the old meaning or to 2 to accept the new one",
+                function
+            ),
+            Self::InvalidArgument { function, message } => {
+                write!(f, "invalid argument to `{}`: {}", function, message)
+            }
+            Self::InvalidControlRate(identifier) => {
+                write!(f, "the control rate of `{}` must be positive", identifier)
+            }
+            Self::Uncompilable(function) => {
+                write!(
+                    f,
+                    "`{}` is a native function and cannot be compiled",
+                    function
+                )
+            }
+            Self::Codegen(message) => write!(f, "native code generation failed: {}", message),
+            Self::UnsupportedSampleFormat {
+                bit_depth,
+                sample_format,
+            } => {
+                let sample_format = match sample_format {
+                    hound::SampleFormat::Int => "int",
+                    hound::SampleFormat::Float => "float",
+                };
+
+                write!(
+                    f,
+                    "{}-bit {} samples are not supported; use 16, 24 or 32-bit int or 32-bit float",
+                    bit_depth, sample_format
+                )
+            }
+            Self::InvalidTemplate { template, message } => {
+                write!(f, "invalid file name template `{}`: {}", template, message)
+            }
+            Self::OutputExists(path) => write!(f, "refusing to overwrite `{}`", path),
+            Self::Wav(error) => write!(f, "{}", error),
+            Self::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A file other than the one being reported on, such as an imported file,
+/// that a diagnostic points into.
+#[derive(Debug)]
+pub struct SourceFile {
+    path: PathBuf,
+    text: String,
+}
+
+impl SourceFile {
+    pub fn new(path: impl Into<PathBuf>, text: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            text: text.into(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+#[derive(Debug)]
+pub struct MusathError {
+    kind: ErrorKind,
+    span: Option<Span>,
+    severity: Severity,
+    file: Option<Arc<SourceFile>>,
+}
+
+impl MusathError {
+    pub fn new(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            span: None,
+            severity: Severity::Error,
+            file: None,
+        }
+    }
+
+    pub fn with_span(kind: ErrorKind, span: Span) -> Self {
+        Self {
+            kind,
+            span: Some(span),
+            severity: Severity::Error,
+            file: None,
+        }
+    }
+
+    /// A diagnostic that does not prevent rendering.
+    pub fn warning(kind: ErrorKind, span: Span) -> Self {
+        Self {
+            kind,
+            span: Some(span),
+            severity: Severity::Warning,
+            file: None,
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    /// Marks the error as pointing into `file` rather than the file being
+    /// reported on, unless it already points into another file.
+    pub fn in_file(mut self, file: &Arc<SourceFile>) -> Self {
+        self.file.get_or_insert_with(|| Arc::clone(file));
+        self
+    }
+
+    /// The file the error points into, if not the one being reported on.
+    pub fn file(&self) -> Option<&SourceFile> {
+        self.file.as_deref()
+    }
+
+    /// Formats the error as a rustc-style diagnostic, underlining the
+    /// offending snippet of `source` with carets. Errors in an imported file
+    /// are shown in that file, followed by the errors that caused them.
+    pub fn render(&self, path: impl Display, source: &str) -> String {
+        let (path, source) = match &self.file {
+            Some(file) => (file.path().display().to_string(), file.text()),
+            None => (path.to_string(), source),
+        };
+
+        let mut rendered = self.render_snippet(&path, source);
+
+        if let ErrorKind::ImportFailed { errors, .. } = &self.kind {
+            for error in errors {
+                rendered.push('\n');
+                rendered.push_str(&error.render(&path, source));
+            }
+        }
+
+        rendered
+    }
+
+    fn render_snippet(&self, path: &str, source: &str) -> String {
+        let mut rendered = format!("{}: {}\n", self.severity, self.kind);
+
+        let Some(span) = self.span else {
+            rendered.push_str(&format!(" --> {}\n", path));
+            return rendered;
+        };
+
+        let line = source
+            .lines()
+            .nth(span.line().saturating_sub(1))
+            .unwrap_or("");
+        let gutter = " ".repeat(span.line().to_string().len());
+
+        let underline_start = span.column().saturating_sub(1);
+        let underline_length = span
+            .end()
+            .saturating_sub(span.start())
+            .min(line.len().saturating_sub(underline_start))
+            .max(1);
+
+        rendered.push_str(&format!(
+            "{}--> {}:{}:{}\n",
+            gutter,
+            path,
+            span.line(),
+            span.column()
+        ));
+        rendered.push_str(&format!("{} |\n", gutter));
+        rendered.push_str(&format!("{} | {}\n", span.line(), line));
+        rendered.push_str(&format!(
+            "{} | {}{}\n",
+            gutter,
+            " ".repeat(underline_start),
+            "^".repeat(underline_length)
+        ));
+
+        rendered
+    }
+}
+
+impl Display for MusathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.span, &self.file) {
+            (Some(span), Some(file)) => write!(
+                f,
+                "{} at {}:{}:{}",
+                self.kind,
+                file.path().display(),
+                span.line(),
+                span.column()
+            ),
+            (Some(span), None) => {
+                write!(f, "{} at {}:{}", self.kind, span.line(), span.column())
+            }
+            (None, Some(file)) => write!(f, "{} in {}", self.kind, file.path().display()),
+            (None, None) => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl std::error::Error for MusathError {}
+
+impl From<pest::error::Error<Rule>> for MusathError {
+    fn from(error: pest::error::Error<Rule>) -> Self {
+        let (start, end) = match error.location {
+            pest::error::InputLocation::Pos(position) => (position, position),
+            pest::error::InputLocation::Span(span) => span,
+        };
+
+        let (line, column) = match error.line_col {
+            pest::error::LineColLocation::Pos(line_col) => line_col,
+            pest::error::LineColLocation::Span(line_col, _) => line_col,
+        };
+
+        Self::with_span(
+            ErrorKind::Syntax(error.variant.message().to_string()),
+            Span::new(start, end, line, column),
+        )
+    }
+}
+
+impl From<hound::Error> for MusathError {
+    fn from(error: hound::Error) -> Self {
+        Self::new(ErrorKind::Wav(error))
+    }
+}
+
+impl From<std::io::Error> for MusathError {
+    fn from(error: std::io::Error) -> Self {
+        Self::new(ErrorKind::Io(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pest::Parser;
+
+    use crate::MusathParser;
+
+    use super::*;
+
+    #[test]
+    fn test_syntax_error_span() {
+        let source = "TITLE = \"test\"\n\noutput(t) = t +\n";
+
+        let error = MusathError::from(MusathParser::parse(Rule::document, source).unwrap_err());
+
+        assert!(matches!(error.kind(), ErrorKind::Syntax(_)));
+        assert_eq!(error.span().unwrap().line(), 4);
+    }
+
+    #[test]
+    fn test_render() {
+        let source = "output(t) = sine(t)";
+
+        let error = MusathError::with_span(
+            ErrorKind::UndefinedFunction(String::from("sine")),
+            Span::new(12, 16, 1, 13),
+        );
+
+        assert_eq!(
+            error.render("test.mth", source),
+            "error: undefined function `sine`\n \
+             --> test.mth:1:13\n  \
+             |\n\
+             1 | output(t) = sine(t)\n  \
+             |             ^^^^\n",
+        );
+    }
+}
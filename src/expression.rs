@@ -1,202 +1,349 @@
-use pest::iterators::Pairs;
+use std::fmt::{self, Display};
 
-use crate::{Rule, context::Context};
+use lazy_static::lazy_static;
+use pest::{
+    iterators::Pairs,
+    pratt_parser::{Assoc, Op, PrattParser},
+};
+
+use crate::{
+    Rule,
+    context::Context,
+    error::{ErrorKind, MusathError, Span},
+    pitch::Note,
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expression {
     Primary(Primary),
     Unary(UnaryOperator, Box<Expression>),
     Binary(Box<Expression>, BinaryOperator, Box<Expression>),
+    Conditional(Box<Expression>, Box<Expression>, Box<Expression>),
+    Let(Vec<(String, Expression)>, Box<Expression>),
 }
 
 impl Expression {
+    /// Parses an expression using the current language version's operator
+    /// precedence.
     pub fn parse(pairs: &mut Pairs<Rule>) -> Self {
-        let remainder_pair = pairs.next().unwrap();
-        assert!(
-            matches!(remainder_pair.as_rule(), Rule::remainder),
-            "expected remainder, found {:?}",
-            remainder_pair
-        );
-        Remainder::parse(&mut remainder_pair.into_inner())
+        Self::parse_versioned(pairs, LanguageVersion::default())
     }
 
-    pub fn eval(&self, context: &Context) -> f64 {
-        match self {
-            Self::Binary(left, operator, right) => {
-                operator.eval(left.eval(context), right.eval(context))
+    pub fn parse_versioned(pairs: &mut Pairs<Rule>, version: LanguageVersion) -> Self {
+        match pairs.peek().map(|pair| pair.as_rule()) {
+            Some(Rule::conditional) => {
+                return Conditional::parse(&mut pairs.next().unwrap().into_inner(), version);
             }
-            Self::Unary(operator, operand) => operator.eval(operand.eval(context)),
-            Self::Primary(primary) => primary.eval(context),
+            Some(Rule::let_expression) => {
+                return Let::parse(&mut pairs.next().unwrap().into_inner(), version);
+            }
+            _ => (),
         }
+
+        version
+            .pratt_parser()
+            .map_primary(|primary| match primary.as_rule() {
+                Rule::primary => Primary::parse_versioned(&mut primary.into_inner(), version),
+                rule => unreachable!("expected primary, found {:?}", rule),
+            })
+            .map_prefix(|operator, operand| {
+                let operator = match operator.as_rule() {
+                    Rule::neg => UnaryOperator::Negate,
+                    Rule::not => UnaryOperator::Not,
+                    rule => unreachable!("expected prefix operator, found {:?}", rule),
+                };
+
+                Expression::Unary(operator, Box::new(operand))
+            })
+            .map_infix(|left, operator, right| {
+                let operator = match operator.as_rule() {
+                    Rule::add => BinaryOperator::Add,
+                    Rule::sub => BinaryOperator::Subtract,
+                    Rule::mul => BinaryOperator::Multiply,
+                    Rule::div => BinaryOperator::Divide,
+                    Rule::pow => BinaryOperator::Exponentiate,
+                    Rule::rem => BinaryOperator::Remainder,
+                    Rule::eq => BinaryOperator::Equal,
+                    Rule::ne => BinaryOperator::NotEqual,
+                    Rule::lt => BinaryOperator::Less,
+                    Rule::le => BinaryOperator::LessOrEqual,
+                    Rule::gt => BinaryOperator::Greater,
+                    Rule::ge => BinaryOperator::GreaterOrEqual,
+                    Rule::and => BinaryOperator::And,
+                    Rule::or => BinaryOperator::Or,
+                    rule => unreachable!("expected infix operator, found {:?}", rule),
+                };
+
+                Expression::Binary(Box::new(left), operator, Box::new(right))
+            })
+            .parse(pairs)
     }
-}
 
-pub struct Remainder;
+    /// Evaluates the expression. Only the taken branch of a conditional is
+    /// evaluated, and `&&`/`||` skip their right operand when the left one
+    /// already decides the result.
+    pub fn eval(&self, context: &mut Context) -> Result<f64, MusathError> {
+        match self {
+            Self::Binary(left, operator, right) => {
+                let left = left.eval(context)?;
 
-impl Remainder {
-    pub fn parse(pairs: &mut Pairs<Rule>) -> Expression {
-        let first_term_pair = pairs.next().unwrap();
-        assert!(
-            matches!(first_term_pair.as_rule(), Rule::term),
-            "expected term, found {:?}",
-            first_term_pair
-        );
-        let first_term = Term::parse(&mut first_term_pair.into_inner());
+                match operator {
+                    BinaryOperator::And if !is_truthy(left) => Ok(0.0),
+                    BinaryOperator::Or if is_truthy(left) => Ok(1.0),
+                    _ => Ok(operator.eval(left, right.eval(context)?)),
+                }
+            }
+            Self::Unary(operator, operand) => Ok(operator.eval(operand.eval(context)?)),
+            Self::Primary(primary) => primary.eval(context),
+            Self::Conditional(condition, consequent, alternative) => {
+                if is_truthy(condition.eval(context)?) {
+                    consequent.eval(context)
+                } else {
+                    alternative.eval(context)
+                }
+            }
+            Self::Let(bindings, body) => {
+                let mut bound = 0;
 
-        let mut remainder = first_term;
+                let value = bindings
+                    .iter()
+                    .try_for_each(|(identifier, expression)| {
+                        let value = expression.eval(context)?;
 
-        while let Some(operator_pair) = pairs.next() {
-            let next_term_pair = pairs.next().unwrap();
-            assert!(
-                matches!(next_term_pair.as_rule(), Rule::term),
-                "expected term, found {:?}",
-                next_term_pair
-            );
-            let next_term = Term::parse(&mut next_term_pair.into_inner());
+                        context.push_value(identifier, value);
+                        bound += 1;
 
-            let operator = match operator_pair.as_rule() {
-                Rule::rem => BinaryOperator::Remainder,
-                _ => unreachable!("expected %, found {}", operator_pair),
-            };
+                        Ok(())
+                    })
+                    .and_then(|()| body.eval(context));
 
-            remainder = Expression::Binary(Box::new(remainder), operator, Box::new(next_term))
+                // The bindings go out of scope even if evaluating failed.
+                for (identifier, _) in &bindings[..bound] {
+                    context.pop_value(identifier);
+                }
+
+                value
+            }
         }
+    }
 
-        remainder
+    /// Returns a copy of the expression with every span reset to the default,
+    /// for comparing structure regardless of where the source text came from.
+    pub fn without_spans(&self) -> Self {
+        match self {
+            Self::Primary(primary) => Self::Primary(primary.without_spans()),
+            Self::Unary(operator, operand) => {
+                Self::Unary(operator.clone(), Box::new(operand.without_spans()))
+            }
+            Self::Binary(left, operator, right) => Self::Binary(
+                Box::new(left.without_spans()),
+                operator.clone(),
+                Box::new(right.without_spans()),
+            ),
+            Self::Conditional(condition, consequent, alternative) => Self::Conditional(
+                Box::new(condition.without_spans()),
+                Box::new(consequent.without_spans()),
+                Box::new(alternative.without_spans()),
+            ),
+            Self::Let(bindings, body) => Self::Let(
+                bindings
+                    .iter()
+                    .map(|(identifier, expression)| {
+                        (identifier.clone(), expression.without_spans())
+                    })
+                    .collect(),
+                Box::new(body.without_spans()),
+            ),
+        }
     }
 }
 
-pub struct Term;
-
-impl Term {
-    pub fn parse(pairs: &mut Pairs<Rule>) -> Expression {
-        let first_factor_pair = pairs.next().unwrap();
-        assert!(
-            matches!(first_factor_pair.as_rule(), Rule::factor),
-            "expected factor, found {:?}",
-            first_factor_pair
-        );
-        let first_factor = Factor::parse(&mut first_factor_pair.into_inner());
-
-        let mut term = first_factor;
+/// Writes the expression in source syntax, parenthesizing every operand that
+/// is not a primary so the result does not depend on operator precedence.
+impl Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Primary(primary) => write!(f, "{}", primary),
+            Self::Unary(operator, operand) => write!(f, "{}{}", operator, Operand(operand)),
+            Self::Binary(left, operator, right) => {
+                write!(f, "{} {} {}", Operand(left), operator, Operand(right))
+            }
+            Self::Conditional(condition, consequent, alternative) => write!(
+                f,
+                "if {} then {} else {}",
+                condition, consequent, alternative
+            ),
+            Self::Let(bindings, body) => {
+                write!(f, "let ")?;
 
-        while let Some(operator_pair) = pairs.next() {
-            let next_factor_pair = pairs.next().unwrap();
-            assert!(
-                matches!(next_factor_pair.as_rule(), Rule::factor),
-                "expected factor, found {:?}",
-                next_factor_pair
-            );
-            let next_factor = Factor::parse(&mut next_factor_pair.into_inner());
+                for (index, (identifier, expression)) in bindings.iter().enumerate() {
+                    let separator = if index == 0 { "" } else { ", " };
 
-            let operator = match operator_pair.as_rule() {
-                Rule::add => BinaryOperator::Add,
-                Rule::sub => BinaryOperator::Subtract,
-                _ => unreachable!("expected + or -, found {}", operator_pair),
-            };
+                    write!(f, "{}{} = {}", separator, identifier, expression)?;
+                }
 
-            term = Expression::Binary(Box::new(term), operator, Box::new(next_factor))
+                write!(f, " in {}", body)
+            }
         }
-
-        term
     }
 }
 
-pub struct Factor;
+/// An operand of a unary or binary operator, parenthesized unless it is a
+/// primary.
+struct Operand<'a>(&'a Expression);
 
-impl Factor {
-    pub fn parse(pairs: &mut Pairs<Rule>) -> Expression {
-        let first_power_pair = pairs.next().unwrap();
-        assert!(
-            matches!(first_power_pair.as_rule(), Rule::power),
-            "expected power, found {:?}",
-            first_power_pair
-        );
-        let first_power = Power::parse(&mut first_power_pair.into_inner());
+impl Display for Operand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Expression::Primary(primary) => write!(f, "{}", primary),
+            expression => write!(f, "({})", expression),
+        }
+    }
+}
 
-        let mut factor = first_power;
+/// Any nonzero value is considered true.
+pub fn is_truthy(value: f64) -> bool {
+    value != 0.0
+}
 
-        while let Some(operator_pair) = pairs.next() {
-            let next_power_pair = pairs.next().unwrap();
-            assert!(
-                matches!(next_power_pair.as_rule(), Rule::power),
-                "expected factor, found {:?}",
-                next_power_pair
-            );
-            let next_power = Power::parse(&mut next_power_pair.into_inner());
+fn from_bool(value: bool) -> f64 {
+    if value { 1.0 } else { 0.0 }
+}
 
-            let operator = match operator_pair.as_rule() {
-                Rule::mul => BinaryOperator::Multiply,
-                Rule::div => BinaryOperator::Divide,
-                _ => unreachable!("expected * or /, found {}", operator_pair),
-            };
+/// Selects the operator precedence used to parse expressions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LanguageVersion {
+    /// `%` binds looser than `+` and `-`, and negation binds tighter than `^`.
+    V1,
+    /// Conventional precedence, from loosest to tightest: `||`, `&&`,
+    /// comparisons, `+ -`, `* / %`, prefix `- !`, and right-associative `^`.
+    #[default]
+    V2,
+}
 
-            factor = Expression::Binary(Box::new(factor), operator, Box::new(next_power))
+impl LanguageVersion {
+    pub fn from_number(number: f64) -> Option<Self> {
+        match number {
+            1.0 => Some(Self::V1),
+            2.0 => Some(Self::V2),
+            _ => None,
         }
+    }
 
-        factor
+    fn pratt_parser(&self) -> &'static PrattParser<Rule> {
+        match self {
+            Self::V1 => &V1_PRATT_PARSER,
+            Self::V2 => &V2_PRATT_PARSER,
+        }
     }
 }
 
-pub struct Power;
-
-impl Power {
-    pub fn parse(pairs: &mut Pairs<Rule>) -> Expression {
-        let first_unary_pair = pairs.next().unwrap();
-        assert!(
-            matches!(first_unary_pair.as_rule(), Rule::unary),
-            "expected unary, found {:?}",
-            first_unary_pair
-        );
-        let first_unary = Unary::parse(&mut first_unary_pair.into_inner());
-
-        let mut power = first_unary;
-
-        if let Some(operator_pair) = pairs.next() {
-            let next_power = Power::parse(pairs);
+lazy_static! {
+    static ref V1_PRATT_PARSER: PrattParser<Rule> = PrattParser::new()
+        .op(Op::infix(Rule::or, Assoc::Left))
+        .op(Op::infix(Rule::and, Assoc::Left))
+        .op(Op::infix(Rule::eq, Assoc::Left)
+            | Op::infix(Rule::ne, Assoc::Left)
+            | Op::infix(Rule::lt, Assoc::Left)
+            | Op::infix(Rule::le, Assoc::Left)
+            | Op::infix(Rule::gt, Assoc::Left)
+            | Op::infix(Rule::ge, Assoc::Left))
+        .op(Op::infix(Rule::rem, Assoc::Left))
+        .op(Op::infix(Rule::add, Assoc::Left) | Op::infix(Rule::sub, Assoc::Left))
+        .op(Op::infix(Rule::mul, Assoc::Left) | Op::infix(Rule::div, Assoc::Left))
+        .op(Op::infix(Rule::pow, Assoc::Right))
+        .op(Op::prefix(Rule::neg) | Op::prefix(Rule::not));
+    static ref V2_PRATT_PARSER: PrattParser<Rule> = PrattParser::new()
+        .op(Op::infix(Rule::or, Assoc::Left))
+        .op(Op::infix(Rule::and, Assoc::Left))
+        .op(Op::infix(Rule::eq, Assoc::Left)
+            | Op::infix(Rule::ne, Assoc::Left)
+            | Op::infix(Rule::lt, Assoc::Left)
+            | Op::infix(Rule::le, Assoc::Left)
+            | Op::infix(Rule::gt, Assoc::Left)
+            | Op::infix(Rule::ge, Assoc::Left))
+        .op(Op::infix(Rule::add, Assoc::Left) | Op::infix(Rule::sub, Assoc::Left))
+        .op(Op::infix(Rule::mul, Assoc::Left)
+            | Op::infix(Rule::div, Assoc::Left)
+            | Op::infix(Rule::rem, Assoc::Left))
+        .op(Op::prefix(Rule::neg) | Op::prefix(Rule::not))
+        .op(Op::infix(Rule::pow, Assoc::Right));
+}
 
-            let operator = match operator_pair.as_rule() {
-                Rule::pow => BinaryOperator::Exponentiate,
-                _ => unreachable!("expected ^, found {}", operator_pair),
-            };
+pub struct Conditional;
 
-            power = Expression::Binary(Box::new(power), operator, Box::new(next_power))
-        }
-
-        power
+impl Conditional {
+    pub fn parse(pairs: &mut Pairs<Rule>, version: LanguageVersion) -> Expression {
+        let mut expressions = pairs.map(|pair| {
+            assert!(
+                matches!(pair.as_rule(), Rule::expression),
+                "expected expression, found {:?}",
+                pair
+            );
+            Box::new(Expression::parse_versioned(&mut pair.into_inner(), version))
+        });
+
+        Expression::Conditional(
+            expressions.next().unwrap(),
+            expressions.next().unwrap(),
+            expressions.next().unwrap(),
+        )
     }
 }
 
-pub struct Unary;
+/// Parses `let a = x, b = y in body`. Each binding may refer to the ones
+/// before it.
+pub struct Let;
 
-impl Unary {
-    pub fn parse(pairs: &mut Pairs<Rule>) -> Expression {
-        let first_pair = pairs.next().unwrap();
+impl Let {
+    pub fn parse(pairs: &mut Pairs<Rule>, version: LanguageVersion) -> Expression {
+        let mut bindings = Vec::new();
+        let mut body = None;
 
-        match first_pair.as_rule() {
-            Rule::neg => {
-                let unary_pair = pairs.next().unwrap();
+        for pair in pairs {
+            match pair.as_rule() {
+                Rule::let_binding => {
+                    let mut pairs = pair.into_inner();
 
-                Expression::Unary(
-                    UnaryOperator::Negate,
-                    Box::new(Unary::parse(&mut unary_pair.into_inner())),
-                )
+                    let identifier = pairs.next().unwrap().as_str().to_string();
+                    let expression = Expression::parse_versioned(
+                        &mut pairs.next().unwrap().into_inner(),
+                        version,
+                    );
+
+                    bindings.push((identifier, expression));
+                }
+                Rule::expression => {
+                    body = Some(Expression::parse_versioned(&mut pair.into_inner(), version));
+                }
+                rule => unreachable!("expected let_binding or expression, found {:?}", rule),
             }
-            Rule::primary => Primary::parse(&mut first_pair.into_inner()),
-            _ => unreachable!("expected negation or primary, found {:?}", first_pair),
         }
+
+        Expression::Let(bindings, Box::new(body.unwrap()))
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum UnaryOperator {
     Negate,
+    Not,
 }
 
 impl UnaryOperator {
     pub fn eval(&self, operand: f64) -> f64 {
         match self {
             Self::Negate => -operand,
+            Self::Not => from_bool(!is_truthy(operand)),
+        }
+    }
+}
+
+impl Display for UnaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Negate => write!(f, "-"),
+            Self::Not => write!(f, "!"),
         }
     }
 }
@@ -209,9 +356,19 @@ pub enum BinaryOperator {
     Divide,
     Exponentiate,
     Remainder,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    And,
+    Or,
 }
 
 impl BinaryOperator {
+    /// Applies the operator to two values. Comparisons and logical operators
+    /// produce `1` for true and `0` for false.
     pub fn eval(&self, left: f64, right: f64) -> f64 {
         match self {
             Self::Add => left + right,
@@ -220,21 +377,58 @@ impl BinaryOperator {
             Self::Divide => left / right,
             Self::Exponentiate => left.powf(right),
             Self::Remainder => left.rem_euclid(right),
+            Self::Equal => from_bool(left == right),
+            Self::NotEqual => from_bool(left != right),
+            Self::Less => from_bool(left < right),
+            Self::LessOrEqual => from_bool(left <= right),
+            Self::Greater => from_bool(left > right),
+            Self::GreaterOrEqual => from_bool(left >= right),
+            Self::And => from_bool(is_truthy(left) && is_truthy(right)),
+            Self::Or => from_bool(is_truthy(left) || is_truthy(right)),
         }
     }
 }
 
+impl Display for BinaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Self::Add => "+",
+            Self::Subtract => "-",
+            Self::Multiply => "*",
+            Self::Divide => "/",
+            Self::Exponentiate => "^",
+            Self::Remainder => "%",
+            Self::Equal => "==",
+            Self::NotEqual => "!=",
+            Self::Less => "<",
+            Self::LessOrEqual => "<=",
+            Self::Greater => ">",
+            Self::GreaterOrEqual => ">=",
+            Self::And => "&&",
+            Self::Or => "||",
+        };
+
+        write!(f, "{}", symbol)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Primary {
     Decimal(f64),
     Integer(i64),
-    Call(String, Vec<Box<Expression>>),
-    Identifier(String),
+    /// A note name such as `C#3`, which evaluates to its frequency in Hz.
+    Note(Note),
+    Call(String, Vec<Box<Expression>>, Span),
+    Identifier(String, Span),
     Grouping(Box<Expression>),
 }
 
 impl Primary {
     pub fn parse(pairs: &mut Pairs<Rule>) -> Expression {
+        Self::parse_versioned(pairs, LanguageVersion::default())
+    }
+
+    pub fn parse_versioned(pairs: &mut Pairs<Rule>, version: LanguageVersion) -> Expression {
         let pair = pairs.next().unwrap();
 
         Expression::Primary(match pair.as_rule() {
@@ -248,57 +442,104 @@ impl Primary {
                     Rule::integer => Self::Integer(specific_pair.as_str().parse::<i64>().unwrap()),
                     _ => unreachable!("expected decimal or integer, found {:?}", specific_pair),
                 }
-            },
+            }
+            Rule::note => Self::Note(Note::parse(pair.as_str()).unwrap()),
             Rule::function_call => {
                 let mut pairs = pair.into_inner();
 
                 let identifier_pair = pairs.next().unwrap();
                 let identifier = identifier_pair.as_str().to_string();
+                let span = Span::from(identifier_pair.as_span());
 
                 let arguments = pairs
                     .map(|expression_pair| {
-                        Box::new(Expression::parse(&mut expression_pair.into_inner()))
+                        Box::new(Expression::parse_versioned(
+                            &mut expression_pair.into_inner(),
+                            version,
+                        ))
                     })
                     .collect();
 
-                Self::Call(identifier, arguments)
+                Self::Call(identifier, arguments, span)
+            }
+            Rule::qualified_identifier => {
+                Self::Identifier(pair.as_str().to_string(), Span::from(pair.as_span()))
             }
-            Rule::identifier => Self::Identifier(pair.as_str().to_string()),
-            Rule::expression => Self::Grouping(Box::new(Expression::parse(&mut pair.into_inner()))),
+            Rule::expression => Self::Grouping(Box::new(Expression::parse_versioned(
+                &mut pair.into_inner(),
+                version,
+            ))),
             _ => unreachable!(
-                "expected number, identifier, or grouped expression, found {}",
+                "expected number, note, identifier, or grouped expression, found {}",
                 pair
             ),
         })
     }
 
-    pub fn eval(&self, context: &Context) -> f64 {
+    pub fn eval(&self, context: &mut Context) -> Result<f64, MusathError> {
         match self {
-            Self::Decimal(number) => *number,
-            Self::Integer(number) => *number as f64,
-            Self::Call(identifier, arguments) => {
-                let function = context
-                    .function(identifier)
-                    .unwrap_or_else(|| panic!("undefined function {}", identifier));
-
-                let mut inner_context = context.clone();
-
-                for (parameter_identifier, argument_expression) in function
-                    .signature()
-                    .parameters()
+            Self::Decimal(number) => Ok(*number),
+            Self::Integer(number) => Ok(*number as f64),
+            Self::Note(note) => Ok(note.frequency(context.a4_hz())),
+            Self::Call(identifier, arguments, span) => {
+                let functions = context.shared_functions();
+                let function = functions.get(identifier).ok_or_else(|| {
+                    MusathError::with_span(ErrorKind::UndefinedFunction(identifier.clone()), *span)
+                })?;
+
+                function.eval(arguments, context)
+            }
+            Self::Identifier(identifier, span) => {
+                context.value(identifier).copied().ok_or_else(|| {
+                    MusathError::with_span(
+                        ErrorKind::UndefinedIdentifier(identifier.clone()),
+                        *span,
+                    )
+                })
+            }
+            Self::Grouping(expression) => expression.eval(context),
+        }
+    }
+
+    fn without_spans(&self) -> Self {
+        match self {
+            Self::Call(identifier, arguments, _) => Self::Call(
+                identifier.clone(),
+                arguments
                     .iter()
-                    .zip(arguments.iter())
-                {
-                    inner_context
-                        .push_value(parameter_identifier, argument_expression.eval(context));
+                    .map(|argument| Box::new(argument.without_spans()))
+                    .collect(),
+                Span::default(),
+            ),
+            Self::Identifier(identifier, _) => {
+                Self::Identifier(identifier.clone(), Span::default())
+            }
+            Self::Grouping(expression) => Self::Grouping(Box::new(expression.without_spans())),
+            primary => primary.clone(),
+        }
+    }
+}
+
+impl Display for Primary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Decimal(number) if number.is_sign_negative() => write!(f, "({})", number),
+            Self::Decimal(number) => write!(f, "{}", number),
+            Self::Integer(number) => write!(f, "{}", number),
+            Self::Note(note) => write!(f, "{}", note),
+            Self::Call(identifier, arguments, _) => {
+                write!(f, "{}(", identifier)?;
+
+                for (index, argument) in arguments.iter().enumerate() {
+                    let separator = if index == 0 { "" } else { ", " };
+
+                    write!(f, "{}{}", separator, argument)?;
                 }
 
-                function.eval(arguments, &inner_context)
+                write!(f, ")")
             }
-            Self::Identifier(identifier) => *context
-                .value(identifier)
-                .unwrap_or_else(|| panic!("undefined identifier {}", identifier)),
-            Self::Grouping(expression) => expression.eval(context),
+            Self::Identifier(identifier, _) => write!(f, "{}", identifier),
+            Self::Grouping(expression) => write!(f, "({})", expression),
         }
     }
 }
@@ -320,8 +561,9 @@ mod tests {
                     .next()
                     .unwrap()
                     .into_inner()
-            ),
-            Expression::Primary(Primary::Decimal(1.0)),
+            )
+            .without_spans(),
+            Expression::Primary(Primary::Integer(1)),
         );
 
         assert_eq!(
@@ -331,7 +573,8 @@ mod tests {
                     .next()
                     .unwrap()
                     .into_inner()
-            ),
+            )
+            .without_spans(),
             Expression::Primary(Primary::Decimal(2.3)),
         );
 
@@ -342,8 +585,9 @@ mod tests {
                     .next()
                     .unwrap()
                     .into_inner()
-            ),
-            Expression::Primary(Primary::Identifier(String::from("test"))),
+            )
+            .without_spans(),
+            Expression::Primary(Primary::Identifier(String::from("test"), Span::default())),
         );
 
         assert_eq!(
@@ -353,9 +597,10 @@ mod tests {
                     .next()
                     .unwrap()
                     .into_inner()
-            ),
+            )
+            .without_spans(),
             Expression::Primary(Primary::Grouping(Box::new(Expression::Primary(
-                Primary::Decimal(1.0)
+                Primary::Integer(1)
             )))),
         );
 
@@ -366,10 +611,12 @@ mod tests {
                     .next()
                     .unwrap()
                     .into_inner()
-            ),
+            )
+            .without_spans(),
             Expression::Primary(Primary::Call(
                 String::from("test"),
-                vec![Box::new(Expression::Primary(Primary::Decimal(1.0)))]
+                vec![Box::new(Expression::Primary(Primary::Integer(1)))],
+                Span::default(),
             )),
         );
 
@@ -380,37 +627,63 @@ mod tests {
                     .next()
                     .unwrap()
                     .into_inner()
-            ),
+            )
+            .without_spans(),
             Expression::Primary(Primary::Call(
                 String::from("test"),
                 vec![
-                    Box::new(Expression::Primary(Primary::Decimal(1.0))),
+                    Box::new(Expression::Primary(Primary::Integer(1))),
                     Box::new(Expression::Binary(
-                        Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                        Box::new(Expression::Primary(Primary::Integer(2))),
                         BinaryOperator::Add,
-                        Box::new(Expression::Primary(Primary::Decimal(3.0))),
+                        Box::new(Expression::Primary(Primary::Integer(3))),
                     )),
-                ]
+                ],
+                Span::default(),
             )),
         );
+
+        assert_eq!(
+            Primary::parse(
+                &mut MusathParser::parse(Rule::primary, "C#3")
+                    .unwrap()
+                    .next()
+                    .unwrap()
+                    .into_inner()
+            )
+            .without_spans(),
+            Expression::Primary(Primary::Note(Note::parse("C#3").unwrap())),
+        );
+
+        assert_eq!(
+            Primary::parse(
+                &mut MusathParser::parse(Rule::primary, "Eb2x")
+                    .unwrap()
+                    .next()
+                    .unwrap()
+                    .into_inner()
+            )
+            .without_spans(),
+            Expression::Primary(Primary::Identifier(String::from("Eb2x"), Span::default())),
+        );
     }
 
     #[test]
     fn test_parse_unary() {
         assert_eq!(
-            Unary::parse(
-                &mut MusathParser::parse(Rule::unary, "1")
+            Expression::parse(
+                &mut MusathParser::parse(Rule::expression, "1")
                     .unwrap()
                     .next()
                     .unwrap()
                     .into_inner()
             ),
-            Expression::Primary(Primary::Decimal(1.0)),
+            Expression::Primary(Primary::Integer(1)),
         );
 
         assert_eq!(
-            Unary::parse(
-                &mut MusathParser::parse(Rule::unary, "-1")
+            Expression::parse(
+                &mut MusathParser::parse(Rule::expression, "-1")
                     .unwrap()
                     .next()
                     .unwrap()
@@ -418,13 +691,13 @@ mod tests {
             ),
             Expression::Unary(
                 UnaryOperator::Negate,
-                Box::new(Expression::Primary(Primary::Decimal(1.0)))
+                Box::new(Expression::Primary(Primary::Integer(1)))
             ),
         );
 
         assert_eq!(
-            Unary::parse(
-                &mut MusathParser::parse(Rule::unary, "--1")
+            Expression::parse(
+                &mut MusathParser::parse(Rule::expression, "--1")
                     .unwrap()
                     .next()
                     .unwrap()
@@ -434,7 +707,7 @@ mod tests {
                 UnaryOperator::Negate,
                 Box::new(Expression::Unary(
                     UnaryOperator::Negate,
-                    Box::new(Expression::Primary(Primary::Decimal(1.0)))
+                    Box::new(Expression::Primary(Primary::Integer(1)))
                 ))
             ),
         );
@@ -443,42 +716,42 @@ mod tests {
     #[test]
     fn test_parse_power() {
         assert_eq!(
-            Power::parse(
-                &mut MusathParser::parse(Rule::power, "1^2")
+            Expression::parse(
+                &mut MusathParser::parse(Rule::expression, "1^2")
                     .unwrap()
                     .next()
                     .unwrap()
                     .into_inner()
             ),
             Expression::Binary(
-                Box::new(Expression::Primary(Primary::Decimal(1.0))),
+                Box::new(Expression::Primary(Primary::Integer(1))),
                 BinaryOperator::Exponentiate,
-                Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                Box::new(Expression::Primary(Primary::Integer(2))),
             ),
         );
 
         assert_eq!(
-            Power::parse(
-                &mut MusathParser::parse(Rule::power, "1^2^3")
+            Expression::parse(
+                &mut MusathParser::parse(Rule::expression, "1^2^3")
                     .unwrap()
                     .next()
                     .unwrap()
                     .into_inner()
             ),
             Expression::Binary(
-                Box::new(Expression::Primary(Primary::Decimal(1.0))),
+                Box::new(Expression::Primary(Primary::Integer(1))),
                 BinaryOperator::Exponentiate,
                 Box::new(Expression::Binary(
-                    Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                    Box::new(Expression::Primary(Primary::Integer(2))),
                     BinaryOperator::Exponentiate,
-                    Box::new(Expression::Primary(Primary::Decimal(3.0))),
+                    Box::new(Expression::Primary(Primary::Integer(3))),
                 )),
             ),
         );
 
         assert_eq!(
-            Power::parse(
-                &mut MusathParser::parse(Rule::power, "(1^2)^3")
+            Expression::parse(
+                &mut MusathParser::parse(Rule::expression, "(1^2)^3")
                     .unwrap()
                     .next()
                     .unwrap()
@@ -487,13 +760,13 @@ mod tests {
             Expression::Binary(
                 Box::new(Expression::Primary(Primary::Grouping(Box::new(
                     Expression::Binary(
-                        Box::new(Expression::Primary(Primary::Decimal(1.0))),
+                        Box::new(Expression::Primary(Primary::Integer(1))),
                         BinaryOperator::Exponentiate,
-                        Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                        Box::new(Expression::Primary(Primary::Integer(2))),
                     )
                 )))),
                 BinaryOperator::Exponentiate,
-                Box::new(Expression::Primary(Primary::Decimal(3.0))),
+                Box::new(Expression::Primary(Primary::Integer(3))),
             ),
         );
     }
@@ -501,23 +774,23 @@ mod tests {
     #[test]
     fn test_parse_term() {
         assert_eq!(
-            Term::parse(
-                &mut MusathParser::parse(Rule::term, "1 + 2")
+            Expression::parse(
+                &mut MusathParser::parse(Rule::expression, "1 + 2")
                     .unwrap()
                     .next()
                     .unwrap()
                     .into_inner()
             ),
             Expression::Binary(
-                Box::new(Expression::Primary(Primary::Decimal(1.0))),
+                Box::new(Expression::Primary(Primary::Integer(1))),
                 BinaryOperator::Add,
-                Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                Box::new(Expression::Primary(Primary::Integer(2))),
             ),
         );
 
         assert_eq!(
-            Term::parse(
-                &mut MusathParser::parse(Rule::term, "1 + 2 - 3")
+            Expression::parse(
+                &mut MusathParser::parse(Rule::expression, "1 + 2 - 3")
                     .unwrap()
                     .next()
                     .unwrap()
@@ -525,18 +798,18 @@ mod tests {
             ),
             Expression::Binary(
                 Box::new(Expression::Binary(
-                    Box::new(Expression::Primary(Primary::Decimal(1.0))),
+                    Box::new(Expression::Primary(Primary::Integer(1))),
                     BinaryOperator::Add,
-                    Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                    Box::new(Expression::Primary(Primary::Integer(2))),
                 )),
                 BinaryOperator::Subtract,
-                Box::new(Expression::Primary(Primary::Decimal(3.0))),
+                Box::new(Expression::Primary(Primary::Integer(3))),
             ),
         );
 
         assert_eq!(
-            Term::parse(
-                &mut MusathParser::parse(Rule::term, "1 + -2 - 3")
+            Expression::parse(
+                &mut MusathParser::parse(Rule::expression, "1 + -2 - 3")
                     .unwrap()
                     .next()
                     .unwrap()
@@ -544,23 +817,23 @@ mod tests {
             ),
             Expression::Binary(
                 Box::new(Expression::Binary(
-                    Box::new(Expression::Primary(Primary::Decimal(1.0))),
+                    Box::new(Expression::Primary(Primary::Integer(1))),
                     BinaryOperator::Add,
                     Box::new(Expression::Unary(
                         UnaryOperator::Negate,
-                        Box::new(Expression::Primary(Primary::Decimal(2.0)))
+                        Box::new(Expression::Primary(Primary::Integer(2)))
                     )),
                 )),
                 BinaryOperator::Subtract,
-                Box::new(Expression::Primary(Primary::Decimal(3.0))),
+                Box::new(Expression::Primary(Primary::Integer(3))),
             ),
         );
     }
 
     #[test]
     fn test_parse_remainder() {
-        let remainder = Remainder::parse(
-            &mut MusathParser::parse(Rule::remainder, "1 % 2")
+        let remainder = Expression::parse(
+            &mut MusathParser::parse(Rule::expression, "1 % 2")
                 .unwrap()
                 .next()
                 .unwrap()
@@ -570,16 +843,16 @@ mod tests {
         assert_eq!(
             remainder,
             Expression::Binary(
-                Box::new(Expression::Primary(Primary::Decimal(1.0))),
+                Box::new(Expression::Primary(Primary::Integer(1))),
                 BinaryOperator::Remainder,
-                Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                Box::new(Expression::Primary(Primary::Integer(2))),
             ),
         );
 
-        assert_eq!(remainder.eval(&Context::default()), 1.0);
+        assert_eq!(remainder.eval(&mut Context::default()).unwrap(), 1.0);
 
-        let remainder = Remainder::parse(
-            &mut MusathParser::parse(Rule::remainder, "3.5 % 2")
+        let remainder = Expression::parse(
+            &mut MusathParser::parse(Rule::expression, "3.5 % 2")
                 .unwrap()
                 .next()
                 .unwrap()
@@ -591,11 +864,89 @@ mod tests {
             Expression::Binary(
                 Box::new(Expression::Primary(Primary::Decimal(3.5))),
                 BinaryOperator::Remainder,
-                Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                Box::new(Expression::Primary(Primary::Integer(2))),
+            ),
+        );
+
+        assert_eq!(remainder.eval(&mut Context::default()).unwrap(), 1.5);
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        let parse = |source, version| {
+            Expression::parse_versioned(
+                &mut MusathParser::parse(Rule::expression, source)
+                    .unwrap()
+                    .next()
+                    .unwrap()
+                    .into_inner(),
+                version,
+            )
+        };
+
+        assert_eq!(
+            parse("5 - 3 % 2", LanguageVersion::V2),
+            Expression::Binary(
+                Box::new(Expression::Primary(Primary::Integer(5))),
+                BinaryOperator::Subtract,
+                Box::new(Expression::Binary(
+                    Box::new(Expression::Primary(Primary::Integer(3))),
+                    BinaryOperator::Remainder,
+                    Box::new(Expression::Primary(Primary::Integer(2))),
+                )),
+            ),
+        );
+
+        assert_eq!(
+            parse("5 - 3 % 2", LanguageVersion::V1),
+            Expression::Binary(
+                Box::new(Expression::Binary(
+                    Box::new(Expression::Primary(Primary::Integer(5))),
+                    BinaryOperator::Subtract,
+                    Box::new(Expression::Primary(Primary::Integer(3))),
+                )),
+                BinaryOperator::Remainder,
+                Box::new(Expression::Primary(Primary::Integer(2))),
+            ),
+        );
+
+        assert_eq!(
+            parse("-2^2", LanguageVersion::V2),
+            Expression::Unary(
+                UnaryOperator::Negate,
+                Box::new(Expression::Binary(
+                    Box::new(Expression::Primary(Primary::Integer(2))),
+                    BinaryOperator::Exponentiate,
+                    Box::new(Expression::Primary(Primary::Integer(2))),
+                )),
             ),
         );
 
-        assert_eq!(remainder.eval(&Context::default()), 1.5);
+        for (source, v1, v2) in [
+            ("5 - 3 % 2", 0.0, 4.0),
+            ("-2^2", 4.0, -4.0),
+            ("2^-1", 0.5, 0.5),
+            ("2^3^2", 512.0, 512.0),
+            ("6 / 3 * 2 % 3", 1.0, 1.0),
+            ("1 + 2 == 3 && 4 > 3", 1.0, 1.0),
+        ] {
+            assert_eq!(
+                parse(source, LanguageVersion::V1)
+                    .eval(&mut Context::default())
+                    .unwrap(),
+                v1,
+                "{}",
+                source
+            );
+            assert_eq!(
+                parse(source, LanguageVersion::V2)
+                    .eval(&mut Context::default())
+                    .unwrap(),
+                v2,
+                "{}",
+                source
+            );
+        }
     }
 
     #[test]
@@ -609,9 +960,9 @@ mod tests {
                     .into_inner()
             ),
             Expression::Binary(
-                Box::new(Expression::Primary(Primary::Decimal(1.0))),
+                Box::new(Expression::Primary(Primary::Integer(1))),
                 BinaryOperator::Add,
-                Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                Box::new(Expression::Primary(Primary::Integer(2))),
             ),
         );
 
@@ -625,12 +976,12 @@ mod tests {
             ),
             Expression::Binary(
                 Box::new(Expression::Binary(
-                    Box::new(Expression::Primary(Primary::Decimal(1.0))),
+                    Box::new(Expression::Primary(Primary::Integer(1))),
                     BinaryOperator::Add,
-                    Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                    Box::new(Expression::Primary(Primary::Integer(2))),
                 )),
                 BinaryOperator::Subtract,
-                Box::new(Expression::Primary(Primary::Decimal(3.0))),
+                Box::new(Expression::Primary(Primary::Integer(3))),
             ),
         );
 
@@ -644,15 +995,15 @@ mod tests {
             ),
             Expression::Binary(
                 Box::new(Expression::Binary(
-                    Box::new(Expression::Primary(Primary::Decimal(1.0))),
+                    Box::new(Expression::Primary(Primary::Integer(1))),
                     BinaryOperator::Multiply,
-                    Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                    Box::new(Expression::Primary(Primary::Integer(2))),
                 )),
                 BinaryOperator::Add,
                 Box::new(Expression::Binary(
-                    Box::new(Expression::Primary(Primary::Decimal(3.0))),
+                    Box::new(Expression::Primary(Primary::Integer(3))),
                     BinaryOperator::Multiply,
-                    Box::new(Expression::Primary(Primary::Decimal(4.0))),
+                    Box::new(Expression::Primary(Primary::Integer(4))),
                 )),
             ),
         );
@@ -669,16 +1020,16 @@ mod tests {
                 Box::new(Expression::Binary(
                     Box::new(Expression::Unary(
                         UnaryOperator::Negate,
-                        Box::new(Expression::Primary(Primary::Decimal(1.0)))
+                        Box::new(Expression::Primary(Primary::Integer(1)))
                     )),
                     BinaryOperator::Multiply,
-                    Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                    Box::new(Expression::Primary(Primary::Integer(2))),
                 )),
                 BinaryOperator::Add,
                 Box::new(Expression::Binary(
-                    Box::new(Expression::Primary(Primary::Decimal(3.0))),
+                    Box::new(Expression::Primary(Primary::Integer(3))),
                     BinaryOperator::Divide,
-                    Box::new(Expression::Primary(Primary::Decimal(4.0))),
+                    Box::new(Expression::Primary(Primary::Integer(4))),
                 )),
             ),
         );
@@ -692,9 +1043,9 @@ mod tests {
                     .into_inner()
             ),
             Expression::Binary(
-                Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                Box::new(Expression::Primary(Primary::Integer(2))),
                 BinaryOperator::Exponentiate,
-                Box::new(Expression::Primary(Primary::Decimal(3.0))),
+                Box::new(Expression::Primary(Primary::Integer(3))),
             ),
         );
 
@@ -707,12 +1058,12 @@ mod tests {
                     .into_inner()
             ),
             Expression::Binary(
-                Box::new(Expression::Primary(Primary::Decimal(1.0))),
+                Box::new(Expression::Primary(Primary::Integer(1))),
                 BinaryOperator::Add,
                 Box::new(Expression::Binary(
-                    Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                    Box::new(Expression::Primary(Primary::Integer(2))),
                     BinaryOperator::Exponentiate,
-                    Box::new(Expression::Primary(Primary::Decimal(3.0))),
+                    Box::new(Expression::Primary(Primary::Integer(3))),
                 )),
             ),
         );
@@ -731,24 +1082,245 @@ mod tests {
                 Box::new(Expression::Binary(
                     Box::new(Expression::Unary(
                         UnaryOperator::Negate,
-                        Box::new(Expression::Primary(Primary::Decimal(1.0)))
+                        Box::new(Expression::Primary(Primary::Integer(1)))
                     )),
                     BinaryOperator::Multiply,
-                    Box::new(Expression::Primary(Primary::Decimal(2.0))),
+                    Box::new(Expression::Primary(Primary::Integer(2))),
                 )),
                 BinaryOperator::Add,
                 Box::new(Expression::Binary(
-                    Box::new(Expression::Primary(Primary::Decimal(3.0))),
+                    Box::new(Expression::Primary(Primary::Integer(3))),
                     BinaryOperator::Divide,
                     Box::new(Expression::Binary(
-                        Box::new(Expression::Primary(Primary::Decimal(4.0))),
+                        Box::new(Expression::Primary(Primary::Integer(4))),
                         BinaryOperator::Exponentiate,
-                        Box::new(Expression::Primary(Primary::Decimal(5.0))),
+                        Box::new(Expression::Primary(Primary::Integer(5))),
                     )),
                 )),
             ),
         );
 
-        assert_eq!(expression.eval(&Context::default()), -2.0 + (3.0 / 1024.0));
+        assert_eq!(
+            expression.eval(&mut Context::default()).unwrap(),
+            -2.0 + (3.0 / 1024.0)
+        );
+    }
+
+    #[test]
+    fn test_parse_comparison() {
+        let expression = Expression::parse(
+            &mut MusathParser::parse(Rule::expression, "1 + 2 < 4 && !(2 == 3) || 0")
+                .unwrap()
+                .next()
+                .unwrap()
+                .into_inner(),
+        );
+
+        assert_eq!(
+            expression,
+            Expression::Binary(
+                Box::new(Expression::Binary(
+                    Box::new(Expression::Binary(
+                        Box::new(Expression::Binary(
+                            Box::new(Expression::Primary(Primary::Integer(1))),
+                            BinaryOperator::Add,
+                            Box::new(Expression::Primary(Primary::Integer(2))),
+                        )),
+                        BinaryOperator::Less,
+                        Box::new(Expression::Primary(Primary::Integer(4))),
+                    )),
+                    BinaryOperator::And,
+                    Box::new(Expression::Unary(
+                        UnaryOperator::Not,
+                        Box::new(Expression::Primary(Primary::Grouping(Box::new(
+                            Expression::Binary(
+                                Box::new(Expression::Primary(Primary::Integer(2))),
+                                BinaryOperator::Equal,
+                                Box::new(Expression::Primary(Primary::Integer(3))),
+                            )
+                        )))),
+                    )),
+                )),
+                BinaryOperator::Or,
+                Box::new(Expression::Primary(Primary::Integer(0))),
+            ),
+        );
+
+        assert_eq!(expression.eval(&mut Context::default()).unwrap(), 1.0);
+
+        for (source, value) in [
+            ("2 <= 2", 1.0),
+            ("2 >= 3", 0.0),
+            ("2 > 1", 1.0),
+            ("1 != 1", 0.0),
+            ("0 || 0", 0.0),
+            ("!0", 1.0),
+        ] {
+            assert_eq!(
+                Expression::parse(
+                    &mut MusathParser::parse(Rule::expression, source)
+                        .unwrap()
+                        .next()
+                        .unwrap()
+                        .into_inner()
+                )
+                .eval(&mut Context::default())
+                .unwrap(),
+                value,
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_conditional() {
+        let expression = Expression::parse(
+            &mut MusathParser::parse(Rule::expression, "if 1 < 2 then 3 else undefined(4)")
+                .unwrap()
+                .next()
+                .unwrap()
+                .into_inner(),
+        );
+
+        assert_eq!(
+            expression.without_spans(),
+            Expression::Conditional(
+                Box::new(Expression::Binary(
+                    Box::new(Expression::Primary(Primary::Integer(1))),
+                    BinaryOperator::Less,
+                    Box::new(Expression::Primary(Primary::Integer(2))),
+                )),
+                Box::new(Expression::Primary(Primary::Integer(3))),
+                Box::new(Expression::Primary(Primary::Call(
+                    String::from("undefined"),
+                    vec![Box::new(Expression::Primary(Primary::Integer(4)))],
+                    Span::default(),
+                ))),
+            ),
+        );
+
+        assert_eq!(expression.eval(&mut Context::default()).unwrap(), 3.0);
+
+        assert!(
+            Expression::parse(
+                &mut MusathParser::parse(Rule::expression, "0 && undefined(1)")
+                    .unwrap()
+                    .next()
+                    .unwrap()
+                    .into_inner()
+            )
+            .eval(&mut Context::default())
+            .is_ok()
+        );
+
+        assert!(MusathParser::parse(Rule::identifier, "then").is_err());
+        assert!(MusathParser::parse(Rule::identifier, "iffy").is_ok());
+        assert!(MusathParser::parse(Rule::conditional, "ifx then 1 else 2").is_err());
+    }
+
+    #[test]
+    fn test_parse_let() {
+        let expression = Expression::parse(
+            &mut MusathParser::parse(Rule::expression, "let a = 2, b = a * 3 in a + b")
+                .unwrap()
+                .next()
+                .unwrap()
+                .into_inner(),
+        );
+
+        assert_eq!(
+            expression.without_spans(),
+            Expression::Let(
+                vec![
+                    (String::from("a"), Expression::Primary(Primary::Integer(2))),
+                    (
+                        String::from("b"),
+                        Expression::Binary(
+                            Box::new(Expression::Primary(Primary::Identifier(
+                                String::from("a"),
+                                Span::default()
+                            ))),
+                            BinaryOperator::Multiply,
+                            Box::new(Expression::Primary(Primary::Integer(3))),
+                        )
+                    ),
+                ],
+                Box::new(Expression::Binary(
+                    Box::new(Expression::Primary(Primary::Identifier(
+                        String::from("a"),
+                        Span::default()
+                    ))),
+                    BinaryOperator::Add,
+                    Box::new(Expression::Primary(Primary::Identifier(
+                        String::from("b"),
+                        Span::default()
+                    ))),
+                )),
+            ),
+        );
+
+        assert_eq!(expression.eval(&mut Context::default()).unwrap(), 8.0);
+
+        let mut context = Context::default();
+        context.push_value("a", 1.0);
+
+        assert_eq!(
+            Expression::parse(
+                &mut MusathParser::parse(Rule::expression, "(let a = a + 1 in a * 10) + a")
+                    .unwrap()
+                    .next()
+                    .unwrap()
+                    .into_inner()
+            )
+            .eval(&mut context)
+            .unwrap(),
+            21.0,
+        );
+
+        // Bindings are popped after the body, even if evaluating fails.
+        assert_eq!(context.value("a"), Some(&1.0));
+        assert!(
+            Expression::parse(
+                &mut MusathParser::parse(Rule::expression, "let b = 2, c = d in b + c")
+                    .unwrap()
+                    .next()
+                    .unwrap()
+                    .into_inner()
+            )
+            .eval(&mut context)
+            .is_err()
+        );
+        assert_eq!(context.value("b"), None);
+
+        assert!(MusathParser::parse(Rule::identifier, "in").is_err());
+        assert!(MusathParser::parse(Rule::identifier, "input").is_ok());
+    }
+
+    #[test]
+    fn test_display() {
+        for (source, expected) in [
+            ("1 + 2 * -x", "1 + (2 * (-x))"),
+            ("(a - b) - c ^ 2 ^ 3", "(a - b) - (c ^ (2 ^ 3))"),
+            ("!f(t, 1.5) || g()", "(!f(t, 1.5)) || g()"),
+            ("A4 + C#3 * Eb2", "A4 + (C#3 * Eb2)"),
+            (
+                "if t < 1 then let a = t, b = a in a * b else 0",
+                "if t < 1 then let a = t, b = a in a * b else 0",
+            ),
+        ] {
+            let expression = Expression::parse(
+                &mut MusathParser::parse(Rule::expression, source)
+                    .unwrap()
+                    .next()
+                    .unwrap()
+                    .into_inner(),
+            );
+
+            assert_eq!(expression.to_string(), expected);
+        }
+
+        assert_eq!(
+            Expression::Primary(Primary::Decimal(-0.25)).to_string(),
+            "(-0.25)"
+        );
     }
 }
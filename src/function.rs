@@ -1,37 +1,83 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    fmt::{Debug, Display},
+    sync::Arc,
+};
 
 use pest::iterators::Pairs;
 
-use crate::{Rule, context::Context, expression::Expression};
+use crate::{
+    Rule,
+    context::Context,
+    control_rate::ControlRate,
+    error::{MusathError, Span},
+    expression::{Expression, LanguageVersion},
+};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Function {
     signature: FunctionSignature,
     body: FunctionBody,
+    control_rate: Option<ControlRate>,
 }
 
 impl Function {
-    pub fn new(identifier: impl Into<String>, body: FunctionBodyClosure) -> Self {
+    pub fn new(
+        identifier: impl Into<String>,
+        parameters: &[&str],
+        body: FunctionBodyClosure,
+    ) -> Self {
+        Self::with_arity(identifier, parameters, Arity::Exact(parameters.len()), body)
+    }
+
+    /// A closure-backed function accepting any number of arguments beyond
+    /// `parameters`.
+    pub fn variadic(
+        identifier: impl Into<String>,
+        parameters: &[&str],
+        body: FunctionBodyClosure,
+    ) -> Self {
+        Self::with_arity(
+            identifier,
+            parameters,
+            Arity::AtLeast(parameters.len()),
+            body,
+        )
+    }
+
+    /// A closure-backed function accepting the numbers of arguments `arity`
+    /// allows, which `parameters` only name.
+    pub fn with_arity(
+        identifier: impl Into<String>,
+        parameters: &[&str],
+        arity: Arity,
+        body: FunctionBodyClosure,
+    ) -> Self {
         Self {
-            signature: FunctionSignature {
-                identifier: identifier.into(),
-                parameters: Vec::new(),
-            },
+            signature: FunctionSignature::new(identifier, parameters, arity),
             body: FunctionBody::Closure(body),
+            control_rate: None,
         }
     }
 
     pub fn parse(pairs: &mut Pairs<Rule>) -> Self {
+        Self::parse_versioned(pairs, LanguageVersion::default())
+    }
+
+    pub fn parse_versioned(pairs: &mut Pairs<Rule>, version: LanguageVersion) -> Self {
         let mut signature = None;
         let mut expression = None;
+        let mut control_rate = None;
 
         for pair in pairs {
             match pair.as_rule() {
+                Rule::control_rate => {
+                    control_rate = Some(ControlRate::parse(&mut pair.into_inner()));
+                }
                 Rule::function_signature => {
                     signature = Some(FunctionSignature::parse(&mut pair.into_inner()));
                 }
                 Rule::expression => {
-                    expression = Some(Expression::parse(&mut pair.into_inner()));
+                    expression = Some(Expression::parse_versioned(&mut pair.into_inner(), version));
                 }
                 rule => unreachable!(
                     "expected function identifier or expression, found {:?}",
@@ -43,6 +89,20 @@ impl Function {
         Self {
             signature: signature.unwrap(),
             body: FunctionBody::Expression(expression.unwrap()),
+            control_rate,
+        }
+    }
+
+    /// A copy of the function under a different name with a new expression
+    /// body, keeping its parameters, declaration span and control rate.
+    pub fn renamed(&self, identifier: impl Into<String>, expression: Expression) -> Self {
+        Self {
+            signature: FunctionSignature {
+                identifier: identifier.into(),
+                ..self.signature.clone()
+            },
+            body: FunctionBody::Expression(expression),
+            control_rate: self.control_rate,
         }
     }
 
@@ -54,8 +114,172 @@ impl Function {
         &self.body
     }
 
-    pub fn eval(&self, arguments: &[Box<Expression>], context: &Context) -> f64 {
-        self.body().eval(arguments, context)
+    /// The rate the function is evaluated at if it is annotated with
+    /// `@rate`. See [`control_rate`](crate::control_rate).
+    pub fn control_rate(&self) -> Option<ControlRate> {
+        self.control_rate
+    }
+
+    /// Returns a copy of the function with the spans of its declaration and
+    /// body reset to the default.
+    pub fn without_spans(&self) -> Self {
+        Self {
+            signature: FunctionSignature {
+                span: Span::default(),
+                ..self.signature.clone()
+            },
+            body: match &self.body {
+                FunctionBody::Closure(closure) => FunctionBody::Closure(closure.clone()),
+                FunctionBody::Expression(expression) => {
+                    FunctionBody::Expression(expression.without_spans())
+                }
+            },
+            control_rate: self.control_rate,
+        }
+    }
+
+    /// Compares two functions structurally, ignoring where they were
+    /// declared. Used to tell whether two imports define the same function.
+    pub fn eq_ignoring_spans(&self, other: &Self) -> bool {
+        self.without_spans() == other.without_spans()
+    }
+
+    /// Calls the function. Closures receive the unevaluated arguments, while
+    /// expression bodies are evaluated with each parameter bound to the value
+    /// of its argument, at the surrounding control points if the function has
+    /// a control rate.
+    pub fn eval(
+        &self,
+        arguments: &[Box<Expression>],
+        context: &mut Context,
+    ) -> Result<f64, MusathError> {
+        match self.body() {
+            FunctionBody::Closure(_) => self.body().eval(arguments, context),
+            FunctionBody::Expression(_) => {
+                let values = arguments
+                    .iter()
+                    .take(self.signature().parameters().len())
+                    .map(|argument_expression| argument_expression.eval(context))
+                    .collect::<Result<Vec<f64>, MusathError>>()?;
+
+                match self.control_rate {
+                    Some(control_rate) if !values.is_empty() => {
+                        let call = (std::ptr::from_ref(self).addr(), arguments.as_ptr().addr());
+                        let mut points = context.take_control_points(call);
+                        let value =
+                            control_rate.interpolate_cached(&mut points, &values, |values| {
+                                self.eval_values(values, arguments, context)
+                            });
+
+                        if let Some(points) = points {
+                            context.set_control_points(call, points);
+                        }
+
+                        value
+                    }
+                    _ => self.eval_values(&values, arguments, context),
+                }
+            }
+        }
+    }
+
+    /// Evaluates an expression body with the parameters bound to `values`.
+    fn eval_values(
+        &self,
+        values: &[f64],
+        arguments: &[Box<Expression>],
+        context: &mut Context,
+    ) -> Result<f64, MusathError> {
+        let parameters = self.signature().parameters();
+
+        for (parameter_identifier, value) in parameters.iter().zip(values) {
+            context.push_value(parameter_identifier, *value);
+        }
+
+        let value = self.body().eval(arguments, context);
+
+        for parameter_identifier in parameters.iter().take(values.len()) {
+            context.pop_value(parameter_identifier);
+        }
+
+        value
+    }
+}
+
+/// Writes the function as a definition, such as `f(t) = t * 2`.
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(control_rate) = self.control_rate {
+            write!(f, "{} ", control_rate)?;
+        }
+
+        write!(
+            f,
+            "{}({}) = {}",
+            self.signature().identifier(),
+            self.signature().parameters().join(", "),
+            self.body()
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+    /// Any one of several exact counts, in increasing order.
+    OneOf(&'static [usize]),
+    /// A number of arguments followed by any number of groups of a fixed
+    /// size.
+    Groups {
+        first: usize,
+        group: usize,
+    },
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match self {
+            Self::Exact(expected) => count == *expected,
+            Self::AtLeast(minimum) => count >= *minimum,
+            Self::OneOf(counts) => counts.contains(&count),
+            Self::Groups { first, group } => {
+                count >= *first && (count - first).is_multiple_of(*group)
+            }
+        }
+    }
+}
+
+impl Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Self::Groups { first, group } = self {
+            let plural = if *first == 1 { "" } else { "s" };
+
+            return write!(
+                f,
+                "{} argument{} followed by groups of {}",
+                first, plural, group
+            );
+        }
+
+        let (prefix, count) = match self {
+            Self::Exact(count) => (String::new(), count),
+            Self::AtLeast(count) => (String::from("at least "), count),
+            Self::OneOf(counts) => match counts.split_last() {
+                Some((count, [])) => (String::new(), count),
+                Some((count, others)) => {
+                    let others = others.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+                    (format!("{} or ", others.join(", ")), count)
+                }
+                None => unreachable!("expected at least one argument count"),
+            },
+            Self::Groups { .. } => unreachable!("expected a single count"),
+        };
+
+        let plural = if *count == 1 { "" } else { "s" };
+
+        write!(f, "{}{} argument{}", prefix, count, plural)
     }
 }
 
@@ -63,34 +287,60 @@ impl Function {
 pub struct FunctionSignature {
     identifier: String,
     parameters: Vec<String>,
+    arity: Arity,
+    span: Span,
 }
 
 impl FunctionSignature {
+    pub fn new(identifier: impl Into<String>, parameters: &[&str], arity: Arity) -> Self {
+        Self {
+            identifier: identifier.into(),
+            parameters: parameters.iter().map(ToString::to_string).collect(),
+            arity,
+            span: Span::default(),
+        }
+    }
+
     pub fn identifier(&self) -> &String {
         &self.identifier
     }
+
     pub fn parameters(&self) -> &Vec<String> {
         &self.parameters
     }
 
+    pub fn arity(&self) -> Arity {
+        self.arity
+    }
+
+    /// The span of the function's identifier in its declaration.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
     pub fn parse(pairs: &mut Pairs<Rule>) -> Self {
-        let identifier = pairs.next().unwrap().as_str().to_string();
+        let identifier_pair = pairs.next().unwrap();
+        let identifier = identifier_pair.as_str().to_string();
+        let span = Span::from(identifier_pair.as_span());
 
         let parameters = pairs
             .map(|pair| match pair.as_rule() {
                 Rule::identifier => pair.as_str().to_string(),
                 _ => unreachable!("expected identifier, found {:?}", pair),
             })
-            .collect();
+            .collect::<Vec<_>>();
 
         Self {
             identifier,
+            arity: Arity::Exact(parameters.len()),
             parameters,
+            span,
         }
     }
 }
 
-pub type FunctionBodyClosure = Arc<dyn Fn(&[Box<Expression>], &Context) -> f64 + Send + Sync>;
+pub type FunctionBodyClosure =
+    Arc<dyn Fn(&[Box<Expression>], &mut Context) -> Result<f64, MusathError> + Send + Sync>;
 
 #[derive(Clone)]
 pub enum FunctionBody {
@@ -107,6 +357,15 @@ impl Debug for FunctionBody {
     }
 }
 
+impl Display for FunctionBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Closure(_) => write!(f, "<built-in>"),
+            Self::Expression(expression) => write!(f, "{}", expression),
+        }
+    }
+}
+
 impl PartialEq for FunctionBody {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -122,7 +381,11 @@ impl PartialEq for FunctionBody {
 }
 
 impl FunctionBody {
-    pub fn eval(&self, arguments: &[Box<Expression>], context: &Context) -> f64 {
+    pub fn eval(
+        &self,
+        arguments: &[Box<Expression>],
+        context: &mut Context,
+    ) -> Result<f64, MusathError> {
         match self {
             Self::Closure(closure) => closure(arguments, context),
             Self::Expression(expression) => expression.eval(context),
@@ -136,6 +399,8 @@ mod tests {
 
     use crate::{
         MusathParser,
+        control_rate::Interpolation,
+        document::Document,
         expression::{BinaryOperator, Primary},
     };
 
@@ -154,6 +419,8 @@ mod tests {
             FunctionSignature {
                 identifier: String::from("test"),
                 parameters: vec![String::from("t")],
+                arity: Arity::Exact(1),
+                span: Span::new(0, 4, 1, 1),
             },
         );
     }
@@ -167,18 +434,111 @@ mod tests {
                     .next()
                     .unwrap()
                     .into_inner()
-            ),
+            )
+            .without_spans(),
             Function {
                 signature: FunctionSignature {
                     identifier: String::from("test"),
                     parameters: vec![String::from("t")],
+                    arity: Arity::Exact(1),
+                    span: Span::default(),
                 },
                 body: FunctionBody::Expression(Expression::Binary(
-                    Box::new(Expression::Primary(Primary::Identifier(String::from("t")))),
+                    Box::new(Expression::Primary(Primary::Identifier(
+                        String::from("t"),
+                        Span::default()
+                    ))),
                     BinaryOperator::Add,
-                    Box::new(Expression::Primary(Primary::Decimal(1.0))),
+                    Box::new(Expression::Primary(Primary::Integer(1))),
                 ),),
+                control_rate: None,
             },
         );
     }
+
+    #[test]
+    fn test_parse_function_semicolon() {
+        let parse = |source| {
+            Function::parse(
+                &mut MusathParser::parse(Rule::function, source)
+                    .unwrap()
+                    .next()
+                    .unwrap()
+                    .into_inner(),
+            )
+        };
+
+        assert!(parse("test(t) = t + 1;").eq_ignoring_spans(&parse("test(t) = t + 1")));
+
+        let document = Document::from_source("f(t) = t * 2;\ng(t) = f(t) + 1\noutput(t) = g(t);");
+
+        assert_eq!(document.unwrap().eval(1.0).unwrap(), 3.0);
+        assert!(Document::from_source("output(t) = t;;").is_err());
+    }
+
+    #[test]
+    fn test_parse_control_rate() {
+        let parse = |source| {
+            Function::parse(
+                &mut MusathParser::parse(Rule::function, source)
+                    .unwrap()
+                    .next()
+                    .unwrap()
+                    .into_inner(),
+            )
+        };
+
+        let function = parse("@rate( 200, cubic ) env(t, a) = t * a");
+
+        assert_eq!(
+            function.control_rate(),
+            Some(ControlRate::new(200.0, Interpolation::Cubic))
+        );
+        assert_eq!(function.to_string(), "@rate(200, cubic) env(t, a) = t * a");
+        assert_eq!(
+            parse("@rate(0.5)\nlfo(t) = t").control_rate(),
+            Some(ControlRate::new(0.5, Interpolation::Linear))
+        );
+        assert!(MusathParser::parse(Rule::function, "@rate(fast) lfo(t) = t").is_err());
+    }
+
+    #[test]
+    fn test_parse_function_comments() {
+        assert!(
+            Function::parse(
+                &mut MusathParser::parse(
+                    Rule::function,
+                    "test(t /* time */) = t // the time\n\
+                     # add one\n\
+                     + /* one */ 1",
+                )
+                .unwrap()
+                .next()
+                .unwrap()
+                .into_inner()
+            )
+            .eq_ignoring_spans(&Function::parse(
+                &mut MusathParser::parse(Rule::function, "test(t) = t + 1")
+                    .unwrap()
+                    .next()
+                    .unwrap()
+                    .into_inner()
+            ))
+        );
+
+        assert_eq!(
+            FunctionSignature::parse(
+                &mut MusathParser::parse(
+                    Rule::function_signature,
+                    "test(a, // first\n b /* second */)"
+                )
+                .unwrap()
+                .next()
+                .unwrap()
+                .into_inner()
+            )
+            .parameters(),
+            &vec![String::from("a"), String::from("b")],
+        );
+    }
 }
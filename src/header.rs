@@ -1,60 +1,154 @@
 use std::collections::HashMap;
 
+use hound::SampleFormat;
 use pest::iterators::Pairs;
 
-use crate::Rule;
+use crate::{
+    Rule,
+    error::{ErrorKind, MusathError, Span},
+    expression::LanguageVersion,
+};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Header {
     key_values: HashMap<String, HeaderValue>,
+    spans: HashMap<String, Span>,
 }
 
 impl Header {
     pub fn parse(pairs: &mut Pairs<Rule>) -> Self {
         let mut key_values = HashMap::new();
+        let mut spans = HashMap::new();
 
         for pair in pairs {
             match pair.as_rule() {
                 Rule::header_declaration => {
                     let header_declaration = HeaderDeclaration::parse(&mut pair.into_inner());
 
+                    spans.insert(header_declaration.key.clone(), header_declaration.span);
                     key_values.insert(header_declaration.key, header_declaration.value);
                 }
                 rule => unreachable!("expected header_declaration, found {:?}", rule),
             };
         }
 
-        Self { key_values }
+        Self { key_values, spans }
     }
 
     pub fn key_values(&self) -> &HashMap<String, HeaderValue> {
         &self.key_values
     }
 
-    pub fn title(&self) -> Option<&str> {
-        self
-            .key_values()
-            .get("TITLE")
-            .map(|header_value| {
-                let HeaderValue::String(title) = header_value else {
-                    panic!("expected TITLE to be a string, found {:?}", header_value);
-                };
+    /// The span of the value assigned to `key`, if it was declared.
+    pub fn span(&self, key: impl AsRef<str>) -> Option<Span> {
+        self.spans.get(key.as_ref()).copied()
+    }
+
+    pub fn title(&self) -> Result<Option<&str>, MusathError> {
+        self.string("TITLE")
+    }
+
+    pub fn duration(&self) -> Result<Option<f64>, MusathError> {
+        self.number("DURATION")
+    }
+
+    pub fn channels(&self) -> Result<Option<u16>, MusathError> {
+        self.positive_integer("CHANNELS")
+    }
 
-                title.as_str()
+    /// The sample rate in Hz.
+    pub fn sample_rate(&self) -> Result<Option<u32>, MusathError> {
+        self.positive_integer("SAMPLE_RATE")
+    }
+
+    /// The frequency of `A4` in Hz, which note names are tuned relative to.
+    pub fn a4_hz(&self) -> Result<Option<f64>, MusathError> {
+        self.number("A4_HZ")?
+            .map(|a4_hz| {
+                Some(a4_hz)
+                    .filter(|a4_hz| *a4_hz > 0.0)
+                    .ok_or_else(|| self.invalid_value("A4_HZ", "positive number"))
             })
+            .transpose()
+    }
+
+    /// The path of the Scala `.scl` file of the scale that `degree` plays.
+    pub fn tuning(&self) -> Result<Option<&str>, MusathError> {
+        self.string("TUNING")
+    }
+
+    /// The path of the Scala `.kbm` file that tunes the scale.
+    pub fn keyboard_map(&self) -> Result<Option<&str>, MusathError> {
+        self.string("KEYBOARD_MAP")
     }
 
-    pub fn duration(&self) -> Option<f64> {
-        self
-            .key_values()
-            .get("DURATION")
-            .map(|header_value| {
-                let HeaderValue::Number(duration_seconds) = header_value else {
-                    panic!("expected DURATION to be a number, found {:?}", header_value);
-                };
+    /// The number of bits per sample: 16, 24 or 32.
+    pub fn bit_depth(&self) -> Result<Option<u16>, MusathError> {
+        match self.positive_integer("BIT_DEPTH") {
+            Ok(Some(bit_depth @ (16 | 24 | 32))) => Ok(Some(bit_depth)),
+            Ok(None) => Ok(None),
+            _ => Err(self.invalid_value("BIT_DEPTH", "bit depth of 16, 24 or 32")),
+        }
+    }
 
-                *duration_seconds
+    /// Whether samples are stored as `"int"` or `"float"`.
+    pub fn sample_format(&self) -> Result<Option<SampleFormat>, MusathError> {
+        match self.string("SAMPLE_FORMAT") {
+            Ok(Some("int")) => Ok(Some(SampleFormat::Int)),
+            Ok(Some("float")) => Ok(Some(SampleFormat::Float)),
+            Ok(None) => Ok(None),
+            _ => Err(self.invalid_value("SAMPLE_FORMAT", "sample format of \"int\" or \"float\"")),
+        }
+    }
+
+    /// The explicitly requested language version, if any.
+    pub fn language_version(&self) -> Result<Option<LanguageVersion>, MusathError> {
+        self.number("LANGUAGE_VERSION")?
+            .map(|number| {
+                LanguageVersion::from_number(number).ok_or_else(|| {
+                    self.invalid_value("LANGUAGE_VERSION", "supported language version (1 or 2)")
+                })
             })
+            .transpose()
+    }
+
+    fn string(&self, key: &str) -> Result<Option<&str>, MusathError> {
+        match self.key_values().get(key) {
+            Some(HeaderValue::String(value)) => Ok(Some(value.as_str())),
+            Some(_) => Err(self.invalid_value(key, "string")),
+            None => Ok(None),
+        }
+    }
+
+    fn number(&self, key: &str) -> Result<Option<f64>, MusathError> {
+        match self.key_values().get(key) {
+            Some(HeaderValue::Number(value)) => Ok(Some(*value)),
+            Some(_) => Err(self.invalid_value(key, "number")),
+            None => Ok(None),
+        }
+    }
+
+    fn positive_integer<T: TryFrom<u64>>(&self, key: &str) -> Result<Option<T>, MusathError> {
+        self.number(key)?
+            .map(|number| {
+                Some(number)
+                    .filter(|number| number.fract() == 0.0 && *number >= 1.0)
+                    .and_then(|number| T::try_from(number as u64).ok())
+                    .ok_or_else(|| self.invalid_value(key, "positive whole number"))
+            })
+            .transpose()
+    }
+
+    fn invalid_value(&self, key: &str, expected: &'static str) -> MusathError {
+        let kind = ErrorKind::InvalidHeaderValue {
+            key: key.to_string(),
+            expected,
+        };
+
+        match self.span(key) {
+            Some(span) => MusathError::with_span(kind, span),
+            None => MusathError::new(kind),
+        }
     }
 }
 
@@ -62,12 +156,14 @@ impl Header {
 pub struct HeaderDeclaration {
     key: String,
     value: HeaderValue,
+    span: Span,
 }
 
 impl HeaderDeclaration {
     pub fn parse(pairs: &mut Pairs<Rule>) -> Self {
         let mut key = None;
         let mut value = None;
+        let mut span = None;
 
         for pair in pairs {
             match pair.as_rule() {
@@ -75,6 +171,7 @@ impl HeaderDeclaration {
                     key = Some(pair.as_str().to_string());
                 }
                 Rule::header_value => {
+                    span = Some(Span::from(pair.as_span()));
                     value = Some(HeaderValue::parse(&mut pair.into_inner()));
                 }
                 rule => unreachable!("expected header_key or header_value, found {:?}", rule),
@@ -84,6 +181,7 @@ impl HeaderDeclaration {
         Self {
             key: key.unwrap(),
             value: value.unwrap(),
+            span: span.unwrap(),
         }
     }
 }
@@ -110,7 +208,7 @@ impl HeaderValue {
 mod tests {
     use pest::Parser;
 
-    use crate::MusathParser;
+    use crate::{MusathParser, document::Document};
 
     use super::*;
 
@@ -139,6 +237,80 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_header_comments() {
+        let document = Document::from_source(
+            "# a comment\n\
+             TITLE = \"a # b // c\" // trailing comment\n\
+             /* block\n\
+                comment */ DURATION /* inline */ = 2.5\n\
+             output(t) = t",
+        )
+        .unwrap();
+        let header = document.header();
+
+        assert_eq!(header.key_values().len(), 2);
+        assert_eq!(header.title().unwrap(), Some("a # b // c"));
+        assert_eq!(header.duration().unwrap(), Some(2.5));
+    }
+
+    #[test]
+    fn test_header_invalid_value() {
+        let header = Header::parse(
+            &mut MusathParser::parse(Rule::header, "TITLE = 1\nDURATION = \"long\"")
+                .unwrap()
+                .next()
+                .unwrap()
+                .into_inner(),
+        );
+
+        let error = header.title().unwrap_err();
+
+        assert!(matches!(error.kind(), ErrorKind::InvalidHeaderValue { .. }));
+        assert_eq!(error.span().unwrap().line(), 1);
+        assert_eq!(error.span().unwrap().column(), 9);
+
+        let error = header.duration().unwrap_err();
+
+        assert_eq!(error.span().unwrap().line(), 2);
+        assert_eq!(error.span().unwrap().column(), 12);
+    }
+
+    #[test]
+    fn test_header_audio_format() {
+        let header = Header::parse(
+            &mut MusathParser::parse(
+                Rule::header,
+                "CHANNELS = 2\nSAMPLE_RATE = 48000\nBIT_DEPTH = 24\nSAMPLE_FORMAT = \"int\"",
+            )
+            .unwrap()
+            .next()
+            .unwrap()
+            .into_inner(),
+        );
+
+        assert_eq!(header.channels().unwrap(), Some(2));
+        assert_eq!(header.sample_rate().unwrap(), Some(48000));
+        assert_eq!(header.bit_depth().unwrap(), Some(24));
+        assert_eq!(header.sample_format().unwrap(), Some(SampleFormat::Int));
+
+        let header = Header::parse(
+            &mut MusathParser::parse(
+                Rule::header,
+                "SAMPLE_RATE = 44.1\nBIT_DEPTH = 8\nSAMPLE_FORMAT = \"double\"",
+            )
+            .unwrap()
+            .next()
+            .unwrap()
+            .into_inner(),
+        );
+
+        assert!(header.sample_rate().is_err());
+        assert!(header.bit_depth().is_err());
+        assert!(header.sample_format().is_err());
+        assert_eq!(header.channels().unwrap(), None);
+    }
+
     #[test]
     fn test_parse_header_declaration() {
         assert_eq!(
@@ -152,6 +324,7 @@ mod tests {
             HeaderDeclaration {
                 key: String::from("TEST"),
                 value: HeaderValue::Number(1.0),
+                span: Span::new(7, 10, 1, 8),
             },
         );
     }
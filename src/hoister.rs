@@ -0,0 +1,543 @@
+//! Hoisting of time-invariant subexpressions out of the per-sample loop.
+//!
+//! Every parameter is assumed to vary with time, since any function may be
+//! called with `t`. A subexpression that depends on no parameter, nor on a
+//! `let` binding computed from one, has the same value at every sample, so it
+//! is evaluated once before rendering and replaced by its value. This catches
+//! what constant folding cannot, such as `sum(n, 1, 5, n^2)`, calls to user
+//! functions with constant arguments and bindings that ignore the parameters.
+//!
+//! A `sum` or `prod` with constant bounds whose terms depend on time is
+//! unrolled when small enough, binding the loop variable to each of its
+//! values in turn, so the parts of the terms that only depend on the loop
+//! variable, such as `4^(n-1)`, are hoisted as well. The terms are combined
+//! in the same order as the built-in, so the result is unchanged.
+//!
+//! Calls to recursive functions are never hoisted, as they might not
+//! terminate for arguments they are not actually called with.
+
+use std::collections::HashSet;
+
+use crate::{
+    context::Context,
+    error::Span,
+    expression::{BinaryOperator, Expression, Primary},
+    function::{Function, FunctionBody},
+    inliner::{recursive, size},
+    validation::BINDING_BUILTINS,
+};
+
+/// The largest unrolled `sum` or `prod`, in nodes, produced by
+/// [`Body::optimize`](crate::body::Body::optimize).
+pub const DEFAULT_THRESHOLD: usize = 256;
+
+/// Replaces the time-invariant subexpressions of every user function in
+/// `context` by their values, unrolling loops of at most `threshold` nodes.
+/// Returns the number of nodes no longer evaluated at every sample.
+pub fn hoist(context: &mut Context, threshold: usize) -> usize {
+    let mut hoister = Hoister {
+        context: &*context,
+        threshold,
+        recursive: recursive(context),
+        hoisted: 0,
+    };
+
+    let functions = context
+        .functions()
+        .values()
+        .filter_map(|function| match function.body() {
+            FunctionBody::Expression(expression) => {
+                let mut scope = function
+                    .signature()
+                    .parameters()
+                    .iter()
+                    .map(|parameter| (parameter.clone(), Binding::Computed(Dependence::Time)))
+                    .collect();
+
+                let (expression, dependence) = hoister.expression(expression, &mut scope);
+
+                Some(function.renamed(
+                    function.signature().identifier(),
+                    hoister.settle(expression, &dependence),
+                ))
+            }
+            FunctionBody::Closure(_) => None,
+        })
+        .collect::<Vec<Function>>();
+
+    let hoisted = hoister.hoisted;
+
+    for function in functions {
+        context.set_function(function);
+    }
+
+    hoisted
+}
+
+/// What the value of an expression depends on.
+#[derive(Debug, Clone, PartialEq)]
+enum Dependence {
+    /// Time, through a parameter.
+    Time,
+    /// Only the listed loop variables of enclosing `sum`s and `prod`s, or
+    /// nothing at all if there are none.
+    Loops(Vec<String>),
+}
+
+impl Dependence {
+    const NONE: Self = Self::Loops(Vec::new());
+
+    /// The dependence of an expression made of parts with `self` and `other`.
+    fn and(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::Loops(mut variables), Self::Loops(others)) => {
+                for variable in others {
+                    if !variables.contains(&variable) {
+                        variables.push(variable);
+                    }
+                }
+
+                Self::Loops(variables)
+            }
+            _ => Self::Time,
+        }
+    }
+
+    /// The dependence outside the loop over `variable`.
+    fn without(self, variable: &str) -> Self {
+        match self {
+            Self::Loops(mut variables) => {
+                variables.retain(|other| other != variable);
+
+                Self::Loops(variables)
+            }
+            Self::Time => Self::Time,
+        }
+    }
+
+    fn is_invariant(&self) -> bool {
+        *self == Self::NONE
+    }
+}
+
+/// What an identifier in scope is bound to.
+#[derive(Debug)]
+enum Binding {
+    /// A value known before rendering, substituted for the identifier.
+    Value(f64),
+    /// A value computed while rendering.
+    Computed(Dependence),
+}
+
+struct Hoister<'a> {
+    context: &'a Context,
+    threshold: usize,
+    recursive: HashSet<String>,
+    hoisted: usize,
+}
+
+impl Hoister<'_> {
+    /// Hoists the time-invariant parts of `expression`, returning what it
+    /// depends on. If it is invariant as a whole, it is left to the caller to
+    /// [`settle`](Self::settle), so only the largest invariant parts are
+    /// evaluated.
+    fn expression(
+        &mut self,
+        expression: &Expression,
+        scope: &mut Vec<(String, Binding)>,
+    ) -> (Expression, Dependence) {
+        match expression {
+            Expression::Binary(left, operator, right) => {
+                let left = self.expression(left, scope);
+                let right = self.expression(right, scope);
+
+                let ([left, right], dependence) = self.combine([left, right], Dependence::NONE);
+
+                (
+                    Expression::Binary(Box::new(left), operator.clone(), Box::new(right)),
+                    dependence,
+                )
+            }
+            Expression::Unary(operator, operand) => {
+                let operand = self.expression(operand, scope);
+
+                let ([operand], dependence) = self.combine([operand], Dependence::NONE);
+
+                (
+                    Expression::Unary(operator.clone(), Box::new(operand)),
+                    dependence,
+                )
+            }
+            Expression::Primary(primary) => self.primary(primary, scope),
+            Expression::Conditional(condition, consequent, alternative) => {
+                let condition = self.expression(condition, scope);
+                let consequent = self.expression(consequent, scope);
+                let alternative = self.expression(alternative, scope);
+
+                let ([condition, consequent, alternative], dependence) =
+                    self.combine([condition, consequent, alternative], Dependence::NONE);
+
+                (
+                    Expression::Conditional(
+                        Box::new(condition),
+                        Box::new(consequent),
+                        Box::new(alternative),
+                    ),
+                    dependence,
+                )
+            }
+            Expression::Let(bindings, body) => {
+                let length = scope.len();
+
+                let mut computed = Vec::new();
+                let mut dependence = Dependence::NONE;
+
+                for (identifier, expression) in bindings {
+                    let (expression, binding_dependence) = self.expression(expression, scope);
+
+                    match self.settle(expression, &binding_dependence) {
+                        Expression::Primary(Primary::Decimal(value)) => {
+                            scope.push((identifier.clone(), Binding::Value(value)));
+                        }
+                        expression => {
+                            // An invariant binding that failed to evaluate is
+                            // computed while rendering, like any other.
+                            let binding_dependence = match binding_dependence {
+                                Dependence::Loops(variables) if variables.is_empty() => {
+                                    Dependence::Time
+                                }
+                                binding_dependence => binding_dependence,
+                            };
+
+                            scope.push((
+                                identifier.clone(),
+                                Binding::Computed(binding_dependence.clone()),
+                            ));
+                            computed.push((identifier.clone(), expression));
+                            dependence = dependence.and(binding_dependence);
+                        }
+                    }
+                }
+
+                let (body, body_dependence) = self.expression(body, scope);
+
+                scope.truncate(length);
+
+                let dependence = dependence.and(body_dependence.clone());
+                let body = self.part((body, body_dependence), &dependence);
+
+                if computed.is_empty() {
+                    (body, dependence)
+                } else {
+                    (Expression::Let(computed, Box::new(body)), dependence)
+                }
+            }
+        }
+    }
+
+    fn primary(
+        &mut self,
+        primary: &Primary,
+        scope: &mut Vec<(String, Binding)>,
+    ) -> (Expression, Dependence) {
+        match primary {
+            Primary::Decimal(_) | Primary::Integer(_) | Primary::Note(_) => {
+                (Expression::Primary(primary.clone()), Dependence::NONE)
+            }
+            Primary::Grouping(expression) => self.expression(expression, scope),
+            Primary::Identifier(identifier, _) => {
+                let binding = scope
+                    .iter()
+                    .rev()
+                    .find(|(other, _)| other == identifier)
+                    .map(|(_, binding)| binding);
+
+                match binding {
+                    Some(Binding::Value(value)) => (decimal(*value), Dependence::NONE),
+                    Some(Binding::Computed(dependence)) => {
+                        (Expression::Primary(primary.clone()), dependence.clone())
+                    }
+                    None if self.context.value(identifier).is_some() => {
+                        (Expression::Primary(primary.clone()), Dependence::NONE)
+                    }
+                    None => (Expression::Primary(primary.clone()), Dependence::Time),
+                }
+            }
+            Primary::Call(identifier, arguments, span) => {
+                let function = self.context.function(identifier);
+
+                if let Some(function) = function
+                    && matches!(function.body(), FunctionBody::Closure(_))
+                    && BINDING_BUILTINS.contains(&identifier.as_str())
+                    && arguments.len() == 4
+                    && let Expression::Primary(Primary::Identifier(variable, _)) =
+                        arguments[0].as_ref()
+                {
+                    return self.binding_call(identifier, variable, arguments, *span, scope);
+                }
+
+                // Calls that might not terminate are left alone.
+                let dependence = match function {
+                    Some(_) if !self.recursive.contains(identifier) => Dependence::NONE,
+                    _ => Dependence::Time,
+                };
+
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| self.expression(argument, scope))
+                    .collect::<Vec<_>>();
+
+                let dependence = arguments
+                    .iter()
+                    .fold(dependence, |dependence, (_, argument)| {
+                        dependence.and(argument.clone())
+                    });
+
+                let arguments = arguments
+                    .into_iter()
+                    .map(|argument| Box::new(self.part(argument, &dependence)))
+                    .collect();
+
+                (
+                    Expression::Primary(Primary::Call(identifier.clone(), arguments, *span)),
+                    dependence,
+                )
+            }
+        }
+    }
+
+    /// Hoists the time-invariant parts of a `sum` or `prod`, unrolling it if
+    /// its terms depend on time but its bounds do not.
+    fn binding_call(
+        &mut self,
+        identifier: &str,
+        variable: &str,
+        arguments: &[Box<Expression>],
+        span: Span,
+        scope: &mut Vec<(String, Binding)>,
+    ) -> (Expression, Dependence) {
+        let body = &arguments[3];
+
+        let (start, start_dependence) = self.expression(&arguments[1], scope);
+        let (end, end_dependence) = self.expression(&arguments[2], scope);
+
+        let hoisted = self.hoisted;
+
+        scope.push((
+            variable.to_string(),
+            Binding::Computed(Dependence::Loops(vec![variable.to_string()])),
+        ));
+
+        let (looped_body, body_dependence) = self.expression(body, scope);
+
+        scope.pop();
+
+        let dependence = start_dependence
+            .clone()
+            .and(end_dependence.clone())
+            .and(body_dependence.clone().without(variable));
+
+        if dependence == Dependence::Time
+            && start_dependence.is_invariant()
+            && end_dependence.is_invariant()
+            && let (Ok(first), Ok(last)) = (
+                start.eval(&mut self.context.clone()),
+                end.eval(&mut self.context.clone()),
+            )
+        {
+            let values = first.round() as isize..last.round() as isize;
+
+            if values.len().saturating_mul(size(body)) <= self.threshold {
+                // The terms are hoisted again once the variable is bound.
+                self.hoisted = hoisted + hoisted_size(&start) + hoisted_size(&end);
+
+                return (
+                    self.unroll(identifier, variable, values, body, scope),
+                    dependence,
+                );
+            }
+        }
+
+        let start = self.part((start, start_dependence), &dependence);
+        let end = self.part((end, end_dependence), &dependence);
+        let body = self.part((looped_body, body_dependence), &dependence);
+
+        (
+            Expression::Primary(Primary::Call(
+                identifier.to_string(),
+                vec![
+                    arguments[0].clone(),
+                    Box::new(start),
+                    Box::new(end),
+                    Box::new(body),
+                ],
+                span,
+            )),
+            dependence,
+        )
+    }
+
+    /// The terms of a `sum` or `prod` over `values`, combined in order.
+    fn unroll(
+        &mut self,
+        identifier: &str,
+        variable: &str,
+        values: std::ops::Range<isize>,
+        body: &Expression,
+        scope: &mut Vec<(String, Binding)>,
+    ) -> Expression {
+        let (operator, empty) = match identifier {
+            "prod" => (
+                BinaryOperator::Multiply,
+                std::iter::empty::<f64>().product(),
+            ),
+            _ => (BinaryOperator::Add, std::iter::empty::<f64>().sum()),
+        };
+
+        values
+            .map(|value| {
+                let term = Expression::Let(
+                    vec![(variable.to_string(), decimal(value as f64))],
+                    Box::new(body.clone()),
+                );
+
+                let (term, dependence) = self.expression(&term, scope);
+
+                self.settle(term, &dependence)
+            })
+            .reduce(|total, term| {
+                Expression::Binary(Box::new(total), operator.clone(), Box::new(term))
+            })
+            .unwrap_or(decimal(empty))
+    }
+
+    /// Combines the parts of an expression that, by itself, has
+    /// `dependence`. See [`part`](Self::part).
+    fn combine<const N: usize>(
+        &mut self,
+        parts: [(Expression, Dependence); N],
+        dependence: Dependence,
+    ) -> ([Expression; N], Dependence) {
+        let dependence = parts.iter().fold(dependence, |dependence, (_, part)| {
+            dependence.and(part.clone())
+        });
+
+        (parts.map(|part| self.part(part, &dependence)), dependence)
+    }
+
+    /// A part of an expression with dependence `whole`, settled unless the
+    /// whole expression is invariant and so will be settled itself.
+    fn part(
+        &mut self,
+        (part, dependence): (Expression, Dependence),
+        whole: &Dependence,
+    ) -> Expression {
+        if whole.is_invariant() {
+            part
+        } else {
+            self.settle(part, &dependence)
+        }
+    }
+
+    /// Replaces `expression` by its value if it is invariant and evaluates
+    /// successfully.
+    fn settle(&mut self, expression: Expression, dependence: &Dependence) -> Expression {
+        if !dependence.is_invariant() || hoisted_size(&expression) == 0 {
+            return expression;
+        }
+
+        match expression.eval(&mut self.context.clone()) {
+            Ok(value) => {
+                self.hoisted += size(&expression);
+
+                decimal(value)
+            }
+            Err(_) => expression,
+        }
+    }
+}
+
+/// The number of nodes hoisted by replacing `expression` by its value.
+fn hoisted_size(expression: &Expression) -> usize {
+    match expression {
+        Expression::Primary(Primary::Decimal(_) | Primary::Integer(_) | Primary::Note(_)) => 0,
+        expression => size(expression),
+    }
+}
+
+fn decimal(value: f64) -> Expression {
+    Expression::Primary(Primary::Decimal(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::document::Document;
+
+    use super::*;
+
+    #[test]
+    fn test_hoist() {
+        let document = Document::from_source(
+            "
+            const base = 220
+            loop(n) = if n <= 0 then 0 else n + loop(n - 1)
+            double(x) = x * 2
+            bindings(t) = let a = double(3), b = a * t in b + loop(2)
+            output(t) = sin(t * double(base)) + sum(n, 1, 5, n^2) + sum(n, 1, 3, t / 4^(n - 1))
+            ",
+        )
+        .unwrap();
+
+        let mut context = document.body().context().clone();
+
+        assert_eq!(hoist(&mut context, DEFAULT_THRESHOLD), 21);
+
+        let function = |identifier: &str| context.function(identifier).unwrap().to_string();
+
+        assert_eq!(function("double"), "double(x) = x * 2");
+        assert_eq!(
+            function("bindings"),
+            "bindings(t) = let b = 6 * t in b + loop(2)"
+        );
+        assert_eq!(
+            function("output"),
+            "output(t) = (sin(t * 440) + 30) + ((t / 1) + (t / 4))"
+        );
+
+        for t in [-1.5, 0.0, 0.25, 3.0] {
+            for identifier in ["bindings", "output"] {
+                let call = Expression::Primary(Primary::Call(
+                    identifier.to_string(),
+                    vec![Box::new(decimal(t))],
+                    Span::default(),
+                ));
+
+                assert_eq!(
+                    call.eval(&mut context.clone()).unwrap().to_bits(),
+                    call.eval(&mut document.body().context().clone())
+                        .unwrap()
+                        .to_bits()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_hoist_threshold() {
+        let document = Document::from_source(
+            "
+            output(t) = sum(n, 0, 100, t * n) + prod(n, 0, t, 2^n) + sum(n, 2, 1, t)
+            ",
+        )
+        .unwrap();
+
+        let mut context = document.body().context().clone();
+
+        hoist(&mut context, DEFAULT_THRESHOLD);
+
+        assert_eq!(
+            context.function("output").unwrap().to_string(),
+            "output(t) = (sum(n, 0, 100, t * n) + prod(n, 0, t, 2 ^ n)) + (-0)"
+        );
+    }
+}
@@ -0,0 +1,538 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use pest::iterators::Pairs;
+
+use crate::{
+    Rule,
+    context::Context,
+    document::Document,
+    error::{ErrorKind, MusathError, SourceFile, Span},
+    expression::{Expression, Primary},
+    function::{Function, FunctionBody},
+    validation::{self, BINDING_BUILTINS},
+};
+
+/// An `import "path" [as alias]` statement.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Import {
+    path: String,
+    alias: Option<String>,
+    span: Span,
+}
+
+impl Import {
+    pub fn parse(pairs: &mut Pairs<Rule>) -> Self {
+        let path_pair = pairs.next().unwrap();
+        let alias = pairs.next().map(|pair| match pair.as_rule() {
+            Rule::identifier => pair.as_str().to_string(),
+            _ => unreachable!("expected identifier, found {:?}", pair),
+        });
+
+        Self {
+            path: path_pair.as_str().to_string(),
+            alias,
+            span: Span::from(path_pair.as_span()),
+        }
+    }
+
+    pub fn path(&self) -> &String {
+        &self.path
+    }
+
+    pub fn alias(&self) -> Option<&String> {
+        self.alias.as_ref()
+    }
+
+    /// The span of the imported path.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// Loads imported files, resolving their paths relative to the importing file
+/// and tracking the files currently being loaded to detect import cycles.
+///
+/// Imported files are evaluated with the settings of the file that imports
+/// them, and their warnings are collected for the importing document.
+///
+/// An importer without a file resolves paths relative to the working
+/// directory.
+#[derive(Debug, Default)]
+pub struct Importer {
+    stack: Vec<PathBuf>,
+    settings: Option<Context>,
+    warnings: Vec<MusathError>,
+}
+
+impl Importer {
+    /// An importer for the file at `path`.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+
+        Self {
+            stack: vec![path.canonicalize().unwrap_or_else(|_| path.to_path_buf())],
+            ..Self::default()
+        }
+    }
+
+    /// Loads, parses and validates the file named by `import`, returning the
+    /// functions and constants it defines, qualified with the import's alias
+    /// if it has one. Its constants are evaluated with the sample rate,
+    /// reference pitch and tuning of `settings`, the importing file's context.
+    pub fn load(&mut self, import: &Import, settings: &Context) -> Result<Module, MusathError> {
+        let failed = |errors: Vec<MusathError>| {
+            MusathError::with_span(
+                ErrorKind::ImportFailed {
+                    path: import.path().clone(),
+                    errors,
+                },
+                import.span(),
+            )
+        };
+
+        let path = self
+            .resolve(import.path())
+            .canonicalize()
+            .map_err(|error| failed(vec![error.into()]))?;
+
+        if let Some(position) = self.stack.iter().position(|entry| *entry == path) {
+            let mut cycle = self.stack[position..]
+                .iter()
+                .map(|entry| file_name(entry))
+                .collect::<Vec<_>>();
+            cycle.push(file_name(&path));
+
+            return Err(MusathError::with_span(
+                ErrorKind::ImportCycle(cycle),
+                import.span(),
+            ));
+        }
+
+        let source = std::fs::read_to_string(&path).map_err(|error| failed(vec![error.into()]))?;
+        let file = Arc::new(SourceFile::new(&path, source));
+
+        self.stack.push(path);
+        let outer_settings = self.settings.replace(settings.clone());
+        let document = Document::from_source_with(file.text(), self);
+        self.settings = outer_settings;
+        self.stack.pop();
+
+        let document = document.map_err(|error| failed(vec![error.in_file(&file)]))?;
+
+        let context = document.body().context();
+
+        let errors = validation::validate_functions(context);
+        if !errors.is_empty() {
+            return Err(failed(
+                errors
+                    .into_iter()
+                    .map(|error| error.in_file(&file))
+                    .collect(),
+            ));
+        }
+
+        let module = Module::new(context, import.alias().map(String::as_str));
+
+        self.warnings.extend(
+            document
+                .into_warnings()
+                .into_iter()
+                .map(|warning| warning.in_file(&file)),
+        );
+
+        Ok(module)
+    }
+
+    /// The settings imported files are evaluated with, if a file is being
+    /// imported.
+    pub fn settings(&self) -> Option<&Context> {
+        self.settings.as_ref()
+    }
+
+    /// Takes the warnings of the files loaded so far.
+    pub fn take_warnings(&mut self) -> Vec<MusathError> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// `path` relative to the directory of the file being loaded.
+    pub fn resolve(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.stack
+            .last()
+            .and_then(|file| file.parent())
+            .unwrap_or(Path::new(""))
+            .join(path)
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .to_string()
+}
+
+/// The user-defined functions and constants of an imported file.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Module {
+    functions: Vec<Function>,
+    values: Vec<(String, f64)>,
+}
+
+impl Module {
+    fn new(context: &Context, alias: Option<&str>) -> Self {
+        let builtins = Context::default();
+
+        let functions = context
+            .functions()
+            .values()
+            .filter(|function| matches!(function.body(), FunctionBody::Expression(_)))
+            .collect::<Vec<_>>();
+
+        let values = context
+            .values()
+            .keys()
+            .filter(|identifier| builtins.value(identifier).is_none())
+            .map(|identifier| (identifier, *context.value(identifier).unwrap()))
+            .collect::<Vec<_>>();
+
+        let Some(alias) = alias else {
+            return Self {
+                functions: functions.into_iter().cloned().collect(),
+                values: values
+                    .into_iter()
+                    .map(|(identifier, value)| (identifier.clone(), value))
+                    .collect(),
+            };
+        };
+
+        let qualifier = Qualifier {
+            alias,
+            functions: functions
+                .iter()
+                .map(|function| function.signature().identifier().as_str())
+                .collect(),
+            values: values
+                .iter()
+                .map(|(identifier, _)| identifier.as_str())
+                .collect(),
+        };
+
+        Self {
+            functions: functions
+                .into_iter()
+                .map(|function| {
+                    let FunctionBody::Expression(expression) = function.body() else {
+                        unreachable!("expected expression body, found {:?}", function.body());
+                    };
+
+                    let mut scope = function.signature().parameters().clone();
+
+                    function.renamed(
+                        qualifier.qualify(function.signature().identifier()),
+                        qualifier.expression(expression, &mut scope),
+                    )
+                })
+                .collect(),
+            values: values
+                .into_iter()
+                .map(|(identifier, value)| (qualifier.qualify(identifier), value))
+                .collect(),
+        }
+    }
+
+    pub fn functions(&self) -> &[Function] {
+        &self.functions
+    }
+
+    pub fn values(&self) -> &[(String, f64)] {
+        &self.values
+    }
+}
+
+/// Rewrites an imported file's references to its own functions and constants
+/// to their `alias.`-qualified names.
+struct Qualifier<'a> {
+    alias: &'a str,
+    functions: HashSet<&'a str>,
+    values: HashSet<&'a str>,
+}
+
+impl Qualifier<'_> {
+    fn qualify(&self, identifier: &str) -> String {
+        format!("{}.{}", self.alias, identifier)
+    }
+
+    fn expression(&self, expression: &Expression, scope: &mut Vec<String>) -> Expression {
+        match expression {
+            Expression::Primary(primary) => Expression::Primary(self.primary(primary, scope)),
+            Expression::Unary(operator, operand) => {
+                Expression::Unary(operator.clone(), Box::new(self.expression(operand, scope)))
+            }
+            Expression::Binary(left, operator, right) => Expression::Binary(
+                Box::new(self.expression(left, scope)),
+                operator.clone(),
+                Box::new(self.expression(right, scope)),
+            ),
+            Expression::Conditional(condition, consequent, alternative) => Expression::Conditional(
+                Box::new(self.expression(condition, scope)),
+                Box::new(self.expression(consequent, scope)),
+                Box::new(self.expression(alternative, scope)),
+            ),
+            Expression::Let(bindings, body) => {
+                let bindings = bindings
+                    .iter()
+                    .map(|(identifier, expression)| {
+                        let expression = self.expression(expression, scope);
+                        scope.push(identifier.clone());
+                        (identifier.clone(), expression)
+                    })
+                    .collect::<Vec<_>>();
+
+                let body = self.expression(body, scope);
+                scope.truncate(scope.len() - bindings.len());
+
+                Expression::Let(bindings, Box::new(body))
+            }
+        }
+    }
+
+    fn primary(&self, primary: &Primary, scope: &mut Vec<String>) -> Primary {
+        match primary {
+            Primary::Decimal(_) | Primary::Integer(_) | Primary::Note(_) => primary.clone(),
+            Primary::Identifier(identifier, span) => {
+                if self.values.contains(identifier.as_str()) && !scope.contains(identifier) {
+                    Primary::Identifier(self.qualify(identifier), *span)
+                } else {
+                    primary.clone()
+                }
+            }
+            Primary::Grouping(expression) => {
+                Primary::Grouping(Box::new(self.expression(expression, scope)))
+            }
+            Primary::Call(identifier, arguments, span) => {
+                let variable = match arguments.first().map(AsRef::as_ref) {
+                    Some(Expression::Primary(Primary::Identifier(variable, _)))
+                        if BINDING_BUILTINS.contains(&identifier.as_str()) =>
+                    {
+                        Some(variable)
+                    }
+                    _ => None,
+                };
+
+                let last = arguments.len().saturating_sub(1);
+
+                let arguments = arguments
+                    .iter()
+                    .enumerate()
+                    .map(|(index, argument)| match variable {
+                        // The loop variable is bound within the loop body,
+                        // where it shadows any constant of the same name.
+                        Some(_) if index == 0 => argument.clone(),
+                        Some(variable) if index == last => {
+                            scope.push(variable.clone());
+                            let argument = self.expression(argument, scope);
+                            scope.pop();
+                            Box::new(argument)
+                        }
+                        _ => Box::new(self.expression(argument, scope)),
+                    })
+                    .collect();
+
+                let identifier = if self.functions.contains(identifier.as_str()) {
+                    self.qualify(identifier)
+                } else {
+                    identifier.clone()
+                };
+
+                Primary::Call(identifier, arguments, *span)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// Writes `files` into a fresh directory under the system temporary
+    /// directory and returns its path.
+    fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("musath-{}", name));
+        let _ = fs::remove_dir_all(&directory);
+
+        for (path, source) in files {
+            let path = directory.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+
+        directory
+    }
+
+    fn load(directory: &Path, path: &str) -> Result<Document, MusathError> {
+        let path = directory.join(path);
+
+        Document::from_source_at(&fs::read_to_string(&path).unwrap(), path)
+    }
+
+    #[test]
+    fn test_import() {
+        let directory = write_files(
+            "test-import",
+            &[
+                (
+                    "main.mth",
+                    "import \"lib/envelopes.mth\"\n\
+                     import \"lib/envelopes.mth\" as env\n\
+                     output(t) = decay(t) + env.decay(t) + env.RATE",
+                ),
+                (
+                    "lib/envelopes.mth",
+                    "import \"shape.mth\"\n\
+                     const RATE = 2\n\
+                     decay(t) = shape(1 - t * RATE) + sum(RATE, 0, 2, RATE)",
+                ),
+                ("lib/shape.mth", "shape(x) = x ^ 2"),
+            ],
+        );
+
+        let document = load(&directory, "main.mth").unwrap();
+        let context = document.body().context();
+
+        assert!(context.function("env.decay").is_some());
+        assert!(context.function("env.shape").is_some());
+        assert_eq!(context.value("env.RATE"), Some(&2.0));
+        assert!(document.validate().is_empty());
+        assert_eq!(document.eval(0.25).unwrap(), 1.25 + 1.25 + 2.0);
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let directory = write_files(
+            "test-import-errors",
+            &[
+                ("cycle.mth", "import \"a.mth\"\noutput(t) = t"),
+                ("a.mth", "import \"b.mth\""),
+                ("b.mth", "import \"a.mth\""),
+                (
+                    "collision.mth",
+                    "import \"f.mth\"\nf(t) = t\noutput(t) = f(t)",
+                ),
+                (
+                    "collisions.mth",
+                    "import \"f.mth\"\nimport \"g.mth\"\noutput(t) = f(t)",
+                ),
+                (
+                    "identical.mth",
+                    "import \"f.mth\"\nimport \"h.mth\"\noutput(t) = f(t)",
+                ),
+                ("f.mth", "f(t) = t"),
+                ("g.mth", "f(t) = 2 * t"),
+                ("h.mth", "// the same definition as f.mth\nf(t) = t"),
+                ("invalid.mth", "import \"undefined.mth\"\noutput(t) = t"),
+                ("undefined.mth", "f(t) = g(t)\nh(t) = k(t)"),
+                ("missing.mth", "import \"nowhere.mth\"\noutput(t) = t"),
+            ],
+        );
+
+        let error = load(&directory, "cycle.mth").unwrap_err();
+        let ErrorKind::ImportFailed { path, errors } = error.kind() else {
+            panic!("expected import failure, found {:?}", error);
+        };
+        assert_eq!(path, "a.mth");
+        assert!(
+            error
+                .to_string()
+                .contains("import cycle: a.mth -> b.mth -> a.mth")
+        );
+        let ErrorKind::ImportFailed { path, errors } = errors[0].kind() else {
+            panic!("expected import failure, found {:?}", errors[0]);
+        };
+        assert_eq!(path, "b.mth");
+        assert!(matches!(errors[0].kind(), ErrorKind::ImportCycle(_)));
+        assert!(errors[0].file().unwrap().path().ends_with("b.mth"));
+
+        let error = load(&directory, "collision.mth").unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::NameCollision(identifier) if identifier == "f"));
+        assert_eq!(error.span().unwrap().line(), 2);
+
+        let error = load(&directory, "collisions.mth").unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::NameCollision(identifier) if identifier == "f"));
+        assert_eq!(error.span().unwrap().line(), 2);
+
+        // Identical definitions at different places do not collide.
+        assert!(load(&directory, "identical.mth").is_ok());
+
+        let error = load(&directory, "invalid.mth").unwrap_err();
+        let ErrorKind::ImportFailed { errors, .. } = error.kind() else {
+            panic!("expected import failure, found {:?}", error);
+        };
+        assert_eq!(errors.len(), 2);
+        assert!(
+            matches!(errors[0].kind(), ErrorKind::UndefinedFunction(identifier) if identifier == "g")
+        );
+        assert_eq!(errors[0].span().unwrap().line(), 1);
+        assert!(
+            matches!(errors[1].kind(), ErrorKind::UndefinedFunction(identifier) if identifier == "k")
+        );
+        assert_eq!(errors[1].span().unwrap().line(), 2);
+        assert!(errors[1].file().unwrap().path().ends_with("undefined.mth"));
+
+        let rendered = error.render("invalid.mth", "import \"undefined.mth\"\noutput(t) = t");
+        assert!(rendered.contains("undefined.mth:2:8"));
+        assert!(rendered.contains("2 | h(t) = k(t)"));
+
+        let error = load(&directory, "missing.mth").unwrap_err();
+        assert!(matches!(error.kind(), ErrorKind::ImportFailed { .. }));
+        assert_eq!(error.span().unwrap().column(), 9);
+    }
+
+    #[test]
+    fn test_import_settings_and_warnings() {
+        let directory = write_files(
+            "test-import-settings",
+            &[
+                (
+                    "main.mth",
+                    "SAMPLE_RATE = 8000\nA4_HZ = 432\n\n\
+                     import \"lib.mth\"\n\
+                     output(t) = PITCH + KEY + SAW",
+                ),
+                (
+                    "lib.mth",
+                    "SAMPLE_RATE = 44100\n\n\
+                     const PITCH = A4\n\
+                     const KEY = ftom(432)\n\
+                     const SAW = saw(3000, 0.1)\n\
+                     f(t) = 2 - t % 2",
+                ),
+            ],
+        );
+
+        let document = load(&directory, "main.mth").unwrap();
+        let context = document.body().context();
+        let saw = Document::from_source("SAMPLE_RATE = 8000\noutput(t) = saw(3000, 0.1)")
+            .unwrap()
+            .eval(0.0)
+            .unwrap();
+
+        assert_eq!(context.value("PITCH"), Some(&432.0));
+        assert_eq!(context.value("KEY"), Some(&69.0));
+        assert_eq!(context.value("SAW"), Some(&saw));
+
+        let [warning] = document.warnings() else {
+            panic!("expected one warning, found {:?}", document.warnings());
+        };
+        assert!(
+            matches!(warning.kind(), ErrorKind::PrecedenceChanged(identifier) if identifier == "f")
+        );
+        assert_eq!(warning.span().unwrap().line(), 6);
+        assert!(warning.file().unwrap().path().ends_with("lib.mth"));
+    }
+}
@@ -0,0 +1,472 @@
+//! Inline expansion of small functions.
+//!
+//! Every call to a user function that is not recursive and whose body, after
+//! its own calls have been expanded, has at most `threshold` nodes is replaced
+//! by that body, unless the function has a [control rate](crate::control_rate).
+//! Arguments that are numbers or identifiers are substituted for the
+//! parameters directly; other arguments are bound with `let` so they are still
+//! evaluated once per call. A file made of small helpers, such as `test.mth`,
+//! ends up with a single expression for `output`.
+//!
+//! Parameters and bindings of an expanded body are renamed to names that
+//! cannot appear in source, such as `freq#3`, so they never capture a name
+//! used by an argument.
+//!
+//! The other names an expanded body refers to are not renamed, so a caller
+//! binding the same name would capture them. Expansion therefore runs only
+//! after [`optimize`](crate::optimizer::optimize) has folded every constant
+//! and global value, as [`Body::optimize`](crate::body::Body::optimize) does,
+//! leaving bodies without free names.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    context::Context,
+    error::Span,
+    expression::{Expression, Primary},
+    function::{Function, FunctionBody},
+    validation::BINDING_BUILTINS,
+};
+
+/// The largest body, in nodes, expanded by [`Body::optimize`](crate::body::Body::optimize).
+pub const DEFAULT_THRESHOLD: usize = 64;
+
+/// Expands calls to small non-recursive functions in every user function of
+/// `context`, whose constants must already be folded.
+pub(crate) fn inline(context: &mut Context, threshold: usize) {
+    let mut inliner = Inliner {
+        context: &*context,
+        threshold,
+        recursive: recursive(context),
+        bodies: HashMap::new(),
+        names: 0,
+    };
+
+    let mut identifiers = context
+        .functions()
+        .iter()
+        .filter(|(_, function)| matches!(function.body(), FunctionBody::Expression(_)))
+        .map(|(identifier, _)| identifier.as_str())
+        .collect::<Vec<_>>();
+
+    // Expand in a fixed order so the generated names are reproducible.
+    identifiers.sort();
+
+    let functions = identifiers
+        .into_iter()
+        .map(|identifier| {
+            let function = &context.functions()[identifier];
+
+            function.renamed(identifier, inliner.body(identifier))
+        })
+        .collect::<Vec<Function>>();
+
+    for function in functions {
+        context.set_function(function);
+    }
+}
+
+/// The user functions that can call themselves, directly or through others.
+pub(crate) fn recursive(context: &Context) -> HashSet<String> {
+    let calls = context
+        .functions()
+        .iter()
+        .filter_map(|(identifier, function)| match function.body() {
+            FunctionBody::Expression(expression) => {
+                let mut callees = Vec::new();
+
+                self::callees(expression, context, &mut callees);
+
+                Some((identifier.as_str(), callees))
+            }
+            FunctionBody::Closure(_) => None,
+        })
+        .collect::<HashMap<_, _>>();
+
+    calls
+        .keys()
+        .filter(|identifier| {
+            let mut stack = calls[*identifier].clone();
+            let mut visited = HashSet::new();
+
+            while let Some(callee) = stack.pop() {
+                if callee == **identifier {
+                    return true;
+                }
+
+                if visited.insert(callee) {
+                    stack.extend(calls.get(callee).into_iter().flatten());
+                }
+            }
+
+            false
+        })
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// The user functions called in `expression`.
+fn callees<'a>(expression: &'a Expression, context: &Context, callees: &mut Vec<&'a str>) {
+    match expression {
+        Expression::Primary(Primary::Call(identifier, arguments, _)) => {
+            if context
+                .function(identifier)
+                .is_some_and(|function| matches!(function.body(), FunctionBody::Expression(_)))
+            {
+                callees.push(identifier);
+            }
+
+            for argument in arguments {
+                self::callees(argument, context, callees);
+            }
+        }
+        Expression::Primary(Primary::Grouping(expression)) => {
+            self::callees(expression, context, callees)
+        }
+        Expression::Primary(_) => {}
+        Expression::Unary(_, operand) => self::callees(operand, context, callees),
+        Expression::Binary(left, _, right) => {
+            self::callees(left, context, callees);
+            self::callees(right, context, callees);
+        }
+        Expression::Conditional(condition, consequent, alternative) => {
+            self::callees(condition, context, callees);
+            self::callees(consequent, context, callees);
+            self::callees(alternative, context, callees);
+        }
+        Expression::Let(bindings, body) => {
+            for (_, expression) in bindings {
+                self::callees(expression, context, callees);
+            }
+
+            self::callees(body, context, callees);
+        }
+    }
+}
+
+/// The number of nodes in `expression`.
+pub fn size(expression: &Expression) -> usize {
+    1 + match expression {
+        Expression::Primary(Primary::Call(_, arguments, _)) => {
+            arguments.iter().map(|argument| size(argument)).sum()
+        }
+        Expression::Primary(Primary::Grouping(expression)) => size(expression),
+        Expression::Primary(_) => 0,
+        Expression::Unary(_, operand) => size(operand),
+        Expression::Binary(left, _, right) => size(left) + size(right),
+        Expression::Conditional(condition, consequent, alternative) => {
+            size(condition) + size(consequent) + size(alternative)
+        }
+        Expression::Let(bindings, body) => {
+            bindings
+                .iter()
+                .map(|(_, expression)| size(expression))
+                .sum::<usize>()
+                + size(body)
+        }
+    }
+}
+
+struct Inliner<'a> {
+    context: &'a Context,
+    threshold: usize,
+    recursive: HashSet<String>,
+    /// The expanded body of each function seen so far.
+    bodies: HashMap<String, Expression>,
+    names: usize,
+}
+
+impl Inliner<'_> {
+    /// The body of a user function with its calls expanded.
+    fn body(&mut self, identifier: &str) -> Expression {
+        if let Some(body) = self.bodies.get(identifier) {
+            return body.clone();
+        }
+
+        let FunctionBody::Expression(expression) = self.context.functions()[identifier].body()
+        else {
+            unreachable!("expected expression body for `{}`", identifier);
+        };
+
+        let body = self.expression(expression);
+
+        self.bodies.insert(identifier.to_string(), body.clone());
+
+        body
+    }
+
+    fn expression(&mut self, expression: &Expression) -> Expression {
+        match expression {
+            Expression::Primary(Primary::Call(identifier, arguments, span)) => {
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| Box::new(self.expression(argument)))
+                    .collect::<Vec<_>>();
+
+                self.expand(identifier, &arguments).unwrap_or_else(|| {
+                    Expression::Primary(Primary::Call(identifier.clone(), arguments, *span))
+                })
+            }
+            Expression::Primary(Primary::Grouping(expression)) => self.expression(expression),
+            Expression::Primary(primary) => Expression::Primary(primary.clone()),
+            Expression::Unary(operator, operand) => {
+                Expression::Unary(operator.clone(), Box::new(self.expression(operand)))
+            }
+            Expression::Binary(left, operator, right) => Expression::Binary(
+                Box::new(self.expression(left)),
+                operator.clone(),
+                Box::new(self.expression(right)),
+            ),
+            Expression::Conditional(condition, consequent, alternative) => Expression::Conditional(
+                Box::new(self.expression(condition)),
+                Box::new(self.expression(consequent)),
+                Box::new(self.expression(alternative)),
+            ),
+            Expression::Let(bindings, body) => Expression::Let(
+                bindings
+                    .iter()
+                    .map(|(identifier, expression)| {
+                        (identifier.clone(), self.expression(expression))
+                    })
+                    .collect(),
+                Box::new(self.expression(body)),
+            ),
+        }
+    }
+
+    /// The expansion of a call, if the callee can be inlined.
+    fn expand(&mut self, identifier: &str, arguments: &[Box<Expression>]) -> Option<Expression> {
+        let function = self.context.function(identifier)?;
+        let parameters = function.signature().parameters();
+
+        if !matches!(function.body(), FunctionBody::Expression(_))
+            || self.recursive.contains(identifier)
+            || parameters.len() != arguments.len()
+            || function.control_rate().is_some()
+        {
+            return None;
+        }
+
+        let body = self.body(identifier);
+
+        if size(&body) > self.threshold {
+            return None;
+        }
+
+        let mut substitutions = HashMap::new();
+        let mut bindings = Vec::new();
+
+        for (parameter, argument) in parameters.iter().zip(arguments) {
+            match argument.as_ref() {
+                Expression::Primary(
+                    Primary::Decimal(_)
+                    | Primary::Integer(_)
+                    | Primary::Note(_)
+                    | Primary::Identifier(..),
+                ) => {
+                    substitutions.insert(parameter.clone(), argument.as_ref().clone());
+                }
+                argument => {
+                    let name = self.name(parameter);
+
+                    substitutions.insert(parameter.clone(), identifier_expression(&name));
+                    bindings.push((name, argument.clone()));
+                }
+            }
+        }
+
+        let body = self.substitute(&body, &substitutions);
+
+        Some(if bindings.is_empty() {
+            body
+        } else {
+            Expression::Let(bindings, Box::new(body))
+        })
+    }
+
+    /// A name for `identifier` that is unused and cannot appear in source.
+    fn name(&mut self, identifier: &str) -> String {
+        self.names += 1;
+
+        let identifier = identifier.split('#').next().unwrap_or(identifier);
+
+        format!("{}#{}", identifier, self.names)
+    }
+
+    /// Replaces the identifiers in `substitutions`, giving every binding in
+    /// `expression` a fresh name.
+    fn substitute(
+        &mut self,
+        expression: &Expression,
+        substitutions: &HashMap<String, Expression>,
+    ) -> Expression {
+        match expression {
+            Expression::Primary(Primary::Identifier(identifier, _)) => substitutions
+                .get(identifier)
+                .cloned()
+                .unwrap_or_else(|| expression.clone()),
+            Expression::Primary(Primary::Call(identifier, arguments, span)) => {
+                let mut arguments = arguments.iter().collect::<Vec<_>>();
+                let mut inner = None;
+
+                if let [variable, _, _, body] = arguments[..]
+                    && BINDING_BUILTINS.contains(&identifier.as_str())
+                    && let Expression::Primary(Primary::Identifier(variable, _)) = variable.as_ref()
+                {
+                    let name = self.name(variable);
+                    let mut substitutions = substitutions.clone();
+
+                    substitutions.insert(variable.clone(), identifier_expression(&name));
+                    inner = Some((name, self.substitute(body, &substitutions)));
+                    arguments.pop();
+                }
+
+                let mut arguments = arguments
+                    .into_iter()
+                    .map(|argument| Box::new(self.substitute(argument, substitutions)))
+                    .collect::<Vec<_>>();
+
+                if let Some((name, body)) = inner {
+                    *arguments[0] = identifier_expression(&name);
+                    arguments.push(Box::new(body));
+                }
+
+                Expression::Primary(Primary::Call(identifier.clone(), arguments, *span))
+            }
+            Expression::Primary(Primary::Grouping(expression)) => {
+                self.substitute(expression, substitutions)
+            }
+            Expression::Primary(_) => expression.clone(),
+            Expression::Unary(operator, operand) => Expression::Unary(
+                operator.clone(),
+                Box::new(self.substitute(operand, substitutions)),
+            ),
+            Expression::Binary(left, operator, right) => Expression::Binary(
+                Box::new(self.substitute(left, substitutions)),
+                operator.clone(),
+                Box::new(self.substitute(right, substitutions)),
+            ),
+            Expression::Conditional(condition, consequent, alternative) => Expression::Conditional(
+                Box::new(self.substitute(condition, substitutions)),
+                Box::new(self.substitute(consequent, substitutions)),
+                Box::new(self.substitute(alternative, substitutions)),
+            ),
+            Expression::Let(bindings, body) => {
+                let mut substitutions = substitutions.clone();
+
+                let bindings = bindings
+                    .iter()
+                    .map(|(identifier, expression)| {
+                        let expression = self.substitute(expression, &substitutions);
+                        let name = self.name(identifier);
+
+                        substitutions.insert(identifier.clone(), identifier_expression(&name));
+
+                        (name, expression)
+                    })
+                    .collect();
+
+                Expression::Let(bindings, Box::new(self.substitute(body, &substitutions)))
+            }
+        }
+    }
+}
+
+fn identifier_expression(identifier: &str) -> Expression {
+    Expression::Primary(Primary::Identifier(identifier.to_string(), Span::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{document::Document, validation::validate};
+
+    use super::*;
+
+    fn calls(context: &Context, identifier: &str) -> Vec<String> {
+        let FunctionBody::Expression(expression) = context.function(identifier).unwrap().body()
+        else {
+            unreachable!();
+        };
+
+        let mut calls = Vec::new();
+
+        callees(expression, context, &mut calls);
+
+        calls.into_iter().map(ToString::to_string).collect()
+    }
+
+    fn output(context: &Context, t: f64) -> f64 {
+        Expression::Primary(Primary::Call(
+            String::from("output"),
+            vec![Box::new(Expression::Primary(Primary::Decimal(t)))],
+            Span::default(),
+        ))
+        .eval(&mut context.clone())
+        .unwrap()
+    }
+
+    #[test]
+    fn test_inline() {
+        let document = Document::from_source(
+            "
+            step(t, freq) = floor((t * freq * 2) % 2)
+            scale(x) = let t = 2 in x * t
+            loop(n) = if n <= 0 then 0 else step(n, 1) + loop(n - 1)
+            output(t) = scale(step(t, 440) + 1) + sum(n, 0, 3, scale(t * n)) + loop(t)
+            ",
+        )
+        .unwrap();
+
+        let expected = document.body().context();
+        let mut context = expected.clone();
+
+        inline(&mut context, DEFAULT_THRESHOLD);
+
+        assert_eq!(calls(&context, "output"), ["loop"]);
+        assert_eq!(calls(&context, "loop"), ["loop"]);
+        assert!(validate(&context).is_empty());
+
+        for t in [0.0, 0.1, 0.25, 1.5, 3.0] {
+            assert_eq!(output(&context, t), output(expected, t));
+        }
+
+        let mut context = expected.clone();
+
+        inline(&mut context, 4);
+
+        assert_eq!(
+            calls(&context, "output"),
+            ["scale", "step", "scale", "loop"]
+        );
+    }
+
+    #[test]
+    fn test_inline_shadowed_constant() {
+        let mut document = Document::from_source(
+            "
+            const X = 3
+            f(a) = a * X
+            g(X) = f(X) + X
+            output(t) = g(t + 1)
+            ",
+        )
+        .unwrap();
+
+        document.optimize();
+
+        assert!(calls(document.body().context(), "output").is_empty());
+        assert_eq!(document.eval(0.5).unwrap(), 1.5 * 3.0 + 1.5);
+    }
+
+    #[test]
+    fn test_inline_examples() {
+        for path in ["delay.mth", "test.mth"] {
+            let source = std::fs::read_to_string(path).unwrap();
+            let mut document = Document::from_source_at(&source, path).unwrap();
+
+            document.optimize();
+
+            assert!(calls(document.body().context(), "output").is_empty());
+        }
+    }
+}
@@ -0,0 +1,1212 @@
+//! Native code for a [`Document`], generated with Cranelift.
+//!
+//! Every user function the document renders with becomes a native function
+//! taking and returning `f64`s, with `sum` and `prod` compiled to loops.
+//! Operations that Cranelift has no exact equivalent for, such as `sin`,
+//! `powf` and `rem_euclid`, are calls to the same Rust functions the
+//! interpreter uses, so a document renders to exactly the same samples
+//! either way. Functions with a [control rate](crate::control_rate) keep the
+//! control points of each call site in a state that every generated function
+//! takes as its first parameter, and that [`Program`] keeps for a block of
+//! samples.
+//!
+//! Only available with the `jit` feature.
+
+use std::{collections::HashMap, mem::ManuallyDrop};
+
+use cranelift_codegen::{
+    Context as CodegenContext,
+    ir::{
+        AbiParam, FuncRef, InstBuilder, MemFlags, Signature, StackSlotData, StackSlotKind, Value,
+        condcodes::{FloatCC, IntCC},
+        types::{F64, I8, I64},
+    },
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{FuncId, Linkage, Module, default_libcall_names};
+
+use crate::{
+    builtins::{self, BUILTINS, Implementation},
+    context::Context,
+    control_rate::{ControlRate, Interpolation},
+    document::Document,
+    error::{ErrorKind, MusathError, Span},
+    expression::{BinaryOperator, Expression, Primary, UnaryOperator},
+    function::{Function, FunctionBody},
+    wave_provider::{MultichannelWaveProvider, WaveProvider},
+};
+
+/// Host functions the generated code calls, by symbol name, besides the
+/// [`WRAPPERS`] of the numeric [builtins](crate::builtins). The names cannot
+/// collide with user functions, whose identifiers never contain `::`.
+const SYMBOLS: [(&str, *const u8, usize); 5] = [
+    ("musath::powf", powf as *const u8, 2),
+    ("musath::rem_euclid", rem_euclid as *const u8, 2),
+    ("musath::round", round as *const u8, 1),
+    (
+        "musath::interpolate_linear",
+        interpolate_linear as *const u8,
+        3,
+    ),
+    (
+        "musath::interpolate_cubic",
+        interpolate_cubic as *const u8,
+        5,
+    ),
+];
+
+/// Wrappers calling a numeric builtin by its index in [`BUILTINS`], by
+/// symbol name and number of values. The builtins are plain Rust functions,
+/// which generated code cannot call directly.
+const WRAPPERS: [(&str, *const u8, usize); 4] = [
+    ("musath::unary", unary as *const u8, 1),
+    ("musath::binary", binary as *const u8, 2),
+    ("musath::ternary", ternary as *const u8, 3),
+    ("musath::quaternary", quaternary as *const u8, 4),
+];
+
+/// The parameters of a host function.
+#[derive(Debug, Clone, Copy)]
+enum Parameters {
+    /// The given number of values.
+    Values(usize),
+    /// The index of a builtin in [`BUILTINS`] followed by the given number of
+    /// values, as the [`WRAPPERS`] take them.
+    Builtin(usize),
+    /// The index of a builtin in [`BUILTINS`] followed by a pointer to any
+    /// number of values and their number, as [`slice`] takes them.
+    Slice,
+}
+
+/// Every host function with its symbol name and parameters.
+fn host_functions() -> impl Iterator<Item = (String, *const u8, Parameters)> {
+    SYMBOLS
+        .into_iter()
+        .map(|(name, pointer, parameters)| {
+            (name.to_string(), pointer, Parameters::Values(parameters))
+        })
+        .chain(WRAPPERS.into_iter().map(|(name, pointer, parameters)| {
+            (name.to_string(), pointer, Parameters::Builtin(parameters))
+        }))
+        .chain(std::iter::once((
+            String::from("musath::slice"),
+            slice as *const u8,
+            Parameters::Slice,
+        )))
+}
+
+extern "C" fn unary(index: usize, x: f64) -> f64 {
+    match BUILTINS[index].implementation() {
+        Implementation::Unary(function) => function(x),
+        _ => unreachable!("expected unary builtin, found {:?}", BUILTINS[index]),
+    }
+}
+
+extern "C" fn binary(index: usize, x: f64, y: f64) -> f64 {
+    match BUILTINS[index].implementation() {
+        Implementation::Binary(function) => function(x, y),
+        _ => unreachable!("expected binary builtin, found {:?}", BUILTINS[index]),
+    }
+}
+
+extern "C" fn ternary(index: usize, x: f64, y: f64, z: f64) -> f64 {
+    match BUILTINS[index].implementation() {
+        Implementation::Ternary(function) => function(x, y, z),
+        _ => unreachable!("expected ternary builtin, found {:?}", BUILTINS[index]),
+    }
+}
+
+extern "C" fn quaternary(index: usize, x: f64, y: f64, z: f64, w: f64) -> f64 {
+    match BUILTINS[index].implementation() {
+        Implementation::Quaternary(function) => function(x, y, z, w),
+        _ => unreachable!("expected quaternary builtin, found {:?}", BUILTINS[index]),
+    }
+}
+
+/// Calls the builtin at `index` in [`BUILTINS`] that takes a slice.
+///
+/// # Safety
+///
+/// `arguments` must point to `count` values.
+unsafe extern "C" fn slice(index: usize, arguments: *const f64, count: usize) -> f64 {
+    // SAFETY: guaranteed by the caller.
+    let arguments = unsafe { std::slice::from_raw_parts(arguments, count) };
+
+    match BUILTINS[index].implementation() {
+        Implementation::Slice(function) => function(arguments),
+        _ => unreachable!(
+            "expected builtin taking a slice, found {:?}",
+            BUILTINS[index]
+        ),
+    }
+}
+
+extern "C" fn powf(x: f64, y: f64) -> f64 {
+    BinaryOperator::Exponentiate.eval(x, y)
+}
+
+extern "C" fn rem_euclid(x: f64, y: f64) -> f64 {
+    BinaryOperator::Remainder.eval(x, y)
+}
+
+/// Rounds a bound of `sum` or `prod` as the interpreter does.
+extern "C" fn round(x: f64) -> f64 {
+    x.round() as isize as f64
+}
+
+extern "C" fn interpolate_linear(a: f64, b: f64, fraction: f64) -> f64 {
+    Interpolation::Linear.interpolate(&[a, b], fraction)
+}
+
+extern "C" fn interpolate_cubic(a: f64, b: f64, c: f64, d: f64, fraction: f64) -> f64 {
+    Interpolation::Cubic.interpolate(&[a, b, c, d], fraction)
+}
+
+type Unary = extern "C" fn(*mut f64, f64) -> f64;
+type Binary = extern "C" fn(*mut f64, f64, f64) -> f64;
+
+/// A compiled document. See [`compile`].
+pub struct Program {
+    module: ManuallyDrop<JITModule>,
+    channels: u16,
+    outputs: Outputs,
+    /// The number of values in the state of the generated code.
+    state: usize,
+}
+
+/// The functions producing each channel, chosen as by
+/// [`Document::eval_channel`].
+enum Outputs {
+    Stereo { left: Unary, right: Unary },
+    Output(Unary),
+    OutputWithChannel(Binary),
+}
+
+// SAFETY: the module is only kept to own the generated code. It is never
+// modified after compilation, and the code itself only keeps state in the
+// buffer it is given.
+unsafe impl Send for Program {}
+unsafe impl Sync for Program {}
+
+impl Drop for Program {
+    fn drop(&mut self) {
+        // SAFETY: the function pointers into the module are dropped with it.
+        unsafe { ManuallyDrop::take(&mut self.module).free_memory() }
+    }
+}
+
+impl Program {
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// A state without control points. NaN never matches a control point's
+    /// index, so the first call at each call site computes its points.
+    fn state(&self) -> Vec<f64> {
+        vec![f64::NAN; self.state]
+    }
+
+    /// Evaluates `channel` at `t`, reusing and updating the control points
+    /// in `state`.
+    fn eval(&self, state: &mut [f64], t: f64, channel: u16) -> f64 {
+        assert_eq!(state.len(), self.state, "expected a state of the program");
+
+        let state = state.as_mut_ptr();
+
+        match self.outputs {
+            Outputs::Stereo { left, .. } if channel == 0 => left(state, t),
+            Outputs::Stereo { right, .. } if channel == 1 => right(state, t),
+            Outputs::Stereo { .. } => unreachable!("expected channel 0 or 1, found {}", channel),
+            Outputs::Output(output) => output(state, t),
+            Outputs::OutputWithChannel(output) => output(state, t, channel.into()),
+        }
+    }
+}
+
+impl WaveProvider for Program {
+    fn value_at_time(&self, t: f64) -> f64 {
+        MultichannelWaveProvider::value_at_time(self, t, 0)
+    }
+
+    fn fill_block(&self, start_sample: u64, sample_rate: u32, out: &mut [f64]) {
+        MultichannelWaveProvider::fill_block(self, start_sample, sample_rate, 0, out)
+    }
+}
+
+impl MultichannelWaveProvider for Program {
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn value_at_time(&self, t: f64, channel: u16) -> f64 {
+        self.eval(&mut self.state(), t, channel)
+    }
+
+    /// Computes the block with a single state, so consecutive samples share
+    /// control points.
+    fn fill_block(&self, start_sample: u64, sample_rate: u32, channel: u16, out: &mut [f64]) {
+        let mut state = self.state();
+
+        for (sample, value) in (start_sample..).zip(out) {
+            *value = self.eval(&mut state, sample as f64 / sample_rate as f64, channel);
+        }
+    }
+}
+
+/// Compiles the functions `document` renders with, and everything they call,
+/// to native code for the host.
+pub fn compile(document: &Document) -> Result<Program, MusathError> {
+    let mut flags = settings::builder();
+
+    flags.set("opt_level", "speed").map_err(codegen_error)?;
+
+    let isa = cranelift_native::builder()
+        .map_err(codegen_error)?
+        .finish(settings::Flags::new(flags))
+        .map_err(codegen_error)?;
+
+    let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+
+    for (name, pointer, _) in host_functions() {
+        builder.symbol(name, pointer);
+    }
+
+    let mut compiler = Compiler {
+        context: document.body().context(),
+        module: JITModule::new(builder),
+        functions: HashMap::new(),
+        pending: Vec::new(),
+        symbols: HashMap::new(),
+        state: 0,
+    };
+
+    for (name, _, parameters) in host_functions() {
+        let signature = compiler.host_signature(parameters);
+        let id = compiler
+            .module
+            .declare_function(&name, Linkage::Import, &signature)
+            .map_err(codegen_error)?;
+
+        compiler.symbols.insert(name, id);
+    }
+
+    let stereo = document.channels() == 2 && document.body().is_stereo();
+    let entries = if stereo {
+        vec![compiler.entry("left")?, compiler.entry("right")?]
+    } else {
+        vec![compiler.entry("output")?]
+    };
+
+    while let Some((id, function)) = compiler.pending.pop() {
+        compiler.define(id, function)?;
+    }
+
+    compiler
+        .module
+        .finalize_definitions()
+        .map_err(codegen_error)?;
+
+    let pointers = entries
+        .iter()
+        .map(|id| compiler.module.get_finalized_function(*id))
+        .collect::<Vec<_>>();
+
+    // SAFETY: each entry was declared with a pointer to the state followed by
+    // one `f64` parameter per parameter of its function, and an `f64` result.
+    let outputs = unsafe {
+        match pointers[..] {
+            [left, right] => Outputs::Stereo {
+                left: std::mem::transmute::<*const u8, Unary>(left),
+                right: std::mem::transmute::<*const u8, Unary>(right),
+            },
+            [output] if parameters(compiler.context, "output") == 2 => {
+                Outputs::OutputWithChannel(std::mem::transmute::<*const u8, Binary>(output))
+            }
+            [output] => Outputs::Output(std::mem::transmute::<*const u8, Unary>(output)),
+            _ => unreachable!("expected one or two entries, found {}", pointers.len()),
+        }
+    };
+
+    Ok(Program {
+        module: ManuallyDrop::new(compiler.module),
+        channels: document.channels(),
+        outputs,
+        state: compiler.state,
+    })
+}
+
+fn parameters(context: &Context, identifier: &str) -> usize {
+    context
+        .function(identifier)
+        .map_or(0, |function| function.signature().parameters().len())
+}
+
+fn codegen_error(error: impl ToString) -> MusathError {
+    MusathError::new(ErrorKind::Codegen(error.to_string()))
+}
+
+struct Compiler<'a> {
+    context: &'a Context,
+    module: JITModule,
+    functions: HashMap<&'a str, FuncId>,
+    /// Functions that have been referred to but not defined yet.
+    pending: Vec<(FuncId, &'a Function)>,
+    symbols: HashMap<String, FuncId>,
+    /// The number of values in the state so far: for each call site of a
+    /// function with a control rate, the index of its last control point at
+    /// offset 0, its arguments after the first and the values at the points.
+    state: usize,
+}
+
+impl<'a> Compiler<'a> {
+    fn signature(&self, parameters: usize) -> Signature {
+        let mut signature = self.module.make_signature();
+
+        signature
+            .params
+            .extend(std::iter::repeat_n(AbiParam::new(F64), parameters));
+        signature.returns.push(AbiParam::new(F64));
+
+        signature
+    }
+
+    /// The signature of a user function, which takes a pointer to the state
+    /// before its parameters.
+    fn function_signature(&self, parameters: usize) -> Signature {
+        let pointer = self.module.target_config().pointer_type();
+        let mut signature = self.signature(parameters);
+
+        signature.params.insert(0, AbiParam::new(pointer));
+
+        signature
+    }
+
+    fn host_signature(&self, parameters: Parameters) -> Signature {
+        match parameters {
+            Parameters::Values(parameters) => self.signature(parameters),
+            Parameters::Builtin(parameters) => {
+                let pointer = self.module.target_config().pointer_type();
+                let mut signature = self.signature(parameters);
+
+                signature.params.insert(0, AbiParam::new(pointer));
+
+                signature
+            }
+            Parameters::Slice => {
+                let pointer = self.module.target_config().pointer_type();
+                let mut signature = self.module.make_signature();
+
+                signature.params.push(AbiParam::new(pointer));
+                signature.params.push(AbiParam::new(pointer));
+                signature.params.push(AbiParam::new(pointer));
+                signature.returns.push(AbiParam::new(F64));
+
+                signature
+            }
+        }
+    }
+
+    /// One of the functions a document renders with.
+    fn entry(&mut self, identifier: &'static str) -> Result<FuncId, MusathError> {
+        let function = self
+            .context
+            .function(identifier)
+            .ok_or_else(|| MusathError::new(ErrorKind::MissingOutput))?;
+
+        self.function(identifier, function, Span::default())
+    }
+
+    /// Declares a user function, queueing it for definition the first time it
+    /// is referred to.
+    fn function(
+        &mut self,
+        identifier: &'a str,
+        function: &'a Function,
+        span: Span,
+    ) -> Result<FuncId, MusathError> {
+        if let Some(id) = self.functions.get(identifier) {
+            return Ok(*id);
+        }
+
+        if !matches!(function.body(), FunctionBody::Expression(_)) {
+            return Err(MusathError::with_span(
+                ErrorKind::Uncompilable(identifier.to_string()),
+                span,
+            ));
+        }
+
+        let signature = self.function_signature(function.signature().parameters().len());
+        let id = self
+            .module
+            .declare_function(identifier, Linkage::Local, &signature)
+            .map_err(codegen_error)?;
+
+        self.functions.insert(identifier, id);
+        self.pending.push((id, function));
+
+        Ok(id)
+    }
+
+    fn define(&mut self, id: FuncId, function: &'a Function) -> Result<(), MusathError> {
+        let FunctionBody::Expression(expression) = function.body() else {
+            unreachable!("expected expression body, found {:?}", function.body());
+        };
+
+        let mut context = CodegenContext::new();
+        let mut builder_context = FunctionBuilderContext::new();
+
+        context.func.signature = self.function_signature(function.signature().parameters().len());
+
+        let mut builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+        let entry = builder.create_block();
+
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+
+        let parameters = builder.block_params(entry).to_vec();
+        let mut translator = Translator {
+            compiler: self,
+            builder,
+            state: parameters[0],
+            scope: Vec::new(),
+            variables: 0,
+            references: HashMap::new(),
+        };
+
+        for (parameter, value) in function
+            .signature()
+            .parameters()
+            .iter()
+            .zip(&parameters[1..])
+        {
+            let variable = translator.variable();
+
+            translator.builder.def_var(variable, *value);
+            translator.scope.push((Some(parameter.as_str()), variable));
+        }
+
+        let value = translator.expression(expression)?;
+
+        translator.builder.ins().return_(&[value]);
+        translator.builder.seal_all_blocks();
+        translator.builder.finalize();
+
+        self.module
+            .define_function(id, &mut context)
+            .map_err(codegen_error)
+    }
+}
+
+/// Generates the body of one function.
+struct Translator<'a, 'b> {
+    compiler: &'b mut Compiler<'a>,
+    builder: FunctionBuilder<'b>,
+    /// The pointer to the state, passed on to every user function called.
+    state: Value,
+    /// The variables in scope. Unnamed ones hold the bounds and totals of
+    /// `sum`/`prod` loops.
+    scope: Vec<(Option<&'a str>, Variable)>,
+    variables: usize,
+    references: HashMap<FuncId, FuncRef>,
+}
+
+impl<'a> Translator<'a, '_> {
+    fn variable(&mut self) -> Variable {
+        let variable = Variable::from_u32(self.variables as u32);
+
+        self.variables += 1;
+        self.builder.declare_var(variable, F64);
+
+        variable
+    }
+
+    fn call(&mut self, id: FuncId, arguments: &[Value]) -> Value {
+        let reference = *self.references.entry(id).or_insert_with(|| {
+            self.compiler
+                .module
+                .declare_func_in_func(id, self.builder.func)
+        });
+
+        let call = self.builder.ins().call(reference, arguments);
+
+        self.builder.inst_results(call)[0]
+    }
+
+    /// Calls a user function, passing the state on.
+    fn call_function(&mut self, id: FuncId, arguments: &[Value]) -> Value {
+        let arguments = std::iter::once(self.state)
+            .chain(arguments.iter().copied())
+            .collect::<Vec<_>>();
+
+        self.call(id, &arguments)
+    }
+
+    fn host(&mut self, name: &str, arguments: &[Value]) -> Value {
+        let id = self.compiler.symbols[name];
+
+        self.call(id, arguments)
+    }
+
+    /// Calls the builtin at `index` in [`BUILTINS`] that takes its arguments
+    /// as a slice, which is stored on the stack.
+    fn host_slice(&mut self, index: Value, arguments: &[Value]) -> Value {
+        let pointer = self.compiler.module.target_config().pointer_type();
+        let slot = self.builder.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            (arguments.len() * size_of::<f64>()) as u32,
+            3,
+        ));
+
+        for (index, argument) in arguments.iter().enumerate() {
+            self.builder
+                .ins()
+                .stack_store(*argument, slot, (index * size_of::<f64>()) as i32);
+        }
+
+        let address = self.builder.ins().stack_addr(pointer, slot, 0);
+        let count = self.builder.ins().iconst(pointer, arguments.len() as i64);
+
+        self.host("musath::slice", &[index, address, count])
+    }
+
+    fn constant(&mut self, value: f64) -> Value {
+        self.builder.ins().f64const(value)
+    }
+
+    /// `1` if `value` is truthy and `0` otherwise.
+    fn truthy(&mut self, value: Value) -> Value {
+        let zero = self.constant(0.0);
+        let condition = self.builder.ins().fcmp(FloatCC::NotEqual, value, zero);
+
+        self.select(condition)
+    }
+
+    fn select(&mut self, condition: Value) -> Value {
+        let one = self.constant(1.0);
+        let zero = self.constant(0.0);
+
+        self.builder.ins().select(condition, one, zero)
+    }
+
+    /// Branches on whether `value` is truthy, passing one argument to each
+    /// block.
+    fn branch(
+        &mut self,
+        value: Value,
+        then: (cranelift_codegen::ir::Block, &[Value]),
+        otherwise: (cranelift_codegen::ir::Block, &[Value]),
+    ) {
+        let zero = self.constant(0.0);
+        let condition = self.builder.ins().fcmp(FloatCC::NotEqual, value, zero);
+
+        self.builder
+            .ins()
+            .brif(condition, then.0, then.1, otherwise.0, otherwise.1);
+    }
+
+    fn expression(&mut self, expression: &'a Expression) -> Result<Value, MusathError> {
+        Ok(match expression {
+            Expression::Binary(left, BinaryOperator::And, right) => {
+                let left = self.expression(left)?;
+                let zero = self.constant(0.0);
+                let right_block = self.builder.create_block();
+                let end = self.builder.create_block();
+                let result = self.builder.append_block_param(end, F64);
+
+                self.branch(left, (right_block, &[]), (end, &[zero]));
+                self.builder.switch_to_block(right_block);
+                let right = self.expression(right)?;
+                let right = self.truthy(right);
+                self.builder.ins().jump(end, &[right]);
+                self.builder.switch_to_block(end);
+
+                result
+            }
+            Expression::Binary(left, BinaryOperator::Or, right) => {
+                let left = self.expression(left)?;
+                let one = self.constant(1.0);
+                let right_block = self.builder.create_block();
+                let end = self.builder.create_block();
+                let result = self.builder.append_block_param(end, F64);
+
+                self.branch(left, (end, &[one]), (right_block, &[]));
+                self.builder.switch_to_block(right_block);
+                let right = self.expression(right)?;
+                let right = self.truthy(right);
+                self.builder.ins().jump(end, &[right]);
+                self.builder.switch_to_block(end);
+
+                result
+            }
+            Expression::Binary(left, operator, right) => {
+                let left = self.expression(left)?;
+                let right = self.expression(right)?;
+
+                self.binary(operator, left, right)
+            }
+            Expression::Unary(operator, operand) => {
+                let operand = self.expression(operand)?;
+
+                match operator {
+                    UnaryOperator::Negate => self.builder.ins().fneg(operand),
+                    UnaryOperator::Not => {
+                        let zero = self.constant(0.0);
+                        let condition = self.builder.ins().fcmp(FloatCC::Equal, operand, zero);
+
+                        self.select(condition)
+                    }
+                }
+            }
+            Expression::Primary(primary) => self.primary(primary)?,
+            Expression::Conditional(condition, consequent, alternative) => {
+                let condition = self.expression(condition)?;
+                let consequent_block = self.builder.create_block();
+                let alternative_block = self.builder.create_block();
+                let end = self.builder.create_block();
+                let result = self.builder.append_block_param(end, F64);
+
+                self.branch(condition, (consequent_block, &[]), (alternative_block, &[]));
+
+                self.builder.switch_to_block(consequent_block);
+                let consequent = self.expression(consequent)?;
+                self.builder.ins().jump(end, &[consequent]);
+
+                self.builder.switch_to_block(alternative_block);
+                let alternative = self.expression(alternative)?;
+                self.builder.ins().jump(end, &[alternative]);
+
+                self.builder.switch_to_block(end);
+
+                result
+            }
+            Expression::Let(bindings, body) => {
+                let length = self.scope.len();
+
+                for (identifier, expression) in bindings {
+                    let value = self.expression(expression)?;
+                    let variable = self.variable();
+
+                    self.builder.def_var(variable, value);
+                    self.scope.push((Some(identifier), variable));
+                }
+
+                let value = self.expression(body)?;
+
+                self.scope.truncate(length);
+
+                value
+            }
+        })
+    }
+
+    fn binary(&mut self, operator: &BinaryOperator, left: Value, right: Value) -> Value {
+        let condition = match operator {
+            BinaryOperator::Add => return self.builder.ins().fadd(left, right),
+            BinaryOperator::Subtract => return self.builder.ins().fsub(left, right),
+            BinaryOperator::Multiply => return self.builder.ins().fmul(left, right),
+            BinaryOperator::Divide => return self.builder.ins().fdiv(left, right),
+            BinaryOperator::Exponentiate => return self.host("musath::powf", &[left, right]),
+            BinaryOperator::Remainder => return self.host("musath::rem_euclid", &[left, right]),
+            BinaryOperator::Equal => FloatCC::Equal,
+            BinaryOperator::NotEqual => FloatCC::NotEqual,
+            BinaryOperator::Less => FloatCC::LessThan,
+            BinaryOperator::LessOrEqual => FloatCC::LessThanOrEqual,
+            BinaryOperator::Greater => FloatCC::GreaterThan,
+            BinaryOperator::GreaterOrEqual => FloatCC::GreaterThanOrEqual,
+            BinaryOperator::And | BinaryOperator::Or => {
+                unreachable!("expected strict operator, found {:?}", operator)
+            }
+        };
+
+        let condition = self.builder.ins().fcmp(condition, left, right);
+
+        self.select(condition)
+    }
+
+    fn primary(&mut self, primary: &'a Primary) -> Result<Value, MusathError> {
+        Ok(match primary {
+            Primary::Decimal(number) => self.constant(*number),
+            Primary::Integer(number) => self.constant(*number as f64),
+            Primary::Note(note) => self.constant(note.frequency(self.compiler.context.a4_hz())),
+            Primary::Grouping(expression) => self.expression(expression)?,
+            Primary::Identifier(identifier, span) => {
+                let variable = self
+                    .scope
+                    .iter()
+                    .rev()
+                    .find(|(name, _)| *name == Some(identifier.as_str()))
+                    .map(|(_, variable)| *variable);
+
+                if let Some(variable) = variable {
+                    self.builder.use_var(variable)
+                } else if let Some(value) = self.compiler.context.value(identifier) {
+                    self.constant(*value)
+                } else {
+                    return Err(MusathError::with_span(
+                        ErrorKind::UndefinedIdentifier(identifier.clone()),
+                        *span,
+                    ));
+                }
+            }
+            Primary::Call(identifier, arguments, span) => {
+                let function = self.compiler.context.function(identifier).ok_or_else(|| {
+                    MusathError::with_span(ErrorKind::UndefinedFunction(identifier.clone()), *span)
+                })?;
+
+                let arity = function.signature().arity();
+
+                if !arity.accepts(arguments.len()) {
+                    return Err(MusathError::with_span(
+                        ErrorKind::ArityMismatch {
+                            function: identifier.clone(),
+                            expected: arity,
+                            found: arguments.len(),
+                        },
+                        *span,
+                    ));
+                }
+
+                match function.body() {
+                    FunctionBody::Expression(_) => {
+                        let id = self.compiler.function(identifier, function, *span)?;
+                        let arguments = arguments
+                            .iter()
+                            .map(|argument| self.expression(argument))
+                            .collect::<Result<Vec<_>, _>>()?;
+
+                        match function.control_rate() {
+                            Some(control_rate) => self.control_call(id, control_rate, &arguments),
+                            None => self.call_function(id, &arguments),
+                        }
+                    }
+                    FunctionBody::Closure(_) => self.builtin(identifier, arguments, *span)?,
+                }
+            }
+        })
+    }
+
+    /// Calls a function with a control rate at the control points around
+    /// `arguments[0]` and interpolates between them, reusing the points this
+    /// call site left in the state as [`ControlRate::interpolate_cached`]
+    /// does.
+    fn control_call(
+        &mut self,
+        id: FuncId,
+        control_rate: ControlRate,
+        arguments: &[Value],
+    ) -> Value {
+        let offsets = control_rate.interpolation().offsets();
+        let start = self.compiler.state;
+
+        self.compiler.state += arguments.len() + offsets.len();
+
+        let rate = self.constant(control_rate.rate());
+        let position = self.builder.ins().fmul(arguments[0], rate);
+        let index = self.builder.ins().floor(position);
+        let fraction = self.builder.ins().fsub(position, index);
+
+        // The points are reused if the other arguments have the same bits and
+        // the index is the same or one further.
+        let mut identical = self.builder.ins().iconst(I8, 1);
+
+        for (offset, argument) in (start + 1..).zip(&arguments[1..]) {
+            let bits = self.builder.ins().bitcast(I64, MemFlags::new(), *argument);
+            let cached = self.load(I64, offset);
+            let equal = self.builder.ins().icmp(IntCC::Equal, bits, cached);
+
+            identical = self.builder.ins().band(identical, equal);
+        }
+
+        let cached_index = self.load(F64, start);
+        let step = self.builder.ins().fsub(index, cached_index);
+        let zero = self.constant(0.0);
+        let one = self.constant(1.0);
+        let same = self.builder.ins().fcmp(FloatCC::Equal, step, zero);
+        let same = self.builder.ins().band(identical, same);
+        let next = self.builder.ins().fcmp(FloatCC::Equal, step, one);
+        let next = self.builder.ins().band(identical, next);
+
+        let values = start + arguments.len();
+        let reuse = self.builder.create_block();
+        let check_next = self.builder.create_block();
+        let shift = self.builder.create_block();
+        let compute = self.builder.create_block();
+        let end = self.builder.create_block();
+
+        for _ in offsets {
+            self.builder.append_block_param(end, F64);
+        }
+
+        self.builder.ins().brif(same, reuse, &[], check_next, &[]);
+
+        self.builder.switch_to_block(reuse);
+        let points = (values..values + offsets.len())
+            .map(|offset| self.load(F64, offset))
+            .collect::<Vec<_>>();
+        self.builder.ins().jump(end, &points);
+
+        self.builder.switch_to_block(check_next);
+        self.builder.ins().brif(next, shift, &[], compute, &[]);
+
+        self.builder.switch_to_block(shift);
+        let mut points = (values + 1..values + offsets.len())
+            .map(|offset| self.load(F64, offset))
+            .collect::<Vec<_>>();
+        points.extend(self.control_points(id, rate, index, arguments, &offsets[points.len()..]));
+        self.builder.ins().jump(end, &points);
+
+        self.builder.switch_to_block(compute);
+        let points = self.control_points(id, rate, index, arguments, offsets);
+        self.builder.ins().jump(end, &points);
+
+        self.builder.switch_to_block(end);
+        let mut points = self.builder.block_params(end).to_vec();
+
+        self.store(index, start);
+
+        for (offset, value) in (start + 1..).zip(arguments[1..].iter().chain(&points)) {
+            self.store(*value, offset);
+        }
+
+        points.push(fraction);
+
+        match control_rate.interpolation() {
+            Interpolation::Linear => self.host("musath::interpolate_linear", &points),
+            Interpolation::Cubic => self.host("musath::interpolate_cubic", &points),
+        }
+    }
+
+    /// Calls a function with a control rate at the control points at
+    /// `offsets` from `index`.
+    fn control_points(
+        &mut self,
+        id: FuncId,
+        rate: Value,
+        index: Value,
+        arguments: &[Value],
+        offsets: &[f64],
+    ) -> Vec<Value> {
+        let mut control_arguments = arguments.to_vec();
+
+        offsets
+            .iter()
+            .map(|offset| {
+                let offset = self.constant(*offset);
+                let time = self.builder.ins().fadd(index, offset);
+
+                control_arguments[0] = self.builder.ins().fdiv(time, rate);
+                self.call_function(id, &control_arguments)
+            })
+            .collect()
+    }
+
+    /// Loads the value at `offset` in the state.
+    fn load(&mut self, ty: cranelift_codegen::ir::Type, offset: usize) -> Value {
+        self.builder.ins().load(
+            ty,
+            MemFlags::trusted(),
+            self.state,
+            (offset * size_of::<f64>()) as i32,
+        )
+    }
+
+    /// Stores `value` at `offset` in the state.
+    fn store(&mut self, value: Value, offset: usize) {
+        self.builder.ins().store(
+            MemFlags::trusted(),
+            value,
+            self.state,
+            (offset * size_of::<f64>()) as i32,
+        );
+    }
+
+    fn builtin(
+        &mut self,
+        identifier: &'a str,
+        arguments: &'a [Box<Expression>],
+        span: Span,
+    ) -> Result<Value, MusathError> {
+        Ok(match identifier {
+            "abs" | "floor" | "ceil" | "trunc" | "sqrt" => {
+                let x = self.expression(&arguments[0])?;
+
+                match identifier {
+                    "abs" => self.builder.ins().fabs(x),
+                    "floor" => self.builder.ins().floor(x),
+                    "ceil" => self.builder.ins().ceil(x),
+                    "trunc" => self.builder.ins().trunc(x),
+                    _ => self.builder.ins().sqrt(x),
+                }
+            }
+            _ if let Some(builtin) = builtins::builtin(identifier)
+                && !matches!(builtin.implementation(), Implementation::Lazy(_)) =>
+            {
+                let mut arguments = arguments
+                    .iter()
+                    .map(|argument| self.expression(argument))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                if let Some(setting) = builtin.setting() {
+                    for value in self.compiler.context.setting(setting) {
+                        arguments.push(self.constant(*value));
+                    }
+                }
+
+                let pointer = self.compiler.module.target_config().pointer_type();
+                let index = BUILTINS
+                    .iter()
+                    .position(|other| other == builtin)
+                    .expect("expected a registered builtin");
+                let index = self.builder.ins().iconst(pointer, index as i64);
+
+                let wrapper = match builtin.implementation() {
+                    Implementation::Unary(_) => "musath::unary",
+                    Implementation::Binary(_) => "musath::binary",
+                    Implementation::Ternary(_) => "musath::ternary",
+                    Implementation::Quaternary(_) => "musath::quaternary",
+                    _ => return Ok(self.host_slice(index, &arguments)),
+                };
+
+                arguments.insert(0, index);
+
+                self.host(wrapper, &arguments)
+            }
+            "mix" => {
+                let mut mix = self.constant(std::iter::empty::<f64>().sum());
+
+                for argument in arguments {
+                    let value = self.expression(argument)?;
+
+                    mix = self.builder.ins().fadd(mix, value);
+                }
+
+                let count = self.constant(arguments.len() as f64);
+
+                self.builder.ins().fdiv(mix, count)
+            }
+            "sum" => self.fold(identifier, arguments, span, BinaryOperator::Add)?,
+            "prod" => self.fold(identifier, arguments, span, BinaryOperator::Multiply)?,
+            _ => {
+                return Err(MusathError::with_span(
+                    ErrorKind::Uncompilable(identifier.to_string()),
+                    span,
+                ));
+            }
+        })
+    }
+
+    /// Compiles `sum` or `prod` to a loop combining the values of the last
+    /// argument.
+    fn fold(
+        &mut self,
+        identifier: &str,
+        arguments: &'a [Box<Expression>],
+        span: Span,
+        operator: BinaryOperator,
+    ) -> Result<Value, MusathError> {
+        let Expression::Primary(Primary::Identifier(name, _)) = arguments[0].as_ref() else {
+            return Err(MusathError::with_span(
+                ErrorKind::InvalidArgument {
+                    function: identifier.to_string(),
+                    message: format!("expected identifier, found {:?}", arguments[0]),
+                },
+                span,
+            ));
+        };
+
+        let identity = match operator {
+            BinaryOperator::Add => std::iter::empty::<f64>().sum(),
+            _ => std::iter::empty::<f64>().product(),
+        };
+
+        let start = self.expression(&arguments[1])?;
+        let start = self.host("musath::round", &[start]);
+        let end = self.expression(&arguments[2])?;
+        let end = self.host("musath::round", &[end]);
+        let identity = self.constant(identity);
+
+        let variable = self.variable();
+        let bound = self.variable();
+        let accumulator = self.variable();
+
+        self.builder.def_var(variable, start);
+        self.builder.def_var(bound, end);
+        self.builder.def_var(accumulator, identity);
+
+        let header = self.builder.create_block();
+        let body = self.builder.create_block();
+        let exit = self.builder.create_block();
+
+        self.builder.ins().jump(header, &[]);
+        self.builder.switch_to_block(header);
+
+        let value = self.builder.use_var(variable);
+        let end = self.builder.use_var(bound);
+        let done = self
+            .builder
+            .ins()
+            .fcmp(FloatCC::GreaterThanOrEqual, value, end);
+
+        self.builder.ins().brif(done, exit, &[], body, &[]);
+        self.builder.switch_to_block(body);
+
+        self.scope.push((Some(name), variable));
+        let term = self.expression(&arguments[3])?;
+        self.scope.pop();
+
+        let total = self.builder.use_var(accumulator);
+        let total = self.binary(&operator, total, term);
+        self.builder.def_var(accumulator, total);
+
+        let value = self.builder.use_var(variable);
+        let one = self.constant(1.0);
+        let next = self.builder.ins().fadd(value, one);
+        self.builder.def_var(variable, next);
+
+        self.builder.ins().jump(header, &[]);
+        self.builder.switch_to_block(exit);
+
+        Ok(self.builder.use_var(accumulator))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jit_examples() {
+        for path in [
+            "delay.mth",
+            "harmonics.mth",
+            "noise.mth",
+            "organ.mth",
+            "pulse.mth",
+            "test.mth",
+        ] {
+            let source = std::fs::read_to_string(path).unwrap();
+            let document = Document::from_source_at(&source, path).unwrap();
+            let program = compile(&document).unwrap();
+
+            for index in 0..500 {
+                let t = index as f64 * 0.0123;
+
+                for channel in 0..document.channels() {
+                    let expected = document.eval_channel(t, channel).unwrap();
+                    let found = MultichannelWaveProvider::value_at_time(&program, t, channel);
+
+                    assert_eq!(
+                        found.to_bits(),
+                        expected.to_bits(),
+                        "{} at t = {}: expected {}, found {}",
+                        path,
+                        t,
+                        expected,
+                        found
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_jit() {
+        let document = Document::from_source(
+            "
+            loop(n) = if n <= 0 then 0 else n + loop(n - 1)
+            binding(x) = let a = x * 2, b = a + 1 in let a = b * a in a - b
+            short(x) = x > 1 && loop(x) || -x
+            folds(t) = sum(i, 0, 4, prod(j, 1, i, j + t)) + sum(i, 3, 1, i) + prod(i, 0, 0, i)
+            builtins(t) = mix(abs(-t), min(t, 2), max(t, 3), floor(t), ceil(t), sin(t) * cos(t))
+            compare(t) = (t == 1) + (t != 1) * 2 + (t < 1) * 4 + (t >= 2) * 8 + !t + t % 0.75 ^ 2
+            library(t) = tan(t) + asin(t / 8) + acos(t / 8) + atan(t) + atan2(t, 2) +
+                sinh(t) + cosh(t) + tanh(t) + exp(t) + ln(abs(t) + 1) + log2(abs(t) + 1) +
+                log10(abs(t) + 1) + sqrt(abs(t)) + cbrt(t) + sign(t) + round(t) + trunc(t) +
+                fract(t) + clamp(t, -1, 1) + lerp(2, 3, t) + smoothstep(-1, 2, t) + hypot(t, 3) +
+                fmod(t, 0.75)
+            output(t) = loop(3) + binding(t) + short(t) + folds(t) + builtins(t) + compare(t) +
+                library(t)
+            ",
+        )
+        .unwrap();
+
+        assert!(document.validate().is_empty());
+
+        let program = compile(&document).unwrap();
+
+        for t in [-2.5, -1.0, 0.0, 0.5, 1.0, 2.0, 7.25] {
+            assert_eq!(
+                WaveProvider::value_at_time(&program, t).to_bits(),
+                document.eval(t).unwrap().to_bits()
+            );
+        }
+
+        let document = Document::from_source(
+            "
+            @rate(100) decay(t, period) = 1 - (t % period) / period
+            @rate(250, cubic) wobble(t) = sin(2 * pi * 3 * t) * decay(t, 0.3)
+            output(t) = sin(2 * pi * 440 * t) * decay(t, 0.5) + wobble(t) + wobble(t / 2)
+            ",
+        )
+        .unwrap();
+        let program = compile(&document).unwrap();
+
+        for index in 0..500 {
+            let t = index as f64 * 0.0123;
+
+            assert_eq!(
+                WaveProvider::value_at_time(&program, t).to_bits(),
+                document.eval(t).unwrap().to_bits()
+            );
+        }
+
+        // Consecutive samples reuse control points within a block, without
+        // changing the result.
+        let mut block = [0.0; 2000];
+
+        MultichannelWaveProvider::fill_block(&program, 17, 8000, 0, &mut block);
+
+        for (sample, value) in (17..).zip(block) {
+            let expected = document.eval(sample as f64 / 8000.0).unwrap();
+
+            assert_eq!(value.to_bits(), expected.to_bits());
+        }
+
+        let document = Document::from_source(
+            "
+            SAMPLE_RATE = 8000
+            A4_HZ = 432
+            pitches(t) = sin(2 * pi * (C#3 + mtof(60 + t)) * t) + ftom(Eb2 * (1 + t)) / 100 +
+                semitones(A4, t) / cents(Bb1, 100 * t) + degree(t * 20 - 7) / 1000
+            envelopes(t) = adsr(t, 0.1, 0.5, 0.05, 0.1, 0.5, 0.2) +
+                adsr(t, 0, 0.3, 0.1, 0.1, 0.2, 0.4, -4) + env(t, 0, 0, 0, 0.2, 1, 0, 0.5, 0.25) +
+                env(t, 0, 1, 3, 0.4, 0, -2, 0.8, 1)
+            output(t) = saw(440, t) + square(220, t, 0.3) + tri(880, -t) + pulse(110, t, 0.5) +
+                envelopes(t) + pitches(t)
+            ",
+        )
+        .unwrap();
+        let program = compile(&document).unwrap();
+
+        for index in 0..1000 {
+            let t = index as f64 / 8000.0;
+
+            assert_eq!(
+                WaveProvider::value_at_time(&program, t).to_bits(),
+                document.eval(t).unwrap().to_bits()
+            );
+        }
+
+        let document =
+            Document::from_source("CHANNELS = 3\noutput(t, channel) = t * channel").unwrap();
+        let program = compile(&document).unwrap();
+
+        assert_eq!(MultichannelWaveProvider::channels(&program), 3);
+        assert_eq!(
+            MultichannelWaveProvider::value_at_time(&program, 0.5, 2),
+            1.0
+        );
+    }
+}
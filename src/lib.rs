@@ -1,11 +1,29 @@
+pub mod block;
 pub mod body;
+pub mod builtins;
+pub mod bytecode;
 pub mod composition;
+pub mod constant;
 pub mod context;
+pub mod control_rate;
 pub mod document;
+pub mod envelope;
+pub mod error;
 pub mod expression;
 pub mod function;
 pub mod header;
+pub mod hoister;
+pub mod import;
+pub mod inliner;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod naming;
+pub mod oscillator;
+pub mod optimizer;
+pub mod pitch;
 pub mod renderer;
+pub mod tuning;
+pub mod validation;
 pub mod wave_provider;
 
 #[derive(pest_derive::Parser)]
@@ -1,54 +1,231 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, process::ExitCode};
 
+use chrono::Local;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use musath::{
-    MusathParser, Rule, composition::Composition, document::Document, renderer::{Renderer, parallel_renderer::ParallelRenderer, serial_renderer::SerialRenderer}
+    composition::Composition, document::Document, error::{ErrorKind, MusathError}, naming, renderer::{DEFAULT_SPEC, Renderer, parallel_renderer::ParallelRenderer, serial_renderer::SerialRenderer, sink::{PcmSink, WavFileSink}, streaming_renderer::StreamingRenderer}
 };
-use pest::Parser;
-use tracing::info;
+use tracing::{info, level_filters::LevelFilter};
 use tracing_subscriber::EnvFilter;
 
 #[derive(clap::Parser)]
 struct Args {
     path: PathBuf,
 
-    #[arg(short, long, value_enum, default_value_t = RendererOption::Parallel)]
+    #[arg(short, long, value_enum, default_value_t = RendererOption::Streaming)]
     renderer: RendererOption,
+
+    /// Overrides the SAMPLE_RATE header key
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    sample_rate: Option<u32>,
+
+    /// Overrides the BIT_DEPTH header key
+    #[arg(
+        long,
+        value_parser = PossibleValuesParser::new(["16", "24", "32"])
+            .map(|bit_depth| bit_depth.parse::<u16>().unwrap())
+    )]
+    bit_depth: Option<u16>,
+
+    /// Renders the functions as written, without folding constants
+    #[arg(long)]
+    no_optimize: bool,
+
+    /// Compiles the functions to native code before rendering
+    #[cfg(feature = "jit")]
+    #[arg(long, conflicts_with = "no_optimize")]
+    jit: bool,
+
+    /// Writes the WAV file to this path instead of naming it with --name
+    #[arg(short, long, value_name = "PATH", conflicts_with_all = ["out_dir", "name", "stdout"])]
+    output: Option<PathBuf>,
+
+    /// The directory to write the WAV file to, created if it does not exist
+    #[arg(long, value_name = "DIR", conflicts_with = "stdout")]
+    out_dir: Option<PathBuf>,
+
+    /// The name of the WAV file, in which {title}, {date}, {time},
+    /// {sample_rate}, {bit_depth} and {channels} are replaced with the
+    /// render's
+    #[arg(long, value_name = "TEMPLATE", default_value = naming::DEFAULT_TEMPLATE)]
+    name: String,
+
+    /// Replaces the WAV file if it already exists
+    #[arg(long)]
+    force: bool,
+
+    /// Writes raw little-endian samples to standard output instead of a WAV
+    /// file
+    #[arg(long)]
+    stdout: bool,
+
+    /// Prints the functions to render, as they will be rendered, instead of
+    /// rendering them
+    #[arg(long)]
+    dump_ast: bool,
+
+    /// Logs progress and optimization statistics, such as the number of
+    /// hoisted time-invariant nodes
+    #[arg(short, long)]
+    verbose: bool,
 }
 
 #[derive(Clone, clap::ValueEnum)]
 enum RendererOption {
     Serial,
     Parallel,
+    Streaming,
 }
 
-fn main() {
+fn main() -> ExitCode {
+    let args = <Args as clap::Parser>::parse();
+
+    let default_level = if args.verbose { LevelFilter::INFO } else { LevelFilter::ERROR };
+
     let subscriber = tracing_subscriber::fmt()
         .compact()
         .with_file(true)
         .with_line_number(true)
         .with_thread_ids(false)
         .with_target(false)
-        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .with_env_filter(
+            EnvFilter::builder()
+                .with_default_directive(default_level.into())
+                .from_env_lossy(),
+        )
         .finish();
 
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
-    let args = <Args as clap::Parser>::parse();
+    let unparsed_file = match std::fs::read_to_string(&args.path) {
+        Ok(unparsed_file) => unparsed_file,
+        Err(error) => {
+            eprintln!("error: cannot read {}: {}", args.path.display(), error);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match run(&args, &unparsed_file) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}", error.render(args.path.display(), &unparsed_file));
+
+                if let ErrorKind::OutputExists(_) = error.kind() {
+                    eprintln!("help: pass --force to overwrite it");
+                }
+            }
 
-    let unparsed_file = std::fs::read_to_string(args.path).expect("cannot read file");
+            let plural = if errors.len() == 1 { "" } else { "s" };
+            eprintln!("error: could not render due to {} previous error{}", errors.len(), plural);
+
+            ExitCode::FAILURE
+        }
+    }
+}
 
+fn run(args: &Args, unparsed_file: &str) -> Result<(), Vec<MusathError>> {
     info!("Parsing...");
-    let document =
-        Document::parse(&mut MusathParser::parse(Rule::document, &unparsed_file).unwrap());
+    let mut document = Document::from_source_at(unparsed_file, &args.path).map_err(|error| vec![error])?;
     info!("Parsed!");
 
+    for warning in document.warnings() {
+        eprintln!("{}", warning.render(args.path.display(), unparsed_file));
+    }
+
+    info!("Validating...");
+    let errors = document.validate();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    info!("Validated!");
+
+    if let Some(sample_rate) = args.sample_rate {
+        document.set_sample_rate(sample_rate);
+    }
+
+    if args.dump_ast {
+        if !args.no_optimize {
+            document.optimize();
+        }
+
+        for identifier in ["output", "left", "right"] {
+            if let Some(function) = document.body().context().function(identifier) {
+                println!("{}", function);
+            }
+        }
+
+        return Ok(());
+    }
+
     info!("Rendering...");
     let renderer = match args.renderer {
         RendererOption::Serial => Box::new(SerialRenderer::default()) as Box<dyn Renderer>,
         RendererOption::Parallel => Box::new(ParallelRenderer::default()) as Box<dyn Renderer>,
+        RendererOption::Streaming => Box::new(StreamingRenderer::default()) as Box<dyn Renderer>,
     };
 
-    renderer.render(&Composition::from_document(document)).unwrap();
+    let mut composition = if args.no_optimize {
+        Composition::from_unoptimized_document(document)
+    } else {
+        from_document(args, document)
+    }
+    .map_err(|error| vec![error])?;
+
+    if let Some(sample_rate) = args.sample_rate {
+        composition = composition.with_sample_rate(sample_rate);
+    }
+
+    if let Some(bit_depth) = args.bit_depth {
+        composition = composition.with_bit_depth(bit_depth);
+    }
+
+    if args.stdout {
+        renderer.render_to(&composition, &mut PcmSink::stdout())
+    } else {
+        let path = output_path(args, &composition).map_err(|error| vec![error])?;
+
+        info!("Writing to {}", path.display());
+        renderer.render_to(&composition, &mut WavFileSink::new(path).with_overwrite(args.force))
+    }
+    .map_err(|error| vec![error])?;
 
     info!("Rendered!");
+
+    Ok(())
+}
+
+/// The path given with `--output`, or else the expanded `--name` template
+/// in `--out-dir`.
+fn output_path(args: &Args, composition: &Composition) -> Result<PathBuf, MusathError> {
+    if let Some(output) = &args.output {
+        return Ok(output.clone());
+    }
+
+    let spec = composition.spec(DEFAULT_SPEC)?;
+    let file_name = naming::file_name(&args.name, composition, spec, Local::now().naive_local())?;
+
+    match &args.out_dir {
+        Some(out_dir) => {
+            std::fs::create_dir_all(out_dir)?;
+
+            Ok(out_dir.join(file_name))
+        }
+        None => Ok(PathBuf::from(file_name)),
+    }
+}
+
+#[cfg(feature = "jit")]
+fn from_document(args: &Args, document: Document) -> Result<Composition, MusathError> {
+    if args.jit {
+        Composition::from_document_native(document)
+    } else {
+        Composition::from_document(document)
+    }
+}
+
+#[cfg(not(feature = "jit"))]
+fn from_document(_args: &Args, document: Document) -> Result<Composition, MusathError> {
+    Composition::from_document(document)
 }
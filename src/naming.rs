@@ -0,0 +1,112 @@
+//! File names for renders, from templates such as
+//! `{title}-{date}-{sample_rate}.wav`.
+//!
+//! | Placeholder | Replaced with |
+//! |-|-|
+//! | `{title}` | The composition's `TITLE`, or `output` without one |
+//! | `{date}` | The date of the render, as `2024-01-31` |
+//! | `{time}` | The time of the render, as `13-45-00` |
+//! | `{sample_rate}` | The sample rate in Hz |
+//! | `{bit_depth}` | The bits per sample |
+//! | `{channels}` | The number of channels |
+
+use chrono::NaiveDateTime;
+use hound::WavSpec;
+
+use crate::{
+    composition::Composition,
+    error::{ErrorKind, MusathError},
+};
+
+/// Names the file after the composition's title, as renderers always have.
+pub const DEFAULT_TEMPLATE: &str = "{title}.wav";
+
+/// Expands the placeholders of `template` for a render of `composition` in
+/// the format of `spec`, started at `now`.
+pub fn file_name(
+    template: &str,
+    composition: &Composition,
+    spec: WavSpec,
+    now: NaiveDateTime,
+) -> Result<String, MusathError> {
+    let error = |message: String| {
+        MusathError::new(ErrorKind::InvalidTemplate {
+            template: template.to_string(),
+            message,
+        })
+    };
+
+    let mut file_name = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        file_name.push_str(&rest[..start]);
+
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| error("unclosed `{`".to_string()))?;
+
+        match &rest[start + 1..start + end] {
+            "title" => file_name.push_str(composition.title().map_or("output", String::as_str)),
+            "date" => file_name.push_str(&now.format("%Y-%m-%d").to_string()),
+            "time" => file_name.push_str(&now.format("%H-%M-%S").to_string()),
+            "sample_rate" => file_name.push_str(&spec.sample_rate.to_string()),
+            "bit_depth" => file_name.push_str(&spec.bits_per_sample.to_string()),
+            "channels" => file_name.push_str(&spec.channels.to_string()),
+            placeholder => return Err(error(format!("unknown placeholder `{{{}}}`", placeholder))),
+        }
+
+        rest = &rest[start + end + 1..];
+    }
+
+    file_name.push_str(rest);
+
+    Ok(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use hound::SampleFormat;
+
+    use super::*;
+
+    #[test]
+    fn test_file_name() {
+        let composition = Composition::from_function("beat", 1.0, |t: f64| t);
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 48000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let now = NaiveDate::from_ymd_opt(2024, 1, 31)
+            .unwrap()
+            .and_hms_opt(13, 45, 0)
+            .unwrap();
+
+        let name = |template| file_name(template, &composition, spec, now);
+
+        assert_eq!(name(DEFAULT_TEMPLATE).unwrap(), "beat.wav");
+        assert_eq!(
+            name("{title}-{date}-{sample_rate}.wav").unwrap(),
+            "beat-2024-01-31-48000.wav"
+        );
+        assert_eq!(
+            name("{time}_{bit_depth}bit_{channels}ch").unwrap(),
+            "13-45-00_16bit_1ch"
+        );
+
+        assert!(matches!(
+            name("{title").unwrap_err().kind(),
+            ErrorKind::InvalidTemplate { message, .. } if message == "unclosed `{`"
+        ));
+
+        let error = name("{author}.wav").unwrap_err();
+
+        assert!(matches!(
+            error.kind(),
+            ErrorKind::InvalidTemplate { message, .. } if message.contains("`{author}`")
+        ));
+    }
+}
@@ -0,0 +1,342 @@
+//! Simplifies function bodies before rendering.
+//!
+//! Subexpressions that do not depend on any parameter are evaluated once
+//! instead of at every sample: constant arithmetic such as `tau * 440` or
+//! `2^(1/12)`, constants, built-in calls with constant arguments, and calls to
+//! functions without parameters, such as `a()`. Identities like `x * 1`,
+//! `x + 0` and `x^1` are removed.
+//!
+//! Folding keeps the order of operations, so results differ from the
+//! unoptimized ones at most by the sign of a zero.
+
+use std::collections::HashMap;
+
+use crate::{
+    context::Context,
+    expression::{BinaryOperator, Expression, Primary, is_truthy},
+    function::{Function, FunctionBody},
+    validation::BINDING_BUILTINS,
+};
+
+/// Simplifies the body of every user function in `context`.
+pub fn optimize(context: &mut Context) {
+    let optimizer = Optimizer {
+        context: &*context,
+        values: values(context),
+    };
+
+    let functions = context
+        .functions()
+        .values()
+        .filter_map(|function| match function.body() {
+            FunctionBody::Expression(expression) => {
+                let mut scope = function.signature().parameters().clone();
+
+                Some(function.renamed(
+                    function.signature().identifier(),
+                    optimizer.expression(expression, &mut scope),
+                ))
+            }
+            FunctionBody::Closure(_) => None,
+        })
+        .collect::<Vec<Function>>();
+
+    for function in functions {
+        context.set_function(function);
+    }
+}
+
+/// The value of every user function without parameters that evaluates
+/// successfully.
+fn values(context: &Context) -> HashMap<String, f64> {
+    context
+        .functions()
+        .iter()
+        .filter(|(_, function)| {
+            function.signature().parameters().is_empty()
+                && matches!(function.body(), FunctionBody::Expression(_))
+        })
+        .filter_map(|(identifier, function)| {
+            Some((
+                identifier.clone(),
+                function.eval(&[], &mut context.clone()).ok()?,
+            ))
+        })
+        .collect()
+}
+
+struct Optimizer<'a> {
+    context: &'a Context,
+    values: HashMap<String, f64>,
+}
+
+impl Optimizer<'_> {
+    fn expression(&self, expression: &Expression, scope: &mut Vec<String>) -> Expression {
+        match expression {
+            Expression::Binary(left, operator, right) => {
+                let left = self.expression(left, scope);
+                let right = self.expression(right, scope);
+
+                binary(left, operator, right)
+            }
+            Expression::Unary(operator, operand) => match self.expression(operand, scope) {
+                Expression::Primary(Primary::Decimal(value)) => decimal(operator.eval(value)),
+                operand => Expression::Unary(operator.clone(), Box::new(operand)),
+            },
+            Expression::Primary(primary) => self.primary(primary, scope),
+            Expression::Conditional(condition, consequent, alternative) => {
+                match self.expression(condition, scope) {
+                    Expression::Primary(Primary::Decimal(value)) if is_truthy(value) => {
+                        self.expression(consequent, scope)
+                    }
+                    Expression::Primary(Primary::Decimal(_)) => self.expression(alternative, scope),
+                    condition => Expression::Conditional(
+                        Box::new(condition),
+                        Box::new(self.expression(consequent, scope)),
+                        Box::new(self.expression(alternative, scope)),
+                    ),
+                }
+            }
+            Expression::Let(bindings, body) => {
+                let length = scope.len();
+
+                let bindings = bindings
+                    .iter()
+                    .map(|(identifier, expression)| {
+                        let expression = self.expression(expression, scope);
+
+                        scope.push(identifier.clone());
+
+                        (identifier.clone(), expression)
+                    })
+                    .collect();
+
+                let body = self.expression(body, scope);
+
+                scope.truncate(length);
+
+                Expression::Let(bindings, Box::new(body))
+            }
+        }
+    }
+
+    fn primary(&self, primary: &Primary, scope: &mut Vec<String>) -> Expression {
+        match primary {
+            Primary::Decimal(value) => decimal(*value),
+            Primary::Integer(value) => decimal(*value as f64),
+            Primary::Note(note) => decimal(note.frequency(self.context.a4_hz())),
+            Primary::Grouping(expression) => self.expression(expression, scope),
+            Primary::Identifier(identifier, _) if !scope.contains(identifier) => {
+                match self.context.value(identifier) {
+                    Some(value) => decimal(*value),
+                    None => Expression::Primary(primary.clone()),
+                }
+            }
+            Primary::Identifier(..) => Expression::Primary(primary.clone()),
+            Primary::Call(identifier, arguments, span) => {
+                if arguments.is_empty()
+                    && let Some(value) = self.values.get(identifier)
+                {
+                    return decimal(*value);
+                }
+
+                let length = scope.len();
+
+                let arguments = match arguments.as_slice() {
+                    [variable, start, end, body]
+                        if BINDING_BUILTINS.contains(&identifier.as_str()) =>
+                    {
+                        let start = self.expression(start, scope);
+                        let end = self.expression(end, scope);
+
+                        // The loop variable is bound, not evaluated.
+                        if let Expression::Primary(Primary::Identifier(variable, _)) =
+                            variable.as_ref()
+                        {
+                            scope.push(variable.clone());
+                        }
+
+                        let body = self.expression(body, scope);
+
+                        scope.truncate(length);
+
+                        return Expression::Primary(Primary::Call(
+                            identifier.clone(),
+                            vec![
+                                variable.clone(),
+                                Box::new(start),
+                                Box::new(end),
+                                Box::new(body),
+                            ],
+                            *span,
+                        ));
+                    }
+                    arguments => arguments
+                        .iter()
+                        .map(|argument| Box::new(self.expression(argument, scope)))
+                        .collect::<Vec<_>>(),
+                };
+
+                let constant = arguments.iter().all(|argument| {
+                    matches!(argument.as_ref(), Expression::Primary(Primary::Decimal(_)))
+                });
+
+                let call = Expression::Primary(Primary::Call(identifier.clone(), arguments, *span));
+
+                match self.context.function(identifier) {
+                    Some(function)
+                        if constant && matches!(function.body(), FunctionBody::Closure(_)) =>
+                    {
+                        call.eval(&mut self.context.clone())
+                            .map(decimal)
+                            .unwrap_or(call)
+                    }
+                    _ => call,
+                }
+            }
+        }
+    }
+}
+
+fn binary(left: Expression, operator: &BinaryOperator, right: Expression) -> Expression {
+    let value = |expression: &Expression| match expression {
+        Expression::Primary(Primary::Decimal(value)) => Some(*value),
+        _ => None,
+    };
+
+    match (value(&left), operator, value(&right)) {
+        (Some(left), operator, Some(right)) => decimal(operator.eval(left, right)),
+        (Some(left), BinaryOperator::And, _) if !is_truthy(left) => decimal(0.0),
+        (Some(left), BinaryOperator::Or, _) if is_truthy(left) => decimal(1.0),
+        (_, BinaryOperator::Multiply | BinaryOperator::Divide, Some(1.0))
+        | (_, BinaryOperator::Add | BinaryOperator::Subtract, Some(0.0))
+        | (_, BinaryOperator::Exponentiate, Some(1.0)) => left,
+        (Some(1.0), BinaryOperator::Multiply, _) | (Some(0.0), BinaryOperator::Add, _) => right,
+        _ => Expression::Binary(Box::new(left), operator.clone(), Box::new(right)),
+    }
+}
+
+fn decimal(value: f64) -> Expression {
+    Expression::Primary(Primary::Decimal(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::TAU;
+
+    use crate::document::Document;
+
+    use super::*;
+
+    fn body<'a>(context: &'a Context, identifier: &str) -> &'a FunctionBody {
+        context.function(identifier).unwrap().body()
+    }
+
+    #[test]
+    fn test_optimize() {
+        let document = Document::from_source(
+            "
+            const base = 220
+            a() = 2^(1/12)
+            b(t) = if base > 100 then abs(-1/4) else t
+            output(t) = sin(tau * 440 * t) * 1 + 0 + a() * t^1 + b(t)
+            ",
+        )
+        .unwrap();
+
+        let mut context = document.body().context().clone();
+
+        optimize(&mut context);
+
+        let expected = Document::from_source(&format!(
+            "output(t) = sin({} * t) + {} * t + b(t)",
+            TAU * 440.0,
+            2f64.powf(1.0 / 12.0),
+        ))
+        .unwrap();
+
+        let FunctionBody::Expression(expected) = body(expected.body().context(), "output") else {
+            unreachable!();
+        };
+
+        assert_eq!(
+            body(&context, "b"),
+            &FunctionBody::Expression(Expression::Primary(Primary::Decimal(0.25)))
+        );
+
+        let FunctionBody::Expression(output) = body(&context, "output") else {
+            unreachable!();
+        };
+
+        assert_eq!(
+            output.without_spans(),
+            Optimizer {
+                context: &Context::default(),
+                values: HashMap::new(),
+            }
+            .expression(expected, &mut vec![String::from("t")])
+            .without_spans()
+        );
+    }
+
+    #[test]
+    fn test_optimize_scopes() {
+        let mut document = Document::from_source(
+            "
+            const x = 2
+            f(x) = x + 1
+            g(t) = let x = t in x * x
+            output(t) = f(t) + g(t) + sum(x, 0, x, x * t)
+            ",
+        )
+        .unwrap();
+
+        let expected = (0..10)
+            .map(|t| document.eval(t as f64).unwrap())
+            .collect::<Vec<_>>();
+
+        document.optimize();
+
+        for (t, expected) in expected.into_iter().enumerate() {
+            assert_eq!(document.eval(t as f64).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_optimize_examples() {
+        for path in [
+            "delay.mth",
+            "harmonics.mth",
+            "noise.mth",
+            "organ.mth",
+            "pulse.mth",
+            "test.mth",
+        ] {
+            let source = std::fs::read_to_string(path).unwrap();
+            let document = Document::from_source_at(&source, path).unwrap();
+            let mut optimized = Document::from_source_at(&source, path).unwrap();
+
+            optimized.optimize();
+
+            assert!(optimized.validate().is_empty());
+
+            for index in 0..200 {
+                let t = index as f64 * 0.0123;
+
+                for channel in 0..document.channels() {
+                    let expected = document.eval_channel(t, channel).unwrap();
+                    let found = optimized.eval_channel(t, channel).unwrap();
+
+                    assert!(
+                        (found - expected).abs() <= 1e-12 * expected.abs().max(1.0),
+                        "{} at t = {}: expected {}, found {}",
+                        path,
+                        t,
+                        expected,
+                        found
+                    );
+                }
+            }
+        }
+    }
+}
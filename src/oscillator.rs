@@ -0,0 +1,186 @@
+//! Band-limited oscillators, which sound like their naive counterparts such
+//! as `2 * (440 * t % 1) - 1` without the aliasing those produce at high
+//! frequencies.
+//!
+//! Each waveform is computed from its phase like the naive one, then
+//! corrected around its discontinuities with a polynomial approximation of a
+//! band-limited step (PolyBLEP) or, for the corners of the triangle, of its
+//! integral (PolyBLAMP). The corrections span one sample on either side, so
+//! they depend on the sample rate of the render, which documents pass as the
+//! `sample_rate` of their [`Context`](crate::context::Context).
+//!
+//! The oscillators are stateless, so any time can be evaluated in any order.
+//! Negative frequencies play the waveforms backwards.
+
+/// The phase of a `freq` Hz oscillator at time `t`, between 0 and 1.
+fn phase(freq: f64, t: f64) -> f64 {
+    (freq * t).rem_euclid(1.0)
+}
+
+/// The duration of a sample as a fraction of a period, limited so the
+/// corrections of the two edges of a period do not overlap.
+fn increment(freq: f64, sample_rate: f64) -> f64 {
+    (freq / sample_rate).min(0.5)
+}
+
+/// The difference between a band-limited step from -1 to 1 at phase 0 and
+/// the naive one, at `phase`.
+fn poly_blep(phase: f64, increment: f64) -> f64 {
+    if phase < increment {
+        let x = phase / increment;
+
+        x + x - x * x - 1.0
+    } else if phase > 1.0 - increment {
+        let x = (phase - 1.0) / increment;
+
+        x * x + x + x + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// The difference between a band-limited corner at phase 0, where the slope
+/// increases by one per sample, and the naive one, at `phase`.
+fn poly_blamp(phase: f64, increment: f64) -> f64 {
+    let x = if phase < increment {
+        1.0 - phase / increment
+    } else if phase > 1.0 - increment {
+        1.0 + (phase - 1.0) / increment
+    } else {
+        return 0.0;
+    };
+
+    x * x * x / 6.0
+}
+
+/// A sawtooth wave rising from -1 to 1 over each period.
+pub fn saw(freq: f64, t: f64, sample_rate: f64) -> f64 {
+    if freq < 0.0 {
+        return -saw(-freq, t, sample_rate);
+    }
+
+    let phase = phase(freq, t);
+
+    2.0 * phase - 1.0 - poly_blep(phase, increment(freq, sample_rate))
+}
+
+/// A square wave that is 1 for the first `width` of each period, between 0
+/// and 1, and -1 for the rest.
+pub fn square(freq: f64, t: f64, width: f64, sample_rate: f64) -> f64 {
+    let width = width.clamp(0.0, 1.0);
+
+    if freq < 0.0 {
+        return -square(-freq, t, 1.0 - width, sample_rate);
+    }
+
+    let phase = phase(freq, t);
+    let increment = increment(freq, sample_rate);
+    let naive = if phase < width { 1.0 } else { -1.0 };
+
+    naive + poly_blep(phase, increment) - poly_blep((phase - width).rem_euclid(1.0), increment)
+}
+
+/// A triangle wave in phase with a sine, rising from 0 to 1, falling to -1
+/// and rising back to 0 over each period.
+pub fn tri(freq: f64, t: f64, sample_rate: f64) -> f64 {
+    if freq < 0.0 {
+        return -tri(-freq, t, sample_rate);
+    }
+
+    let phase = (phase(freq, t) + 0.25).rem_euclid(1.0);
+    let increment = increment(freq, sample_rate);
+
+    // The slope changes by 8 per period, from -4 to 4 at the trough and back
+    // at the peak.
+    1.0 - 4.0 * (phase - 0.5).abs()
+        + 8.0
+            * increment
+            * (poly_blamp(phase, increment) - poly_blamp((phase + 0.5).rem_euclid(1.0), increment))
+}
+
+/// A [`square`] wave scaled to be 1 for the first `width` of each period and
+/// 0 for the rest, for gating other signals.
+pub fn pulse(freq: f64, t: f64, width: f64, sample_rate: f64) -> f64 {
+    (square(freq, t, width, sample_rate) + 1.0) / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 44100.0;
+
+    #[test]
+    fn test_naive_between_discontinuities() {
+        assert_eq!(saw(1.0, 0.25, SAMPLE_RATE), -0.5);
+        assert_eq!(saw(-1.0, 0.25, SAMPLE_RATE), 0.5);
+        assert_eq!(square(1.0, 0.25, 0.5, SAMPLE_RATE), 1.0);
+        assert_eq!(square(1.0, 0.25, 0.2, SAMPLE_RATE), -1.0);
+        assert_eq!(tri(1.0, 0.125, SAMPLE_RATE), 0.5);
+        assert_eq!(tri(1.0, 0.625, SAMPLE_RATE), -0.5);
+        assert_eq!(tri(-1.0, 0.125, SAMPLE_RATE), -0.5);
+        assert_eq!(pulse(1.0, 0.75, 0.5, SAMPLE_RATE), 0.0);
+        assert_eq!(pulse(1.0, -0.75, 0.5, SAMPLE_RATE), 1.0);
+    }
+
+    #[test]
+    fn test_discontinuities() {
+        // Halfway through a jump, the waves are halfway between either side.
+        assert_eq!(saw(100.0, 0.0, SAMPLE_RATE), 0.0);
+        assert_eq!(square(100.0, 0.0, 0.5, SAMPLE_RATE), 0.0);
+        assert_eq!(square(100.0, 0.005, 0.5, SAMPLE_RATE), 0.0);
+
+        // The samples either side of a jump are pulled towards each other.
+        let before = saw(100.0, -0.5 / SAMPLE_RATE, SAMPLE_RATE);
+        let after = saw(100.0, 0.5 / SAMPLE_RATE, SAMPLE_RATE);
+
+        assert!(before < 1.0 && before > 0.0);
+        assert!(after > -1.0 && after < 0.0);
+        assert!((before + after).abs() < 1e-12);
+
+        // Corners are rounded off.
+        assert!(tri(100.0, 0.0025, SAMPLE_RATE) < 1.0);
+        assert!(tri(100.0, 0.0075, SAMPLE_RATE) > -1.0);
+    }
+
+    #[test]
+    fn test_aliasing() {
+        // A 5 kHz saw sampled at 44.1 kHz folds its harmonics back below it.
+        // The band-limited one has much less energy at the folded 4.1 kHz
+        // alias of its eighth harmonic than the naive one.
+        let naive = |t: f64| 2.0 * phase(5000.0, t) - 1.0;
+        let band_limited = |t: f64| saw(5000.0, t, SAMPLE_RATE);
+
+        let amplitude = |wave: &dyn Fn(f64) -> f64| {
+            let (mut re, mut im) = (0.0, 0.0);
+
+            for sample in 0..44100 {
+                let t = sample as f64 / SAMPLE_RATE;
+                let angle = std::f64::consts::TAU * 4100.0 * t;
+
+                re += wave(t) * angle.cos();
+                im += wave(t) * angle.sin();
+            }
+
+            re.hypot(im) / 44100.0
+        };
+
+        assert!(amplitude(&band_limited) < amplitude(&naive) / 4.0);
+    }
+
+    #[test]
+    fn test_bounded() {
+        for sample in 0..1000 {
+            let t = sample as f64 / SAMPLE_RATE;
+
+            for value in [
+                saw(3000.0, t, SAMPLE_RATE),
+                square(3000.0, t, 0.3, SAMPLE_RATE),
+                tri(3000.0, t, SAMPLE_RATE),
+                2.0 * pulse(3000.0, t, 0.7, SAMPLE_RATE) - 1.0,
+            ] {
+                assert!(value.abs() <= 1.0 + 1e-9, "{} is out of range", value);
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Musical pitch: note names, MIDI note numbers and frequencies.
+//!
+//! Notes are numbered as in MIDI, by semitone from 0 for C-1, so that middle
+//! C, `C4`, is 60 and `A4` is 69. Frequencies are in equal temperament
+//! relative to the frequency of `A4`, which documents set with the `A4_HZ`
+//! header key and which is [`A4_HZ`] otherwise.
+
+use std::fmt::{self, Display};
+
+/// The usual frequency of `A4` in Hz.
+pub const A4_HZ: f64 = 440.0;
+
+/// The MIDI note number of `A4`.
+pub const A4: f64 = 69.0;
+
+/// A note name such as `A4`, `C#3` or `Eb2`: a letter from `A` to `G`,
+/// optionally raised a semitone by `#` or lowered by `b`, and an octave from
+/// 0 to 9. Octaves start at C, so `B3` is a semitone below `C4`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Note {
+    letter: char,
+    accidental: i64,
+    octave: i64,
+}
+
+impl Note {
+    /// The note called `name`, if it is one.
+    pub fn parse(name: &str) -> Option<Self> {
+        let mut characters = name.chars();
+
+        let letter = characters
+            .next()
+            .filter(|letter| ('A'..='G').contains(letter))?;
+
+        let (accidental, octave) = match (characters.next()?, characters.next()) {
+            ('#', octave) => (1, octave?),
+            ('b', octave) => (-1, octave?),
+            (octave, None) => (0, octave),
+            _ => return None,
+        };
+
+        if characters.next().is_some() {
+            return None;
+        }
+
+        Some(Self {
+            letter,
+            accidental,
+            octave: octave.to_digit(10)?.into(),
+        })
+    }
+
+    /// The MIDI note number.
+    pub fn number(&self) -> i64 {
+        let semitone = match self.letter {
+            'C' => 0,
+            'D' => 2,
+            'E' => 4,
+            'F' => 5,
+            'G' => 7,
+            'A' => 9,
+            'B' => 11,
+            letter => unreachable!("expected a letter from A to G, found {}", letter),
+        };
+
+        (self.octave + 1) * 12 + semitone + self.accidental
+    }
+
+    /// The frequency in Hz, with `A4` at `a4_hz`.
+    pub fn frequency(&self, a4_hz: f64) -> f64 {
+        mtof(self.number() as f64, a4_hz)
+    }
+}
+
+/// Writes the note name, such as `C#3`.
+impl Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let accidental = match self.accidental {
+            1 => "#",
+            -1 => "b",
+            _ => "",
+        };
+
+        write!(f, "{}{}{}", self.letter, accidental, self.octave)
+    }
+}
+
+/// The frequency in Hz of the MIDI note `note`, which may be fractional, with
+/// `A4` at `a4_hz`.
+pub fn mtof(note: f64, a4_hz: f64) -> f64 {
+    semitones(a4_hz, note - A4)
+}
+
+/// The MIDI note, possibly fractional, with frequency `hz`, with `A4` at
+/// `a4_hz`.
+pub fn ftom(hz: f64, a4_hz: f64) -> f64 {
+    A4 + 12.0 * (hz / a4_hz).log2()
+}
+
+/// `hz` raised by `n` equal-tempered semitones, or lowered if `n` is
+/// negative.
+pub fn semitones(hz: f64, n: f64) -> f64 {
+    hz * 2.0f64.powf(n / 12.0)
+}
+
+/// `hz` raised by `c` cents, hundredths of a semitone, or lowered if `c` is
+/// negative.
+pub fn cents(hz: f64, c: f64) -> f64 {
+    hz * 2.0f64.powf(c / 1200.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_note() {
+        for (name, number) in [("A4", 69), ("C4", 60), ("C#3", 49), ("Eb2", 39), ("B3", 59)] {
+            let note = Note::parse(name).unwrap();
+
+            assert_eq!(note.number(), number);
+            assert_eq!(note.to_string(), name);
+        }
+
+        assert_eq!(Note::parse("Cb4").unwrap().number(), 59);
+        assert_eq!(Note::parse("C0").unwrap().number(), 12);
+        assert_eq!(Note::parse("G9").unwrap().number(), 127);
+
+        for name in ["H4", "a4", "A", "A#", "A10", "Ax4", "A4b", ""] {
+            assert_eq!(Note::parse(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_frequency() {
+        assert_eq!(Note::parse("A4").unwrap().frequency(A4_HZ), 440.0);
+        assert_eq!(Note::parse("A5").unwrap().frequency(A4_HZ), 880.0);
+        assert_eq!(Note::parse("A4").unwrap().frequency(432.0), 432.0);
+        assert!((Note::parse("C4").unwrap().frequency(A4_HZ) - 261.6256).abs() < 1e-4);
+
+        assert_eq!(mtof(57.0, A4_HZ), 220.0);
+        assert_eq!(ftom(880.0, A4_HZ), 81.0);
+        assert!((ftom(mtof(61.5, 415.0), 415.0) - 61.5).abs() < 1e-12);
+
+        assert_eq!(semitones(440.0, 12.0), 880.0);
+        assert_eq!(semitones(440.0, -24.0), 110.0);
+        assert_eq!(cents(440.0, 1200.0), 880.0);
+        assert!((cents(440.0, 100.0) - semitones(440.0, 1.0)).abs() < 1e-12);
+    }
+}
@@ -1,8 +1,158 @@
-use crate::composition::Composition;
+use std::io::{Seek, Write};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::{
+    composition::Composition, error::MusathError, wave_provider::MultichannelWaveProvider,
+};
+
+use sink::{AudioSink, BufferSink, WavFileSink};
 
 pub mod parallel_renderer;
 pub mod serial_renderer;
+pub mod sink;
+pub mod streaming_renderer;
 
 pub trait Renderer {
-    fn render(&self, composition: &Composition) -> Result<(), hound::Error>;
+    /// Renders `composition` through `sink`.
+    fn render_to(
+        &self,
+        composition: &Composition,
+        sink: &mut dyn AudioSink,
+    ) -> Result<(), MusathError>;
+
+    /// Renders `composition` to `{title}.wav` in the current directory, or
+    /// `output.wav` if it has no title.
+    fn render(&self, composition: &Composition) -> Result<(), MusathError> {
+        let title = composition.title().map(String::as_str).unwrap_or("output");
+
+        self.render_to(composition, &mut WavFileSink::new(format!("{}.wav", title)))
+    }
+
+    /// Renders `composition` into memory, interleaved one sample per channel
+    /// and neither clipped nor quantized.
+    fn render_to_buffer(&self, composition: &Composition) -> Result<Vec<f32>, MusathError> {
+        let mut sink = BufferSink::new();
+
+        self.render_to(composition, &mut sink)?;
+
+        Ok(sink.into_samples())
+    }
+}
+
+/// The format renderers use for anything a composition leaves unspecified.
+pub const DEFAULT_SPEC: WavSpec = WavSpec {
+    channels: 1,
+    sample_rate: 44100,
+    bits_per_sample: 32,
+    sample_format: SampleFormat::Float,
+};
+
+/// The number of frames renderers ask a wave provider for at a time.
+pub const BLOCK_SIZE: usize = 1024;
+
+/// Fills `out` with interleaved frames, one sample per channel, starting
+/// with frame number `start_frame`. Each channel is computed as one block.
+pub fn render_block(
+    wave_provider: &dyn MultichannelWaveProvider,
+    start_frame: u64,
+    sample_rate: u32,
+    out: &mut [f64],
+) -> Result<(), MusathError> {
+    let channels = wave_provider.channels() as usize;
+
+    let mut block = vec![0.0; out.len() / channels];
+
+    for channel in 0..channels {
+        wave_provider.try_fill_block(start_frame, sample_rate, channel as u16, &mut block)?;
+
+        for (sample, value) in out.iter_mut().skip(channel).step_by(channels).zip(&block) {
+            *sample = *value;
+        }
+    }
+
+    Ok(())
+}
+
+/// Like [`render_block`], but stores the samples as `f32`s, which is precise
+/// enough for any output format and halves the memory of a whole render.
+pub fn render_block_f32(
+    wave_provider: &dyn MultichannelWaveProvider,
+    start_frame: u64,
+    sample_rate: u32,
+    out: &mut [f32],
+) -> Result<(), MusathError> {
+    let mut block = vec![0.0; out.len()];
+
+    render_block(wave_provider, start_frame, sample_rate, &mut block)?;
+
+    for (sample, value) in out.iter_mut().zip(block) {
+        *sample = value as f32;
+    }
+
+    Ok(())
+}
+
+/// Writes the interleaved samples of `mix` through `sink` a block at a time.
+pub fn write_mix(
+    sink: &mut dyn AudioSink,
+    mix: &[f32],
+    channels: usize,
+) -> Result<(), MusathError> {
+    for samples in mix.chunks(BLOCK_SIZE * channels) {
+        let samples = samples.iter().map(|sample| *sample as f64).collect::<Vec<_>>();
+
+        sink.write(&samples)?;
+    }
+
+    Ok(())
+}
+
+/// Writes one sample in the format of `spec`.
+pub fn write_sample<W: Write + Seek>(
+    writer: &mut WavWriter<W>,
+    spec: WavSpec,
+    value: f64,
+) -> Result<(), MusathError> {
+    match spec.sample_format {
+        SampleFormat::Float => writer.write_sample(value as f32)?,
+        SampleFormat::Int => writer.write_sample(quantize(value, spec.bits_per_sample))?,
+    }
+
+    Ok(())
+}
+
+/// Converts `value` to a signed integer sample of `bits_per_sample` bits,
+/// clipping anything outside `-1.0..=1.0`.
+pub fn quantize(value: f64, bits_per_sample: u16) -> i32 {
+    let max = ((1i64 << (bits_per_sample - 1)) - 1) as f64;
+
+    (value.clamp(-1.0, 1.0) * max).round() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_block() {
+        let wave_provider = [|t: f64| t, |t: f64| -t];
+
+        let mut out = vec![0.0; 6];
+
+        render_block(&wave_provider, 2, 4, &mut out).unwrap();
+
+        assert_eq!(out, [0.5, -0.5, 0.75, -0.75, 1.0, -1.0]);
+    }
+
+    #[test]
+    fn test_quantize() {
+        assert_eq!(quantize(0.0, 16), 0);
+        assert_eq!(quantize(1.0, 16), 32767);
+        assert_eq!(quantize(-1.0, 16), -32767);
+        assert_eq!(quantize(0.5, 24), 4194304);
+        assert_eq!(quantize(3.0, 24), 8388607);
+        assert_eq!(quantize(-3.0, 32), -2147483647);
+        assert_eq!(quantize(f64::NAN, 16), 0);
+    }
 }
@@ -1,10 +1,14 @@
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use hound::{WavSpec, WavWriter};
+use hound::WavSpec;
 use rayon::prelude::*;
 use tracing::debug;
 
-use crate::{composition::Composition, renderer::Renderer};
+use crate::{
+    composition::Composition,
+    error::MusathError,
+    renderer::{BLOCK_SIZE, DEFAULT_SPEC, Renderer, render_block_f32, sink::AudioSink, write_mix},
+};
 
 
 
@@ -20,59 +24,57 @@ impl ParallelRenderer {
 
 impl Default for ParallelRenderer {
     fn default() -> Self {
-        Self::new(WavSpec {
-            channels: 1,
-            sample_rate: 44100,
-            bits_per_sample: 32,
-            sample_format: hound::SampleFormat::Float,
-        })
+        Self::new(DEFAULT_SPEC)
     }
 }
 
 impl Renderer for ParallelRenderer {
-    fn render(&self, composition: &Composition) -> Result<(), hound::Error> {
+    fn render_to(
+        &self,
+        composition: &Composition,
+        sink: &mut dyn AudioSink,
+    ) -> Result<(), MusathError> {
         debug!("creating spec");
 
-        let title = composition.title().map(String::as_str).unwrap_or("output");
+        let spec = composition.spec(self.spec)?;
 
         let duration_seconds = composition.duration().unwrap_or(10.0);
 
         debug!("calculating total samples");
-        let total_samples =
-            (self.spec.channels as f64 * duration_seconds * self.spec.sample_rate as f64).ceil()
-                as usize;
+        let channels = spec.channels as usize;
+        let total_samples = (duration_seconds * spec.sample_rate as f64).ceil() as usize * channels;
+
+        let samples_completed = AtomicUsize::new(0);
 
         debug!("allocating samples vector");
         let mut mix = vec![0.0f32; total_samples];
 
-        let samples_completed = Arc::new(Mutex::new(0));
+        sink.begin(spec)?;
 
         debug!("rendering");
-        (0..total_samples)
-            .into_par_iter()
-            .map(|i| {
-                let t = i as f64 / self.spec.sample_rate as f64;
-
-                let value = composition.wave_provider().value_at_time(t) as f32;
+        mix.par_chunks_mut(BLOCK_SIZE * channels)
+            .enumerate()
+            .try_for_each(|(i, block)| {
+                let start_frame = (i * BLOCK_SIZE) as u64;
 
-                let mut lock = samples_completed.lock().unwrap();
-                *lock += 1;
-                debug!("{}/{}", *lock, total_samples);
+                render_block_f32(
+                    composition.wave_provider(),
+                    start_frame,
+                    spec.sample_rate,
+                    block,
+                )?;
 
-                value
-            })
-            .collect_into_vec(&mut mix);
+                let completed = samples_completed.fetch_add(block.len(), Ordering::Relaxed);
+                debug!("{}/{}", completed + block.len(), total_samples);
 
-        debug!("creating writer");
-        let mut writer = WavWriter::create(format!("{}.wav", title), self.spec)?;
+                Ok::<(), MusathError>(())
+            })?;
 
         debug!("writing samples");
-        for sample in mix {
-            writer.write_sample(sample)?;
-        }
+        write_mix(sink, &mix, channels)?;
 
-        debug!("finalizing writer");
-        writer.finalize()?;
+        debug!("finishing sink");
+        sink.finish()?;
 
         Ok(())
     }
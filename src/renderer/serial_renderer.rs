@@ -1,7 +1,11 @@
-use hound::{WavSpec, WavWriter};
+use hound::WavSpec;
 use tracing::debug;
 
-use crate::{composition::Composition, document::Document, renderer::Renderer};
+use crate::{
+    composition::Composition,
+    error::MusathError,
+    renderer::{BLOCK_SIZE, DEFAULT_SPEC, Renderer, render_block_f32, sink::AudioSink, write_mix},
+};
 
 pub struct SerialRenderer {
     spec: WavSpec,
@@ -15,52 +19,54 @@ impl SerialRenderer {
 
 impl Default for SerialRenderer {
     fn default() -> Self {
-        Self::new(WavSpec {
-            channels: 1,
-            sample_rate: 44100,
-            bits_per_sample: 32,
-            sample_format: hound::SampleFormat::Float,
-        })
+        Self::new(DEFAULT_SPEC)
     }
 }
 
 impl Renderer for SerialRenderer {
-    fn render(&self, composition: &Composition) -> Result<(), hound::Error> {
+    fn render_to(
+        &self,
+        composition: &Composition,
+        sink: &mut dyn AudioSink,
+    ) -> Result<(), MusathError> {
         debug!("creating spec");
 
-        let title = composition.title().map(String::as_str).unwrap_or("output");
+        let spec = composition.spec(self.spec)?;
 
         let duration_seconds = composition.duration().unwrap_or(10.0);
 
         debug!("calculating total samples");
-        let total_samples =
-            (self.spec.channels as f64 * duration_seconds * self.spec.sample_rate as f64).ceil()
-                as usize;
+        let channels = spec.channels as usize;
+        let total_samples = (duration_seconds * spec.sample_rate as f64).ceil() as usize * channels;
 
         debug!("allocating samples vector");
         let mut mix = vec![0.0f32; total_samples];
 
-        debug!("rendering");
-        for i in 0..total_samples {
-            let t = i as f64 / self.spec.sample_rate as f64;
-
-            let value = composition.wave_provider().value_at_time(t) as f32;
-
-            debug!("{}/{}", i, total_samples);
+        sink.begin(spec)?;
 
-            mix[i] = value;
+        debug!("rendering");
+        for (i, block) in mix.chunks_mut(BLOCK_SIZE * channels).enumerate() {
+            let start_frame = (i * BLOCK_SIZE) as u64;
+
+            render_block_f32(
+                composition.wave_provider(),
+                start_frame,
+                spec.sample_rate,
+                block,
+            )?;
+
+            debug!(
+                "{}/{}",
+                i * BLOCK_SIZE * channels + block.len(),
+                total_samples
+            );
         }
 
-        debug!("creating writer");
-        let mut writer = WavWriter::create(format!("{}.wav", title), self.spec)?;
-
         debug!("writing samples");
-        for sample in mix {
-            writer.write_sample(sample)?;
-        }
+        write_mix(sink, &mix, channels)?;
 
-        debug!("finalizing writer");
-        writer.finalize()?;
+        debug!("finishing sink");
+        sink.finish()?;
 
         Ok(())
     }
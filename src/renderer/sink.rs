@@ -0,0 +1,375 @@
+//! Destinations for rendered samples.
+//!
+//! A [`Renderer`](super::Renderer) resolves the format of a composition,
+//! passes it to [`AudioSink::begin`], writes the samples through
+//! [`AudioSink::write`] in order, possibly over several calls, and calls
+//! [`AudioSink::finish`] once they are all written.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, Stdout, Write},
+    path::PathBuf,
+};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::{
+    error::{ErrorKind, MusathError},
+    renderer::{quantize, write_sample},
+};
+
+/// Receives the samples of a render, interleaved one per channel.
+pub trait AudioSink {
+    /// Prepares to receive samples in the format of `spec`.
+    fn begin(&mut self, spec: WavSpec) -> Result<(), MusathError>;
+
+    /// Receives the next samples, interleaved one per channel.
+    fn write(&mut self, samples: &[f64]) -> Result<(), MusathError>;
+
+    /// Completes the output once every sample has been written.
+    fn finish(&mut self) -> Result<(), MusathError>;
+}
+
+enum WavState<W: Write + Seek> {
+    Ready(W),
+    Writing(WavWriter<W>, WavSpec),
+    Finished,
+}
+
+/// Writes a WAV file into any seekable writer, such as a
+/// `Cursor<Vec<u8>>`.
+pub struct WavSink<W: Write + Seek> {
+    state: WavState<W>,
+}
+
+impl<W: Write + Seek> WavSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            state: WavState::Ready(writer),
+        }
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavSink<W> {
+    fn begin(&mut self, spec: WavSpec) -> Result<(), MusathError> {
+        let WavState::Ready(writer) = std::mem::replace(&mut self.state, WavState::Finished) else {
+            panic!("expected a sink that has not begun");
+        };
+
+        self.state = WavState::Writing(WavWriter::new(writer, spec)?, spec);
+
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[f64]) -> Result<(), MusathError> {
+        let WavState::Writing(writer, spec) = &mut self.state else {
+            panic!("expected a sink that has begun");
+        };
+
+        for sample in samples {
+            write_sample(writer, *spec, *sample)?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), MusathError> {
+        match std::mem::replace(&mut self.state, WavState::Finished) {
+            WavState::Writing(writer, _) => Ok(writer.finalize()?),
+            _ => panic!("expected a sink that has begun"),
+        }
+    }
+}
+
+/// Writes a WAV file at a path, created once the format is known.
+pub struct WavFileSink {
+    path: PathBuf,
+    overwrite: bool,
+    sink: Option<WavSink<BufWriter<File>>>,
+}
+
+impl WavFileSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            overwrite: true,
+            sink: None,
+        }
+    }
+
+    /// Fails with [`ErrorKind::OutputExists`] instead of replacing an
+    /// existing file if `overwrite` is false.
+    pub fn with_overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+}
+
+impl AudioSink for WavFileSink {
+    fn begin(&mut self, spec: WavSpec) -> Result<(), MusathError> {
+        let file = if self.overwrite {
+            File::create(&self.path)?
+        } else {
+            File::create_new(&self.path).map_err(|error| match error.kind() {
+                io::ErrorKind::AlreadyExists => {
+                    MusathError::new(ErrorKind::OutputExists(self.path.display().to_string()))
+                }
+                _ => error.into(),
+            })?
+        };
+
+        let mut sink = WavSink::new(BufWriter::new(file));
+
+        sink.begin(spec)?;
+        self.sink = Some(sink);
+
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[f64]) -> Result<(), MusathError> {
+        self.sink
+            .as_mut()
+            .expect("expected a sink that has begun")
+            .write(samples)
+    }
+
+    fn finish(&mut self) -> Result<(), MusathError> {
+        self.sink
+            .take()
+            .expect("expected a sink that has begun")
+            .finish()
+    }
+}
+
+/// Writes headerless little-endian samples in the format of the render, for
+/// piping into tools such as `aplay` or `ffmpeg`.
+pub struct PcmSink<W: Write> {
+    writer: W,
+    spec: Option<WavSpec>,
+}
+
+impl<W: Write> PcmSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, spec: None }
+    }
+}
+
+impl PcmSink<BufWriter<Stdout>> {
+    /// Writes to standard output.
+    pub fn stdout() -> Self {
+        Self::new(BufWriter::new(io::stdout()))
+    }
+}
+
+impl<W: Write> AudioSink for PcmSink<W> {
+    fn begin(&mut self, spec: WavSpec) -> Result<(), MusathError> {
+        self.spec = Some(spec);
+
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[f64]) -> Result<(), MusathError> {
+        let spec = self.spec.expect("expected a sink that has begun");
+
+        for sample in samples {
+            match spec.sample_format {
+                SampleFormat::Float => self.writer.write_all(&(*sample as f32).to_le_bytes())?,
+                SampleFormat::Int => {
+                    let bytes = quantize(*sample, spec.bits_per_sample).to_le_bytes();
+
+                    self.writer
+                        .write_all(&bytes[..spec.bits_per_sample as usize / 8])?
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), MusathError> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+/// Keeps the samples as `f32`s, without clipping or quantizing them.
+#[derive(Debug, Default)]
+pub struct BufferSink {
+    samples: Vec<f32>,
+}
+
+impl BufferSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn into_samples(self) -> Vec<f32> {
+        self.samples
+    }
+}
+
+impl AudioSink for BufferSink {
+    fn begin(&mut self, _spec: WavSpec) -> Result<(), MusathError> {
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[f64]) -> Result<(), MusathError> {
+        self.samples
+            .extend(samples.iter().map(|sample| *sample as f32));
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), MusathError> {
+        Ok(())
+    }
+}
+
+/// Discards the samples, counting them, to measure rendering on its own.
+#[derive(Debug, Default)]
+pub struct NullSink {
+    samples: usize,
+}
+
+impl NullSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of samples written so far.
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+}
+
+impl AudioSink for NullSink {
+    fn begin(&mut self, _spec: WavSpec) -> Result<(), MusathError> {
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[f64]) -> Result<(), MusathError> {
+        self.samples += samples.len();
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), MusathError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use hound::WavReader;
+
+    use super::*;
+
+    const SPEC: WavSpec = WavSpec {
+        channels: 2,
+        sample_rate: 8000,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+
+    fn write(sink: &mut impl AudioSink, spec: WavSpec) {
+        sink.begin(spec).unwrap();
+        sink.write(&[0.0, 0.5]).unwrap();
+        sink.write(&[-1.0, 2.0]).unwrap();
+        sink.finish().unwrap();
+    }
+
+    #[test]
+    fn test_wav_sink() {
+        let mut buffer = Cursor::new(Vec::new());
+
+        write(&mut WavSink::new(&mut buffer), SPEC);
+
+        buffer.set_position(0);
+
+        let reader = WavReader::new(buffer).unwrap();
+
+        assert_eq!(reader.spec(), SPEC);
+        assert_eq!(
+            reader
+                .into_samples::<i16>()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            [0, 16384, -32767, 32767]
+        );
+    }
+
+    #[test]
+    fn test_wav_file_sink() {
+        let path = std::env::temp_dir().join(format!("musath-sink-{}.wav", std::process::id()));
+
+        write(&mut WavFileSink::new(&path), SPEC);
+
+        let error = WavFileSink::new(&path)
+            .with_overwrite(false)
+            .begin(SPEC)
+            .unwrap_err();
+
+        assert!(matches!(error.kind(), ErrorKind::OutputExists(_)));
+
+        write(&mut WavFileSink::new(&path), SPEC);
+
+        assert_eq!(WavReader::open(&path).unwrap().len(), 4);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pcm_sink() {
+        let mut sink = PcmSink::new(Vec::new());
+
+        write(&mut sink, SPEC);
+
+        assert_eq!(sink.writer, [0, 0, 0, 64, 1, 128, 255, 127]);
+
+        let mut sink = PcmSink::new(Vec::new());
+
+        write(
+            &mut sink,
+            WavSpec {
+                bits_per_sample: 24,
+                ..SPEC
+            },
+        );
+
+        assert_eq!(&sink.writer[..6], [0, 0, 0, 0, 0, 64]);
+
+        let mut sink = PcmSink::new(Vec::new());
+
+        write(
+            &mut sink,
+            WavSpec {
+                bits_per_sample: 32,
+                sample_format: SampleFormat::Float,
+                ..SPEC
+            },
+        );
+
+        assert_eq!(&sink.writer[4..8], 0.5f32.to_le_bytes());
+        assert_eq!(sink.writer.len(), 16);
+    }
+
+    #[test]
+    fn test_buffer_and_null_sinks() {
+        let mut sink = BufferSink::new();
+
+        write(&mut sink, SPEC);
+
+        assert_eq!(sink.into_samples(), [0.0, 0.5, -1.0, 2.0]);
+
+        let mut sink = NullSink::new();
+
+        write(&mut sink, SPEC);
+
+        assert_eq!(sink.samples(), 4);
+    }
+}
@@ -0,0 +1,154 @@
+use hound::WavSpec;
+use rayon::prelude::*;
+use tracing::debug;
+
+use crate::{
+    composition::Composition,
+    error::MusathError,
+    renderer::{BLOCK_SIZE, DEFAULT_SPEC, Renderer, render_block, sink::AudioSink},
+};
+
+/// The number of frames a [`StreamingRenderer`] holds in memory by default.
+pub const CHUNK_SIZE: usize = 64 * BLOCK_SIZE;
+
+/// Renders a chunk of frames at a time, in parallel within the chunk, and
+/// writes each chunk before starting the next, so memory use does not grow
+/// with the duration of the composition.
+pub struct StreamingRenderer {
+    spec: WavSpec,
+    chunk_size: usize,
+}
+
+impl StreamingRenderer {
+    pub fn new(spec: WavSpec) -> Self {
+        Self {
+            spec,
+            chunk_size: CHUNK_SIZE,
+        }
+    }
+
+    /// Holds `chunk_size` frames in memory at a time instead of
+    /// [`CHUNK_SIZE`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "expected a positive chunk size");
+
+        self.chunk_size = chunk_size;
+        self
+    }
+}
+
+impl Default for StreamingRenderer {
+    fn default() -> Self {
+        Self::new(DEFAULT_SPEC)
+    }
+}
+
+impl Renderer for StreamingRenderer {
+    fn render_to(
+        &self,
+        composition: &Composition,
+        sink: &mut dyn AudioSink,
+    ) -> Result<(), MusathError> {
+        debug!("creating spec");
+
+        let spec = composition.spec(self.spec)?;
+
+        let duration_seconds = composition.duration().unwrap_or(10.0);
+
+        debug!("calculating total frames");
+        let channels = spec.channels as usize;
+        let total_frames = (duration_seconds * spec.sample_rate as f64).ceil() as usize;
+
+        debug!("allocating chunk");
+        let mut chunk = vec![0.0; self.chunk_size.min(total_frames) * channels];
+
+        let mut start_frame = 0;
+
+        sink.begin(spec)?;
+
+        while start_frame < total_frames {
+            let frames = self.chunk_size.min(total_frames - start_frame);
+            let chunk = &mut chunk[..frames * channels];
+
+            debug!("rendering");
+            chunk
+                .par_chunks_mut(BLOCK_SIZE * channels)
+                .enumerate()
+                .try_for_each(|(i, block)| {
+                    render_block(
+                        composition.wave_provider(),
+                        (start_frame + i * BLOCK_SIZE) as u64,
+                        spec.sample_rate,
+                        block,
+                    )
+                })?;
+
+            debug!("writing samples");
+            sink.write(chunk)?;
+
+            start_frame += frames;
+            debug!("{}/{}", start_frame, total_frames);
+        }
+
+        debug!("finishing sink");
+        sink.finish()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::renderer::{
+        parallel_renderer::ParallelRenderer, serial_renderer::SerialRenderer, sink::NullSink,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_render_to() {
+        let left: fn(f64) -> f64 = f64::sin;
+        let right: fn(f64) -> f64 = |t| -t;
+        let composition = Composition::from_functions("stereo", 0.1, [left, right]);
+
+        let found = StreamingRenderer::default()
+            .with_chunk_size(1000)
+            .render_to_buffer(&composition)
+            .unwrap();
+
+        let mut expected = vec![0.0; 4410 * 2];
+
+        render_block(composition.wave_provider(), 0, 44100, &mut expected).unwrap();
+
+        assert_eq!(found.len(), expected.len());
+
+        for (found, expected) in found.iter().zip(expected) {
+            assert_eq!(*found, expected as f32);
+        }
+
+        assert_eq!(
+            SerialRenderer::default()
+                .render_to_buffer(&composition)
+                .unwrap(),
+            found
+        );
+        assert_eq!(
+            ParallelRenderer::default()
+                .render_to_buffer(&composition)
+                .unwrap(),
+            found
+        );
+
+        let mut sink = NullSink::new();
+
+        StreamingRenderer::default()
+            .render_to(&composition, &mut sink)
+            .unwrap();
+
+        assert_eq!(sink.samples(), found.len());
+    }
+}
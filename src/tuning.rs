@@ -0,0 +1,562 @@
+//! Tunings from [Scala](https://www.huygens-fokker.org/scala/) scale (`.scl`)
+//! and keyboard mapping (`.kbm`) files, for microtonal compositions.
+//!
+//! A [`Scale`] lists the pitches of its degrees above the first, in cents or
+//! as ratios, and repeats every period, the pitch of its last degree. A
+//! [`KeyboardMap`] says which MIDI key plays which degree, and the frequency
+//! of one key, which tunes the whole scale. Together they make a [`Tuning`].
+//!
+//! Documents load them with the `TUNING` and `KEYBOARD_MAP` header keys and
+//! play the degrees with the `degree` builtin. Without them, the tuning is 12
+//! tone equal temperament with middle C as degree 0.
+
+use crate::{
+    error::{ErrorKind, MusathError},
+    pitch,
+};
+
+/// A scale, as read from a `.scl` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scale {
+    description: String,
+    ratios: Vec<f64>,
+}
+
+impl Scale {
+    /// Parses the contents of a `.scl` file: a description, the number of
+    /// pitches and then each pitch on its own line, with lines starting with
+    /// `!` ignored. Pitches containing a `.` are in cents, and others are
+    /// ratios such as `3/2` or whole numbers such as `2`.
+    pub fn parse(source: &str) -> Result<Self, MusathError> {
+        let mut lines = Lines::new(source);
+
+        let description = lines.next_line("description")?.to_string();
+        let count = lines.next_value("number of pitches", |token| token.parse::<usize>().ok())?;
+
+        let ratios = (0..count)
+            .map(|_| lines.next_value("pitch", pitch_ratio))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            description,
+            ratios,
+        })
+    }
+
+    /// `notes` equal steps per `period`, as a ratio.
+    pub fn equal_temperament(notes: usize, period: f64) -> Self {
+        Self {
+            description: format!("{} equal divisions of {}", notes, period),
+            ratios: (1..=notes)
+                .map(|degree| period.powf(degree as f64 / notes as f64))
+                .collect(),
+        }
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// The ratio of each degree after the first to the first, ending with
+    /// the period.
+    pub fn ratios(&self) -> &[f64] {
+        &self.ratios
+    }
+
+    /// The ratio of `degree`, counting from 0, to degree 0, repeating the
+    /// scale every period in either direction.
+    pub fn ratio(&self, degree: i64) -> f64 {
+        ratio(&self.ratios, degree as f64)
+    }
+}
+
+/// 12 tone equal temperament.
+impl Default for Scale {
+    fn default() -> Self {
+        Self::equal_temperament(12, 2.0)
+    }
+}
+
+/// A keyboard mapping, as read from a `.kbm` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyboardMap {
+    size: usize,
+    first: i64,
+    last: i64,
+    middle: i64,
+    reference: i64,
+    frequency: f64,
+    octave_degree: usize,
+    mapping: Vec<Option<usize>>,
+}
+
+impl KeyboardMap {
+    /// Parses the contents of a `.kbm` file: the size of the repeating
+    /// pattern of keys, the first and last keys to tune, the middle key,
+    /// which plays degree 0, the reference key and its frequency in Hz, the
+    /// degree the pattern repeats at, and then the degree of each key of the
+    /// pattern, or `x` for keys left silent. Lines starting with `!` are
+    /// ignored.
+    ///
+    /// A size of 0 maps each key to the degree its distance from the middle
+    /// key, and keys missing from the end of the pattern are silent.
+    pub fn parse(source: &str) -> Result<Self, MusathError> {
+        let mut lines = Lines::new(source);
+
+        let integer = |token: &str| token.parse::<i64>().ok();
+        let count = |token: &str| token.parse::<usize>().ok();
+
+        let size = lines.next_value("map size", count)?;
+        let first = lines.next_value("first key", integer)?;
+        let last = lines.next_value("last key", integer)?;
+        let middle = lines.next_value("middle key", integer)?;
+        let reference = lines.next_value("reference key", integer)?;
+        let frequency = lines.next_value("reference frequency", |token| {
+            token
+                .parse::<f64>()
+                .ok()
+                .filter(|frequency| *frequency > 0.0)
+        })?;
+        let octave_degree = lines.next_value("octave degree", count)?;
+
+        let mut mapping = Vec::with_capacity(size);
+
+        while mapping.len() < size {
+            let key = lines.next_optional_value("degree or `x`", |token| match token {
+                "x" => Some(None),
+                token => token.parse::<usize>().ok().map(Some),
+            })?;
+
+            match key {
+                Some(key) => mapping.push(key),
+                None => break,
+            }
+        }
+
+        mapping.resize(size, None);
+
+        Ok(Self {
+            size,
+            first,
+            last,
+            middle,
+            reference,
+            frequency,
+            octave_degree,
+            mapping,
+        })
+    }
+
+    /// Maps every key to the degree its distance from middle C, tuning
+    /// middle C as 12 tone equal temperament with `A4` at `a4_hz` does.
+    pub fn linear(a4_hz: f64) -> Self {
+        Self {
+            size: 0,
+            first: 0,
+            last: 127,
+            middle: 60,
+            reference: 60,
+            frequency: pitch::mtof(60.0, a4_hz),
+            octave_degree: 0,
+            mapping: Vec::new(),
+        }
+    }
+
+    /// The degree `key` plays within its repetition of the pattern, and that
+    /// repetition, counting from the one starting at the middle key, if it
+    /// plays one.
+    fn degree(&self, key: i64) -> Option<(usize, i64)> {
+        if key < self.first || key > self.last {
+            return None;
+        }
+
+        let offset = key - self.middle;
+
+        if self.size == 0 {
+            return Some((0, offset));
+        }
+
+        let size = self.size as i64;
+        let degree = self.mapping[offset.rem_euclid(size) as usize]?;
+
+        Some((degree, offset.div_euclid(size)))
+    }
+
+    /// The ratio of the frequency of `key` to that of the middle key in
+    /// `scale`, if it plays a degree. Each repetition of the pattern of keys
+    /// is a formal octave above the last.
+    fn ratio(&self, scale: &Scale, key: i64) -> Option<f64> {
+        let (degree, repetition) = self.degree(key)?;
+
+        if self.size == 0 {
+            return Some(scale.ratio(repetition));
+        }
+
+        let octave = match self.octave_degree {
+            0 => scale.ratios.len(),
+            octave_degree => octave_degree,
+        };
+
+        Some(scale.ratio(degree as i64) * scale.ratio(octave as i64).powf(repetition as f64))
+    }
+}
+
+/// Maps keys to the degrees of 12 tone equal temperament with `A4` at 440
+/// Hz.
+impl Default for KeyboardMap {
+    fn default() -> Self {
+        Self::linear(pitch::A4_HZ)
+    }
+}
+
+/// A scale tuned by a keyboard mapping.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    scale: Scale,
+    keyboard_map: KeyboardMap,
+    table: Vec<f64>,
+}
+
+impl Tuning {
+    /// Tunes `scale` so that the reference key of `keyboard_map` has its
+    /// frequency, which is an error if the reference key plays no degree.
+    pub fn new(scale: Scale, keyboard_map: KeyboardMap) -> Result<Self, MusathError> {
+        let reference = keyboard_map.reference;
+        let ratio = keyboard_map.ratio(&scale, reference).ok_or_else(|| {
+            MusathError::new(ErrorKind::InvalidTuning(format!(
+                "the reference key {} plays no degree",
+                reference
+            )))
+        })?;
+
+        let table = std::iter::once(keyboard_map.frequency / ratio)
+            .chain(scale.ratios.iter().copied())
+            .collect();
+
+        Ok(Self {
+            scale,
+            keyboard_map,
+            table,
+        })
+    }
+
+    pub fn scale(&self) -> &Scale {
+        &self.scale
+    }
+
+    pub fn keyboard_map(&self) -> &KeyboardMap {
+        &self.keyboard_map
+    }
+
+    /// The frequency in Hz of degree 0, followed by the
+    /// [ratios](Scale::ratios) of the scale: everything [`degree`] needs.
+    pub fn table(&self) -> &[f64] {
+        &self.table
+    }
+
+    /// The frequency in Hz of degree `n` of the scale. See [`degree`].
+    pub fn degree(&self, n: f64) -> f64 {
+        degree(n, &self.table)
+    }
+
+    /// The frequency in Hz of the MIDI key `key`, if it plays a degree.
+    pub fn key(&self, key: i64) -> Option<f64> {
+        Some(self.table[0] * self.keyboard_map.ratio(&self.scale, key)?)
+    }
+}
+
+/// 12 tone equal temperament with middle C as degree 0 and `A4` at 440 Hz.
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::new(Scale::default(), KeyboardMap::default())
+            .expect("the linear keyboard map plays every key")
+    }
+}
+
+/// The frequency in Hz of degree `n` of the scale in `table`, laid out as
+/// [`Tuning::table`] is. Fractional degrees glide evenly in pitch between the
+/// degrees either side of them.
+pub fn degree(n: f64, table: &[f64]) -> f64 {
+    let Some((tonic, ratios)) = table.split_first() else {
+        return 0.0;
+    };
+
+    let lower = n.floor();
+    let fraction = n - lower;
+
+    if fraction == 0.0 {
+        tonic * ratio(ratios, lower)
+    } else {
+        let (from, to) = (ratio(ratios, lower), ratio(ratios, lower + 1.0));
+
+        tonic * from * (to / from).powf(fraction)
+    }
+}
+
+/// The ratio of the whole-numbered `degree` of the scale with `ratios` to
+/// degree 0.
+fn ratio(ratios: &[f64], degree: f64) -> f64 {
+    let Some(period) = ratios.last() else {
+        return 1.0;
+    };
+
+    let notes = ratios.len() as f64;
+    let index = degree.rem_euclid(notes);
+    let octave = (degree - index) / notes;
+
+    let step = match index as usize {
+        0 => 1.0,
+        index => ratios[index.min(ratios.len()) - 1],
+    };
+
+    step * period.powf(octave)
+}
+
+/// Parses a pitch of a `.scl` file as a ratio.
+fn pitch_ratio(token: &str) -> Option<f64> {
+    if token.contains('.') {
+        return token
+            .parse::<f64>()
+            .ok()
+            .map(|cents| 2.0f64.powf(cents / 1200.0));
+    }
+
+    let (numerator, denominator) = token.split_once('/').unwrap_or((token, "1"));
+    let (numerator, denominator) = (
+        numerator.parse::<u64>().ok()?,
+        denominator.parse::<u64>().ok()?,
+    );
+
+    Some(numerator as f64 / denominator as f64).filter(|ratio| ratio.is_normal())
+}
+
+/// The lines of a Scala file that are not comments, with their line numbers
+/// for errors.
+struct Lines<'a> {
+    lines: std::iter::Enumerate<std::str::Lines<'a>>,
+    line: usize,
+}
+
+impl<'a> Lines<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            lines: source.lines().enumerate(),
+            line: 0,
+        }
+    }
+
+    fn next_optional_line(&mut self) -> Option<&'a str> {
+        let (index, line) = self
+            .lines
+            .find(|(_, line)| !line.trim_start().starts_with('!'))?;
+
+        self.line = index + 1;
+
+        Some(line.trim())
+    }
+
+    fn next_line(&mut self, expected: &str) -> Result<&'a str, MusathError> {
+        self.next_optional_line()
+            .ok_or_else(|| end_of_file(expected))
+    }
+
+    /// Parses the first word of the next line, ignoring the rest as Scala
+    /// does.
+    fn next_optional_value<T>(
+        &mut self,
+        expected: &str,
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> Result<Option<T>, MusathError> {
+        let Some(line) = self.next_optional_line() else {
+            return Ok(None);
+        };
+        let token = line.split_whitespace().next().unwrap_or("");
+
+        parse(token)
+            .map(Some)
+            .ok_or_else(|| self.error(format!("expected a {}, found `{}`", expected, token)))
+    }
+
+    fn next_value<T>(
+        &mut self,
+        expected: &str,
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> Result<T, MusathError> {
+        self.next_optional_value(expected, parse)?
+            .ok_or_else(|| end_of_file(expected))
+    }
+
+    fn error(&self, message: String) -> MusathError {
+        MusathError::new(ErrorKind::InvalidTuning(format!(
+            "line {}: {}",
+            self.line, message
+        )))
+    }
+}
+
+fn end_of_file(expected: &str) -> MusathError {
+    MusathError::new(ErrorKind::InvalidTuning(format!(
+        "expected a {}, found the end of the file",
+        expected
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-8 * expected.abs().max(1.0),
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_parse_scale() {
+        let scale = Scale::parse(include_str!("../tunings/pyth_12.scl")).unwrap();
+
+        assert_eq!(scale.description(), "12-tone Pythagorean scale");
+        assert_eq!(scale.ratios().len(), 12);
+        assert_eq!(scale.ratio(0), 1.0);
+        assert_eq!(scale.ratio(1), 2187.0 / 2048.0);
+        assert_eq!(scale.ratio(7), 1.5);
+        assert_eq!(scale.ratio(12), 2.0);
+        assert_eq!(scale.ratio(19), 3.0);
+        assert_eq!(scale.ratio(-5), 0.75);
+
+        let scale = Scale::parse(include_str!("../tunings/meanquar.scl")).unwrap();
+
+        // Four quarter-comma meantone fifths make a pure major third, to the
+        // five decimal places of the cents in the file.
+        assert_close(scale.ratio(4), 1.25);
+        assert_close(scale.ratio(7), 5.0f64.powf(0.25));
+
+        let scale = Scale::parse(include_str!("../tunings/bohlen-p_et.scl")).unwrap();
+
+        assert_eq!(scale.ratios().len(), 13);
+        assert_eq!(scale.ratio(13), 3.0);
+        assert_close(scale.ratio(26), 9.0);
+        assert_close(scale.ratio(1), 3.0f64.powf(1.0 / 13.0));
+
+        let scale = Scale::parse("!\n\n 0\n").unwrap();
+
+        assert_eq!(scale.description(), "");
+        assert_eq!(scale.ratio(5), 1.0);
+
+        for (source, message) in [
+            ("scale\n12\n", "expected a pitch, found the end of the file"),
+            (
+                "scale\ntwelve\n",
+                "line 2: expected a number of pitches, found `twelve`",
+            ),
+            (
+                "scale\n2\n9/8\n! comment\n3/0\n",
+                "line 5: expected a pitch, found `3/0`",
+            ),
+            ("scale\n1\n-3/2\n", "line 3: expected a pitch, found `-3/2`"),
+            (
+                "! empty\n",
+                "expected a description, found the end of the file",
+            ),
+        ] {
+            assert_eq!(Scale::parse(source).unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn test_parse_keyboard_map() {
+        let keyboard_map = KeyboardMap::parse(include_str!("../tunings/white_keys.kbm")).unwrap();
+
+        assert_eq!(keyboard_map.size, 12);
+        assert_eq!(keyboard_map.middle, 60);
+        assert_eq!(keyboard_map.reference, 69);
+        assert_eq!(keyboard_map.frequency, 440.0);
+        assert_eq!(keyboard_map.octave_degree, 7);
+        assert_eq!(keyboard_map.degree(60), Some((0, 0)));
+        assert_eq!(keyboard_map.degree(61), None);
+        assert_eq!(keyboard_map.degree(71), Some((6, 0)));
+        assert_eq!(keyboard_map.degree(55), Some((4, -1)));
+        assert_eq!(keyboard_map.degree(128), None);
+
+        // Keys missing from the end of the pattern are silent.
+        let keyboard_map = KeyboardMap::parse("3\n0\n127\n60\n60\n261.6\n0\n0\n1\n").unwrap();
+
+        assert_eq!(keyboard_map.degree(61), Some((1, 0)));
+        assert_eq!(keyboard_map.degree(62), None);
+
+        for (source, message) in [
+            (
+                "12\n0\n127\n60\n69\n",
+                "expected a reference frequency, found the end of the file",
+            ),
+            (
+                "0\n0\n127\n60\n69\n-440\n0\n",
+                "line 6: expected a reference frequency, found `-440`",
+            ),
+            (
+                "1\n0\n127\n60\n69\n440.0\n0\ny\n",
+                "line 8: expected a degree or `x`, found `y`",
+            ),
+        ] {
+            assert_eq!(KeyboardMap::parse(source).unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn test_degree() {
+        let tuning = Tuning::default();
+
+        for n in -30..30 {
+            assert_close(
+                tuning.degree(n as f64),
+                pitch::mtof(60.0 + n as f64, pitch::A4_HZ),
+            );
+            assert_close(tuning.key(60 + n).unwrap(), tuning.degree(n as f64));
+        }
+
+        assert_close(tuning.degree(9.0), 440.0);
+        assert_close(tuning.degree(8.5), pitch::mtof(68.5, pitch::A4_HZ));
+
+        let scale = Scale::parse(include_str!("../tunings/pyth_12.scl")).unwrap();
+        let tuning = Tuning::new(scale, KeyboardMap::linear(pitch::A4_HZ)).unwrap();
+
+        assert_close(tuning.degree(0.0), pitch::mtof(60.0, pitch::A4_HZ));
+        assert_close(tuning.degree(7.0) / tuning.degree(0.0), 1.5);
+        assert_close(tuning.degree(-12.0) * 2.0, tuning.degree(0.0));
+
+        // Halfway between the degrees is halfway in pitch.
+        assert_close(
+            tuning.degree(6.5),
+            (tuning.degree(6.0) * tuning.degree(7.0)).sqrt(),
+        );
+
+        assert_eq!(degree(3.0, &[]), 0.0);
+        assert_eq!(degree(3.0, &[100.0]), 100.0);
+    }
+
+    #[test]
+    fn test_key() {
+        let scale = Scale::parse(include_str!("../tunings/just_major.scl")).unwrap();
+        let keyboard_map = KeyboardMap::parse(include_str!("../tunings/white_keys.kbm")).unwrap();
+        let tuning = Tuning::new(scale, keyboard_map).unwrap();
+
+        // A4 is 440 Hz and a just major sixth above C4.
+        assert_close(tuning.key(69).unwrap(), 440.0);
+        assert_close(tuning.key(60).unwrap(), 264.0);
+        assert_close(tuning.key(67).unwrap(), 396.0);
+        assert_close(tuning.key(72).unwrap(), 528.0);
+        assert_close(tuning.key(48).unwrap(), 132.0);
+        assert_eq!(tuning.key(61), None);
+
+        assert_close(tuning.degree(0.0), 264.0);
+        assert_close(tuning.degree(7.0), 528.0);
+
+        let keyboard_map = KeyboardMap::parse("12\n0\n127\n60\n61\n440.0\n0\n0\nx\n").unwrap();
+        let error = Tuning::new(Scale::default(), keyboard_map).unwrap_err();
+
+        assert_eq!(error.to_string(), "the reference key 61 plays no degree");
+    }
+}
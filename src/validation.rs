@@ -0,0 +1,531 @@
+use std::collections::HashMap;
+
+use crate::{
+    context::Context,
+    error::{ErrorKind, MusathError, Span},
+    expression::{BinaryOperator, Expression, Primary},
+    function::{Arity, FunctionBody, FunctionSignature},
+};
+
+/// Builtins whose first argument names a loop variable that is bound while
+/// evaluating their last argument.
+pub(crate) const BINDING_BUILTINS: [&str; 2] = ["sum", "prod"];
+
+/// Statically checks every user-defined function in `context`, returning all
+/// problems found rather than stopping at the first one.
+///
+/// Identifiers are resolved lexically: a function body may only refer to its
+/// own parameters, values already in the context (such as `pi`) and the loop
+/// variables of enclosing `sum`/`prod` calls.
+pub fn validate(context: &Context) -> Vec<MusathError> {
+    let mut errors = validate_functions(context);
+
+    // `output` may take the channel number as a second argument.
+    for (identifier, expected) in [
+        ("output", Arity::OneOf(&[1, 2])),
+        ("left", Arity::Exact(1)),
+        ("right", Arity::Exact(1)),
+    ] {
+        if let Some(function) = context.function(identifier)
+            && !matches!(function.signature().arity(), Arity::Exact(count) if expected.accepts(count))
+        {
+            errors.push(MusathError::with_span(
+                ErrorKind::InvalidSignature {
+                    function: identifier.to_string(),
+                    expected,
+                },
+                function.signature().span(),
+            ));
+        }
+    }
+
+    let stereo = context.function("left").is_some() && context.function("right").is_some();
+
+    if context.function("output").is_none() && !stereo {
+        errors.push(MusathError::new(ErrorKind::MissingOutput));
+    }
+
+    errors.sort_by_key(|error| error.span().map(|span| span.start()).unwrap_or(usize::MAX));
+    errors
+}
+
+/// Like [`validate`], but does not require an `output` function, as for files
+/// that are only ever imported.
+pub fn validate_functions(context: &Context) -> Vec<MusathError> {
+    let mut validator = Validator {
+        context,
+        errors: Vec::new(),
+        calls: HashMap::new(),
+        guards: 0,
+    };
+
+    let mut identifiers = context.functions().keys().collect::<Vec<_>>();
+    identifiers.sort();
+
+    for identifier in &identifiers {
+        let function = context.function(identifier).unwrap();
+
+        if let Some(control_rate) = function.control_rate() {
+            validator.control_rate(identifier, control_rate.rate(), function.signature());
+        }
+
+        if let FunctionBody::Expression(expression) = function.body() {
+            let mut scope = function.signature().parameters().clone();
+
+            validator.calls.insert(identifier.as_str(), Vec::new());
+            validator.expression(expression, &mut scope, identifier);
+        }
+    }
+
+    validator.recursion(&identifiers);
+
+    let mut errors = validator.errors;
+    errors.sort_by_key(|error| error.span().map(|span| span.start()).unwrap_or(usize::MAX));
+    errors
+}
+
+struct Validator<'a> {
+    context: &'a Context,
+    errors: Vec<MusathError>,
+    /// Calls to user functions that are always made when the caller runs.
+    calls: HashMap<&'a str, Vec<(&'a str, Span)>>,
+    /// How many conditional branches or short-circuited operands enclose the
+    /// expression being checked.
+    guards: usize,
+}
+
+impl<'a> Validator<'a> {
+    /// Checks that a function evaluated at a control rate has a first
+    /// parameter to take as time, and a rate it can be evaluated at.
+    fn control_rate(&mut self, identifier: &str, rate: f64, signature: &FunctionSignature) {
+        if signature.parameters().is_empty() {
+            self.errors.push(MusathError::with_span(
+                ErrorKind::InvalidSignature {
+                    function: identifier.to_string(),
+                    expected: Arity::AtLeast(1),
+                },
+                signature.span(),
+            ));
+        }
+
+        if !(rate > 0.0 && rate.is_finite()) {
+            self.errors.push(MusathError::with_span(
+                ErrorKind::InvalidControlRate(identifier.to_string()),
+                signature.span(),
+            ));
+        }
+    }
+
+    fn expression(&mut self, expression: &'a Expression, scope: &mut Vec<String>, caller: &'a str) {
+        match expression {
+            Expression::Binary(left, BinaryOperator::And | BinaryOperator::Or, right) => {
+                self.expression(left, scope, caller);
+                self.guarded(right, scope, caller);
+            }
+            Expression::Binary(left, _, right) => {
+                self.expression(left, scope, caller);
+                self.expression(right, scope, caller);
+            }
+            Expression::Unary(_, operand) => self.expression(operand, scope, caller),
+            Expression::Primary(primary) => self.primary(primary, scope, caller),
+            Expression::Conditional(condition, consequent, alternative) => {
+                self.expression(condition, scope, caller);
+                self.guarded(consequent, scope, caller);
+                self.guarded(alternative, scope, caller);
+            }
+            Expression::Let(bindings, body) => {
+                for (identifier, expression) in bindings {
+                    self.expression(expression, scope, caller);
+                    scope.push(identifier.clone());
+                }
+
+                self.expression(body, scope, caller);
+                scope.truncate(scope.len() - bindings.len());
+            }
+        }
+    }
+
+    fn guarded(&mut self, expression: &'a Expression, scope: &mut Vec<String>, caller: &'a str) {
+        self.guards += 1;
+        self.expression(expression, scope, caller);
+        self.guards -= 1;
+    }
+
+    fn primary(&mut self, primary: &'a Primary, scope: &mut Vec<String>, caller: &'a str) {
+        match primary {
+            Primary::Decimal(_) | Primary::Integer(_) | Primary::Note(_) => (),
+            Primary::Identifier(identifier, span) => {
+                if !scope.contains(identifier) && self.context.value(identifier).is_none() {
+                    self.errors.push(MusathError::with_span(
+                        ErrorKind::UndefinedIdentifier(identifier.clone()),
+                        *span,
+                    ));
+                }
+            }
+            Primary::Grouping(expression) => self.expression(expression, scope, caller),
+            Primary::Call(identifier, arguments, span) => {
+                let Some(function) = self.context.function(identifier) else {
+                    self.errors.push(MusathError::with_span(
+                        ErrorKind::UndefinedFunction(identifier.clone()),
+                        *span,
+                    ));
+
+                    for argument in arguments {
+                        self.expression(argument, scope, caller);
+                    }
+
+                    return;
+                };
+
+                let arity = function.signature().arity();
+
+                if !arity.accepts(arguments.len()) {
+                    self.errors.push(MusathError::with_span(
+                        ErrorKind::ArityMismatch {
+                            function: identifier.clone(),
+                            expected: arity,
+                            found: arguments.len(),
+                        },
+                        *span,
+                    ));
+                }
+
+                match function.body() {
+                    FunctionBody::Closure(_) if BINDING_BUILTINS.contains(&identifier.as_str()) => {
+                        self.binding_call(identifier, arguments, *span, scope, caller);
+                    }
+                    FunctionBody::Closure(_) => {
+                        for argument in arguments {
+                            self.expression(argument, scope, caller);
+                        }
+                    }
+                    FunctionBody::Expression(_) => {
+                        for argument in arguments {
+                            self.expression(argument, scope, caller);
+                        }
+
+                        if self.guards == 0 {
+                            self.calls
+                                .entry(caller)
+                                .or_default()
+                                .push((identifier.as_str(), *span));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn binding_call(
+        &mut self,
+        identifier: &str,
+        arguments: &'a [Box<Expression>],
+        span: Span,
+        scope: &mut Vec<String>,
+        caller: &'a str,
+    ) {
+        let Some((variable, rest)) = arguments.split_first() else {
+            return;
+        };
+
+        let Expression::Primary(Primary::Identifier(variable, _)) = variable.as_ref() else {
+            self.errors.push(MusathError::with_span(
+                ErrorKind::InvalidArgument {
+                    function: identifier.to_string(),
+                    message: String::from("expected the loop variable to be an identifier"),
+                },
+                span,
+            ));
+
+            for argument in arguments {
+                self.expression(argument, scope, caller);
+            }
+
+            return;
+        };
+
+        let (body, bounds) = match rest.split_last() {
+            Some((body, bounds)) => (Some(body), bounds),
+            None => (None, rest),
+        };
+
+        for bound in bounds {
+            self.expression(bound, scope, caller);
+        }
+
+        if let Some(body) = body {
+            scope.push(variable.clone());
+            self.expression(body, scope, caller);
+            scope.pop();
+        }
+    }
+
+    /// Reports every call that closes a cycle of unconditional calls. Such a
+    /// cycle can never terminate, while recursion through a conditional
+    /// branch may.
+    fn recursion(&mut self, identifiers: &[&'a String]) {
+        let mut finished = Vec::new();
+        let mut stack = Vec::new();
+
+        for identifier in identifiers {
+            self.visit(identifier, &mut stack, &mut finished);
+        }
+    }
+
+    fn visit(
+        &mut self,
+        identifier: &'a str,
+        stack: &mut Vec<&'a str>,
+        finished: &mut Vec<&'a str>,
+    ) {
+        if finished.contains(&identifier) {
+            return;
+        }
+
+        stack.push(identifier);
+
+        for (callee, span) in self.calls.get(identifier).cloned().unwrap_or_default() {
+            if let Some(position) = stack.iter().position(|entry| *entry == callee) {
+                let mut cycle = stack[position..]
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>();
+                cycle.push(callee.to_string());
+
+                self.errors.push(MusathError::with_span(
+                    ErrorKind::UnboundedRecursion(cycle),
+                    span,
+                ));
+            } else {
+                self.visit(callee, stack, finished);
+            }
+        }
+
+        stack.pop();
+        finished.push(identifier);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::document::Document;
+
+    use super::*;
+
+    fn validate_source(source: &str) -> Vec<MusathError> {
+        validate(Document::from_source(source).unwrap().body().context())
+    }
+
+    #[test]
+    fn test_validate_valid() {
+        assert!(
+            validate_source(
+                "step(t, freq) = floor((t * freq * 2) % 2)\n\
+                 output(t) = sum(n, 1, 4, step(t, n) * pi) / mix(1, 2, 3)"
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_validate_undefined() {
+        let errors = validate_source("output(t) = sine(t) + x + sum(n, 1, 2, n) + n");
+
+        assert_eq!(errors.len(), 3);
+        assert!(
+            matches!(errors[0].kind(), ErrorKind::UndefinedFunction(identifier) if identifier == "sine")
+        );
+        assert!(
+            matches!(errors[1].kind(), ErrorKind::UndefinedIdentifier(identifier) if identifier == "x")
+        );
+        assert!(
+            matches!(errors[2].kind(), ErrorKind::UndefinedIdentifier(identifier) if identifier == "n")
+        );
+        assert_eq!(errors[1].span().unwrap().column(), 23);
+    }
+
+    #[test]
+    fn test_validate_arity() {
+        let errors = validate_source("f(a, b) = a * b\noutput(t) = f(t) + sin(t, 1) + mix()");
+
+        assert_eq!(errors.len(), 3);
+        assert!(matches!(
+            errors[0].kind(),
+            ErrorKind::ArityMismatch {
+                expected: Arity::Exact(2),
+                found: 1,
+                ..
+            }
+        ));
+        assert!(matches!(
+            errors[1].kind(),
+            ErrorKind::ArityMismatch {
+                expected: Arity::Exact(1),
+                found: 2,
+                ..
+            }
+        ));
+        assert!(matches!(
+            errors[2].kind(),
+            ErrorKind::ArityMismatch {
+                expected: Arity::AtLeast(1),
+                found: 0,
+                ..
+            }
+        ));
+
+        let errors = validate_source(
+            "output(t) = env(t, 0, 0, 0, 1, 1) + env(t, 0, 0, 1) + adsr(t, 0, 1, 0.1, 0.1, 0.5)",
+        );
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0].kind().to_string(),
+            "`env` takes 3 arguments followed by groups of 3 but was called with 4"
+        );
+        assert!(matches!(
+            errors[1].kind(),
+            ErrorKind::ArityMismatch {
+                expected: Arity::OneOf([7, 8]),
+                found: 6,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_validate_output() {
+        let errors = validate_source("f(t) = t");
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].kind(), ErrorKind::MissingOutput));
+
+        let errors = validate_source("output(a, b, c) = a");
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0].kind(),
+            ErrorKind::InvalidSignature {
+                expected: Arity::OneOf([1, 2]),
+                ..
+            }
+        ));
+        assert_eq!(
+            errors[0].kind().to_string(),
+            "expected `output` to take 1 or 2 arguments"
+        );
+
+        let errors = validate_source("output(t, channel) = t\nleft(a, b) = a\nright(t) = t");
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].kind().to_string(),
+            "expected `left` to take 1 argument"
+        );
+
+        assert!(validate_source("output(t, channel) = t * channel").is_empty());
+        assert!(validate_source("left(t) = t\nright(t) = -t").is_empty());
+
+        let errors = validate_source("left(t) = t");
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].kind(), ErrorKind::MissingOutput));
+    }
+
+    #[test]
+    fn test_validate_recursion() {
+        let errors =
+            validate_source("a(t) = b(t)\nb(t) = a(t)\nc(t) = c(t)\noutput(t) = a(t) + c(t)");
+
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            errors[0].kind(),
+            ErrorKind::UnboundedRecursion(cycle) if cycle == &["a", "b", "a"]
+        ));
+        assert!(matches!(
+            errors[1].kind(),
+            ErrorKind::UnboundedRecursion(cycle) if cycle == &["c", "c"]
+        ));
+    }
+
+    #[test]
+    fn test_validate_guarded_recursion() {
+        assert!(
+            validate_source(
+                "f(n) = if n <= 0 then 1 else n * f(n - 1)\n\
+                 g(n) = n > 0 && g(n - 1)\n\
+                 output(t) = f(3) + g(2)"
+            )
+            .is_empty()
+        );
+
+        let errors = validate_source("f(n) = if f(n) then 1 else 0\noutput(t) = f(t)");
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].kind(), ErrorKind::UnboundedRecursion(_)));
+    }
+
+    #[test]
+    fn test_validate_let() {
+        assert!(validate_source("output(t) = let a = t * 2, b = a + 1 in a * b").is_empty());
+
+        let errors = validate_source("output(t) = let a = b, b = t in a + b");
+
+        assert_eq!(errors.len(), 1);
+        assert!(
+            matches!(errors[0].kind(), ErrorKind::UndefinedIdentifier(identifier) if identifier == "b")
+        );
+
+        let errors = validate_source("output(t) = (let a = t in a) + a");
+
+        assert_eq!(errors.len(), 1);
+        assert!(
+            matches!(errors[0].kind(), ErrorKind::UndefinedIdentifier(identifier) if identifier == "a")
+        );
+    }
+
+    #[test]
+    fn test_validate_control_rate() {
+        assert!(
+            validate_source("@rate(100, cubic) lfo(t, f) = sin(t * f)\noutput(t) = lfo(t, 2)")
+                .is_empty()
+        );
+
+        let errors = validate_source("@rate(100) a() = 1\n@rate(0) output(t) = a()");
+
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            errors[0].kind(),
+            ErrorKind::InvalidSignature {
+                expected: Arity::AtLeast(1),
+                ..
+            }
+        ));
+        assert!(
+            matches!(errors[1].kind(), ErrorKind::InvalidControlRate(identifier) if identifier == "output")
+        );
+    }
+
+    #[test]
+    fn test_validate_examples() {
+        for path in [
+            "delay.mth",
+            "harmonics.mth",
+            "noise.mth",
+            "organ.mth",
+            "pulse.mth",
+            "test.mth",
+        ] {
+            let source = std::fs::read_to_string(path).unwrap();
+            let document = Document::from_source_at(&source, path).unwrap();
+
+            assert!(document.warnings().is_empty());
+            assert!(document.validate().is_empty());
+        }
+
+        let source = std::fs::read_to_string("lib/envelopes.mth").unwrap();
+        let document = Document::from_source_at(&source, "lib/envelopes.mth").unwrap();
+
+        assert!(validate_functions(document.body().context()).is_empty());
+    }
+}
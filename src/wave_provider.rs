@@ -1,5 +1,35 @@
+use crate::error::MusathError;
+
 pub trait WaveProvider {
     fn value_at_time(&self, t: f64) -> f64;
+
+    /// Like `value_at_time`, but surfaces evaluation errors instead of
+    /// panicking. Renderers call this so a bad composition fails cleanly.
+    fn try_value_at_time(&self, t: f64) -> Result<f64, MusathError> {
+        Ok(self.value_at_time(t))
+    }
+
+    /// Fills `out` with consecutive samples, the first being sample number
+    /// `start_sample` at `sample_rate`. Providers that can compute many
+    /// samples at once more cheaply than one at a time should override this.
+    fn fill_block(&self, start_sample: u64, sample_rate: u32, out: &mut [f64]) {
+        for (sample, value) in (start_sample..).zip(out) {
+            *value = self.value_at_time(sample as f64 / sample_rate as f64);
+        }
+    }
+
+    /// Like `fill_block`, but surfaces evaluation errors instead of
+    /// panicking.
+    fn try_fill_block(
+        &self,
+        start_sample: u64,
+        sample_rate: u32,
+        out: &mut [f64],
+    ) -> Result<(), MusathError> {
+        self.fill_block(start_sample, sample_rate, out);
+
+        Ok(())
+    }
 }
 
 impl <F: Fn(f64) -> f64> WaveProvider for F {
@@ -7,3 +37,69 @@ impl <F: Fn(f64) -> f64> WaveProvider for F {
         self(t)
     }
 }
+
+/// A [`WaveProvider`] with one value per channel at each point in time.
+/// Channels are numbered from zero; for stereo, channel 0 is left and
+/// channel 1 is right.
+pub trait MultichannelWaveProvider {
+    fn channels(&self) -> u16;
+
+    fn value_at_time(&self, t: f64, channel: u16) -> f64;
+
+    /// Like `value_at_time`, but surfaces evaluation errors instead of
+    /// panicking.
+    fn try_value_at_time(&self, t: f64, channel: u16) -> Result<f64, MusathError> {
+        Ok(self.value_at_time(t, channel))
+    }
+
+    /// Fills `out` with consecutive samples of one channel. See
+    /// [`WaveProvider::fill_block`].
+    fn fill_block(&self, start_sample: u64, sample_rate: u32, channel: u16, out: &mut [f64]) {
+        for (sample, value) in (start_sample..).zip(out) {
+            *value = self.value_at_time(sample as f64 / sample_rate as f64, channel);
+        }
+    }
+
+    /// Like `fill_block`, but surfaces evaluation errors instead of
+    /// panicking.
+    fn try_fill_block(
+        &self,
+        start_sample: u64,
+        sample_rate: u32,
+        channel: u16,
+        out: &mut [f64],
+    ) -> Result<(), MusathError> {
+        self.fill_block(start_sample, sample_rate, channel, out);
+
+        Ok(())
+    }
+}
+
+/// One wave provider per channel.
+impl<W: WaveProvider, const N: usize> MultichannelWaveProvider for [W; N] {
+    fn channels(&self) -> u16 {
+        N as u16
+    }
+
+    fn value_at_time(&self, t: f64, channel: u16) -> f64 {
+        self[channel as usize].value_at_time(t)
+    }
+
+    fn try_value_at_time(&self, t: f64, channel: u16) -> Result<f64, MusathError> {
+        self[channel as usize].try_value_at_time(t)
+    }
+
+    fn fill_block(&self, start_sample: u64, sample_rate: u32, channel: u16, out: &mut [f64]) {
+        self[channel as usize].fill_block(start_sample, sample_rate, out)
+    }
+
+    fn try_fill_block(
+        &self,
+        start_sample: u64,
+        sample_rate: u32,
+        channel: u16,
+        out: &mut [f64],
+    ) -> Result<(), MusathError> {
+        self[channel as usize].try_fill_block(start_sample, sample_rate, out)
+    }
+}